@@ -0,0 +1,103 @@
+mod settings;
+
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use crate::settings::{LogFormat, Settings};
+
+/// Relays iris transfers between senders and receivers that cannot reach each other directly.
+#[derive(Debug, Parser)]
+#[command(name = "iris-relay", version)]
+struct Cli {
+    /// TOML file to read the settings from, the flags below take precedence over it
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Check the settings and exit without starting the relay
+    #[arg(long)]
+    check_config: bool,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // The address lists are reloaded from the configuration file, still giving precedence to
+    // the command line
+    let allowed_network_overrides = cli.settings.allowed_networks.clone();
+    let denied_network_overrides = cli.settings.denied_networks.clone();
+    let settings = match &cli.config {
+        Some(path) => match Settings::from_file(path) {
+            Ok(settings) => settings.merge(cli.settings),
+            Err(e) => {
+                eprintln!("error: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => cli.settings,
+    };
+    let relay_builder = match settings.relay_builder() {
+        Ok(relay_builder) => relay_builder,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if cli.check_config {
+        // Binding is left to the relay, but an address that does not even resolve will
+        // certainly fail to bind
+        let network_addresses = settings
+            .listen_addresses
+            .iter()
+            .chain(&settings.metrics_address)
+            .chain(&settings.admin_address)
+            .filter(|address| !address.starts_with("unix:"));
+        for address in network_addresses {
+            if address.to_socket_addrs().is_err() {
+                eprintln!("error: {address} is not a valid address");
+                return ExitCode::FAILURE;
+            }
+        }
+        println!("configuration is valid");
+        return ExitCode::SUCCESS;
+    }
+
+    // Already checked by `relay_builder`
+    let log_level = settings.log_level().unwrap();
+    let subscriber = tracing_subscriber::fmt().with_max_level(log_level);
+    match settings.log_format() {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    let reload = |relay: &iris::RelayHandle| {
+        let Some(path) = &cli.config else {
+            return;
+        };
+        match Settings::from_file(path) {
+            Ok(settings) => {
+                let settings = settings.merge(Settings {
+                    allowed_networks: allowed_network_overrides.clone(),
+                    denied_networks: denied_network_overrides.clone(),
+                    ..Settings::default()
+                });
+                tracing::info!("reloaded the address lists from {}", path.display());
+                relay.set_address_lists(settings.allowed_networks, settings.denied_networks);
+            }
+            Err(e) => tracing::error!("kept the address lists as they were: {e}"),
+        }
+    };
+    match relay_builder.run_with_reload(reload) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use iris::{
+    AccessToken, AuditLogConfig, ClusterConfig, IpNetwork, MailboxConfig, NamespaceLimits,
+    RelayBuilder, RelayConfig, WebhookConfig,
+};
+use serde::Deserialize;
+
+/// How the relay's logs are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Every setting of the relay, read from the configuration file and the command line alike.
+///
+/// Everything is optional so that the command line only overrides what it sets, and whatever
+/// neither of them sets falls back to the defaults of [`RelayConfig`].
+#[derive(Debug, Default, Deserialize, Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Address to accept senders and receivers on, e.g. 0.0.0.0:7777, [::]:7777 or
+    /// unix:/run/iris/relay.sock, can be given several times
+    #[arg(long = "listen-address")]
+    pub listen_addresses: Vec<String>,
+
+    /// Whether IPv6 listen addresses only accept IPv6 clients, left to the operating system
+    /// unless set
+    #[arg(long)]
+    pub ipv6_only: Option<bool>,
+
+    /// Address to expose Prometheus metrics on, disabled unless set
+    #[arg(long)]
+    pub metrics_address: Option<String>,
+
+    /// Address of the admin API, either on localhost or e.g. unix:/run/iris/admin.sock,
+    /// disabled unless set
+    #[arg(long)]
+    pub admin_address: Option<String>,
+
+    /// Maximum number of paired transfers relayed at the same time
+    #[arg(long)]
+    pub max_concurrent_relays: Option<usize>,
+
+    /// Maximum number of connections going through the handshake at the same time
+    #[arg(long)]
+    pub max_concurrent_handshakes: Option<usize>,
+
+    /// Maximum number of new connections waiting for a handshake worker
+    #[arg(long)]
+    pub max_queued_handshakes: Option<usize>,
+
+    /// Seconds a new connection has to get through the handshake once a worker picks it up
+    #[arg(long)]
+    pub handshake_timeout_secs: Option<f64>,
+
+    /// Largest message, in bytes, a client may send before it is paired
+    #[arg(long)]
+    pub max_handshake_message_size: Option<u32>,
+
+    /// Maximum number of senders waiting for a receiver at the same time
+    #[arg(long)]
+    pub max_waiting_rooms: Option<usize>,
+
+    /// Seconds a sender may wait for its receiver before the room is closed
+    #[arg(long)]
+    pub room_ttl_secs: Option<f64>,
+
+    /// Shortest room identifier handed out, in digits
+    #[arg(long)]
+    pub min_room_identifier_digits: Option<u32>,
+
+    /// Longest room identifier handed out, in digits
+    #[arg(long)]
+    pub max_room_identifier_digits: Option<u32>,
+
+    /// Largest fraction of the room identifiers of a given length in use at once
+    #[arg(long)]
+    pub max_room_identifier_occupancy: Option<f64>,
+
+    /// Most receivers a single broadcast room may gather, at most max_concurrent_relays
+    #[arg(long)]
+    pub max_broadcast_receivers: Option<usize>,
+
+    /// Most receivers a single reusable room may be used by
+    #[arg(long)]
+    pub max_room_uses: Option<u32>,
+
+    /// Failed room joins or authentications after which an address is banned, 0 disables. One
+    /// failure is forgiven every ban duration divided by this many
+    #[arg(long)]
+    pub max_failed_joins: Option<u32>,
+
+    /// Seconds an address has to wait after a failed room join, doubled with every failure
+    #[arg(long)]
+    pub failed_join_backoff_secs: Option<f64>,
+
+    /// Seconds an address is banned for after too many failed room joins
+    #[arg(long)]
+    pub ban_duration_secs: Option<f64>,
+
+    /// Rooms a single address may create per minute, 0 disables
+    #[arg(long)]
+    pub max_room_creations_per_minute: Option<u32>,
+
+    /// Bytes per second a single paired room may forward, unlimited unless set
+    #[arg(long)]
+    pub room_bandwidth_limit: Option<u64>,
+
+    /// Bytes per second the whole relay may forward, shared equally between the paired rooms,
+    /// unlimited unless set
+    #[arg(long)]
+    pub global_bandwidth_limit: Option<u64>,
+
+    /// Bytes a single paired transfer may forward, unlimited unless set
+    #[arg(long)]
+    pub max_session_bytes: Option<u64>,
+
+    /// Seconds a single paired transfer may last, unlimited unless set
+    #[arg(long)]
+    pub max_session_duration_secs: Option<f64>,
+
+    /// Seconds a paired transfer may go without forwarding anything before it is ended
+    #[arg(long)]
+    pub session_idle_timeout_secs: Option<f64>,
+
+    /// Bytes a single address may have relayed per day, unlimited unless set
+    #[arg(long)]
+    pub daily_quota_bytes: Option<u64>,
+
+    /// Seconds paired transfers get to finish after SIGTERM
+    #[arg(long)]
+    pub drain_timeout_secs: Option<f64>,
+
+    /// Load balancer allowed to send a PROXY protocol header, as an address or a network such
+    /// as 10.0.0.0/8, can be given several times
+    #[arg(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpNetwork>,
+
+    /// Whether clients on a Unix domain socket may send a PROXY protocol header and are exempt
+    /// from the rate limits, otherwise they share the limits of a single address
+    #[arg(long)]
+    pub trust_unix_clients: Option<bool>,
+
+    /// Network allowed to connect, as an address or a network such as 10.0.0.0/8, can be given
+    /// several times. Every other address is turned away once a network is allowed
+    #[arg(long = "allowed-network")]
+    pub allowed_networks: Vec<IpNetwork>,
+
+    /// Network turned away even if it is allowed, can be given several times
+    #[arg(long = "denied-network")]
+    pub denied_networks: Vec<IpNetwork>,
+
+    /// File to keep a JSON Lines record of every room in, disabled unless set
+    #[arg(long)]
+    pub audit_log_path: Option<PathBuf>,
+
+    /// Size in bytes after which the audit log is rotated
+    #[arg(long)]
+    pub audit_log_max_file_size: Option<u64>,
+
+    /// Number of rotated audit logs kept next to the current one
+    #[arg(long)]
+    pub audit_log_max_rotated_files: Option<usize>,
+
+    /// Address of a node of the cluster the relay is part of, can be given several times to
+    /// list every node, in the same order on all of them
+    #[arg(long = "cluster-node")]
+    pub cluster_nodes: Vec<String>,
+
+    /// Which of the cluster nodes the relay is, counting from 0
+    #[arg(long)]
+    pub cluster_node_index: Option<usize>,
+
+    /// Directory to keep files in for receivers that are not online, disabled unless set
+    #[arg(long)]
+    pub mailbox_directory: Option<PathBuf>,
+
+    /// Seconds a mailbox is kept for after it was uploaded
+    #[arg(long)]
+    pub mailbox_ttl_secs: Option<f64>,
+
+    /// Size in bytes of the largest mailbox
+    #[arg(long)]
+    pub mailbox_max_size: Option<u64>,
+
+    /// Number of mailboxes after which new uploads are turned away
+    #[arg(long)]
+    pub max_mailboxes: Option<usize>,
+
+    /// Size in bytes of all the mailboxes together, uploads being cut off past it
+    #[arg(long)]
+    pub mailbox_max_total_bytes: Option<u64>,
+
+    /// Number of mailbox uploads and downloads underway at once
+    #[arg(long)]
+    pub max_mailbox_transfers: Option<usize>,
+
+    /// Plain HTTP URL to POST room events to, disabled unless set
+    #[arg(long)]
+    pub webhook_url: Option<String>,
+
+    /// Number of events waiting for the webhook after which new events are dropped
+    #[arg(long)]
+    pub webhook_max_queued_events: Option<usize>,
+
+    /// Number of times delivering an event to the webhook is attempted
+    #[arg(long)]
+    pub webhook_max_attempts: Option<u32>,
+
+    /// Format of the logs
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Most verbose level logged, one of error, warn, info, debug or trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Tokens granting access to the relay, only read from the configuration file so that
+    /// they do not show up in the process list
+    #[arg(skip)]
+    pub access_tokens: Vec<AccessToken>,
+
+    /// Limits on the rooms of single namespaces, only read from the configuration file
+    #[arg(skip)]
+    pub namespace_limits: Vec<NamespaceLimits>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+        toml::from_str(&contents).map_err(|e| format!("unable to parse {}: {e}", path.display()))
+    }
+
+    /// Layers `overrides` on top of these settings.
+    pub fn merge(self, overrides: Settings) -> Settings {
+        Settings {
+            listen_addresses: if overrides.listen_addresses.is_empty() {
+                self.listen_addresses
+            } else {
+                overrides.listen_addresses
+            },
+            ipv6_only: overrides.ipv6_only.or(self.ipv6_only),
+            metrics_address: overrides.metrics_address.or(self.metrics_address),
+            admin_address: overrides.admin_address.or(self.admin_address),
+            max_concurrent_relays: overrides
+                .max_concurrent_relays
+                .or(self.max_concurrent_relays),
+            max_concurrent_handshakes: overrides
+                .max_concurrent_handshakes
+                .or(self.max_concurrent_handshakes),
+            max_queued_handshakes: overrides
+                .max_queued_handshakes
+                .or(self.max_queued_handshakes),
+            handshake_timeout_secs: overrides
+                .handshake_timeout_secs
+                .or(self.handshake_timeout_secs),
+            max_handshake_message_size: overrides
+                .max_handshake_message_size
+                .or(self.max_handshake_message_size),
+            max_waiting_rooms: overrides.max_waiting_rooms.or(self.max_waiting_rooms),
+            room_ttl_secs: overrides.room_ttl_secs.or(self.room_ttl_secs),
+            min_room_identifier_digits: overrides
+                .min_room_identifier_digits
+                .or(self.min_room_identifier_digits),
+            max_room_identifier_digits: overrides
+                .max_room_identifier_digits
+                .or(self.max_room_identifier_digits),
+            max_room_identifier_occupancy: overrides
+                .max_room_identifier_occupancy
+                .or(self.max_room_identifier_occupancy),
+            max_broadcast_receivers: overrides
+                .max_broadcast_receivers
+                .or(self.max_broadcast_receivers),
+            max_room_uses: overrides.max_room_uses.or(self.max_room_uses),
+            max_failed_joins: overrides.max_failed_joins.or(self.max_failed_joins),
+            failed_join_backoff_secs: overrides
+                .failed_join_backoff_secs
+                .or(self.failed_join_backoff_secs),
+            ban_duration_secs: overrides.ban_duration_secs.or(self.ban_duration_secs),
+            max_room_creations_per_minute: overrides
+                .max_room_creations_per_minute
+                .or(self.max_room_creations_per_minute),
+            room_bandwidth_limit: overrides.room_bandwidth_limit.or(self.room_bandwidth_limit),
+            global_bandwidth_limit: overrides
+                .global_bandwidth_limit
+                .or(self.global_bandwidth_limit),
+            max_session_bytes: overrides.max_session_bytes.or(self.max_session_bytes),
+            max_session_duration_secs: overrides
+                .max_session_duration_secs
+                .or(self.max_session_duration_secs),
+            session_idle_timeout_secs: overrides
+                .session_idle_timeout_secs
+                .or(self.session_idle_timeout_secs),
+            daily_quota_bytes: overrides.daily_quota_bytes.or(self.daily_quota_bytes),
+            drain_timeout_secs: overrides.drain_timeout_secs.or(self.drain_timeout_secs),
+            trusted_proxies: if overrides.trusted_proxies.is_empty() {
+                self.trusted_proxies
+            } else {
+                overrides.trusted_proxies
+            },
+            trust_unix_clients: overrides.trust_unix_clients.or(self.trust_unix_clients),
+            allowed_networks: if overrides.allowed_networks.is_empty() {
+                self.allowed_networks
+            } else {
+                overrides.allowed_networks
+            },
+            denied_networks: if overrides.denied_networks.is_empty() {
+                self.denied_networks
+            } else {
+                overrides.denied_networks
+            },
+            audit_log_path: overrides.audit_log_path.or(self.audit_log_path),
+            audit_log_max_file_size: overrides
+                .audit_log_max_file_size
+                .or(self.audit_log_max_file_size),
+            audit_log_max_rotated_files: overrides
+                .audit_log_max_rotated_files
+                .or(self.audit_log_max_rotated_files),
+            cluster_nodes: if overrides.cluster_nodes.is_empty() {
+                self.cluster_nodes
+            } else {
+                overrides.cluster_nodes
+            },
+            cluster_node_index: overrides.cluster_node_index.or(self.cluster_node_index),
+            mailbox_directory: overrides.mailbox_directory.or(self.mailbox_directory),
+            mailbox_ttl_secs: overrides.mailbox_ttl_secs.or(self.mailbox_ttl_secs),
+            mailbox_max_size: overrides.mailbox_max_size.or(self.mailbox_max_size),
+            max_mailboxes: overrides.max_mailboxes.or(self.max_mailboxes),
+            mailbox_max_total_bytes: overrides
+                .mailbox_max_total_bytes
+                .or(self.mailbox_max_total_bytes),
+            max_mailbox_transfers: overrides
+                .max_mailbox_transfers
+                .or(self.max_mailbox_transfers),
+            webhook_url: overrides.webhook_url.or(self.webhook_url),
+            webhook_max_queued_events: overrides
+                .webhook_max_queued_events
+                .or(self.webhook_max_queued_events),
+            webhook_max_attempts: overrides.webhook_max_attempts.or(self.webhook_max_attempts),
+            log_format: overrides.log_format.or(self.log_format),
+            log_level: overrides.log_level.or(self.log_level),
+            access_tokens: if overrides.access_tokens.is_empty() {
+                self.access_tokens
+            } else {
+                overrides.access_tokens
+            },
+            namespace_limits: if overrides.namespace_limits.is_empty() {
+                self.namespace_limits
+            } else {
+                overrides.namespace_limits
+            },
+        }
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format.unwrap_or(LogFormat::Text)
+    }
+
+    pub fn log_level(&self) -> Result<tracing::Level, String> {
+        match &self.log_level {
+            Some(log_level) => log_level
+                .parse()
+                .map_err(|_| format!("log_level {log_level:?} is not a valid log level")),
+            None => Ok(tracing::Level::INFO),
+        }
+    }
+
+    /// Turns the settings into a relay ready to be started, checking them along the way.
+    pub fn relay_builder(&self) -> Result<RelayBuilder, String> {
+        self.log_level()?;
+        let Some((listen_address, other_listen_addresses)) = self.listen_addresses.split_first()
+        else {
+            return Err("at least one listen address is required".to_string());
+        };
+
+        let mut config = RelayConfig::default();
+        if let Some(max_concurrent_relays) = self.max_concurrent_relays {
+            config.max_concurrent_relays = max_concurrent_relays;
+        }
+        if let Some(max_concurrent_handshakes) = self.max_concurrent_handshakes {
+            config.max_concurrent_handshakes = max_concurrent_handshakes;
+        }
+        if let Some(max_queued_handshakes) = self.max_queued_handshakes {
+            config.max_queued_handshakes = max_queued_handshakes;
+        }
+        if let Some(handshake_timeout_secs) = self.handshake_timeout_secs {
+            config.handshake_timeout = duration("handshake_timeout_secs", handshake_timeout_secs)?;
+        }
+        if let Some(max_handshake_message_size) = self.max_handshake_message_size {
+            config.max_handshake_message_size = max_handshake_message_size;
+        }
+        if let Some(max_waiting_rooms) = self.max_waiting_rooms {
+            config.max_waiting_rooms = max_waiting_rooms;
+        }
+        if let Some(room_ttl_secs) = self.room_ttl_secs {
+            config.room_ttl = duration("room_ttl_secs", room_ttl_secs)?;
+        }
+        if let Some(min_room_identifier_digits) = self.min_room_identifier_digits {
+            config.min_room_identifier_digits = min_room_identifier_digits;
+        }
+        if let Some(max_room_identifier_digits) = self.max_room_identifier_digits {
+            config.max_room_identifier_digits = max_room_identifier_digits;
+        }
+        if let Some(max_room_identifier_occupancy) = self.max_room_identifier_occupancy {
+            config.max_room_identifier_occupancy = max_room_identifier_occupancy;
+        }
+        if let Some(max_broadcast_receivers) = self.max_broadcast_receivers {
+            config.max_broadcast_receivers = max_broadcast_receivers;
+        }
+        if let Some(max_room_uses) = self.max_room_uses {
+            config.max_room_uses = max_room_uses;
+        }
+        if let Some(max_failed_joins) = self.max_failed_joins {
+            config.max_failed_joins = max_failed_joins;
+        }
+        if let Some(failed_join_backoff_secs) = self.failed_join_backoff_secs {
+            config.failed_join_backoff =
+                duration("failed_join_backoff_secs", failed_join_backoff_secs)?;
+        }
+        if let Some(ban_duration_secs) = self.ban_duration_secs {
+            config.ban_duration = duration("ban_duration_secs", ban_duration_secs)?;
+        }
+        if let Some(max_room_creations_per_minute) = self.max_room_creations_per_minute {
+            config.max_room_creations_per_minute = max_room_creations_per_minute;
+        }
+        if self.room_bandwidth_limit.is_some() {
+            config.room_bandwidth_limit = self.room_bandwidth_limit;
+        }
+        if self.global_bandwidth_limit.is_some() {
+            config.global_bandwidth_limit = self.global_bandwidth_limit;
+        }
+        if self.max_session_bytes.is_some() {
+            config.max_session_bytes = self.max_session_bytes;
+        }
+        if let Some(max_session_duration_secs) = self.max_session_duration_secs {
+            config.max_session_duration = Some(duration(
+                "max_session_duration_secs",
+                max_session_duration_secs,
+            )?);
+        }
+        if let Some(session_idle_timeout_secs) = self.session_idle_timeout_secs {
+            config.session_idle_timeout =
+                duration("session_idle_timeout_secs", session_idle_timeout_secs)?;
+        }
+        if self.daily_quota_bytes.is_some() {
+            config.daily_quota_bytes = self.daily_quota_bytes;
+        }
+        if let Some(drain_timeout_secs) = self.drain_timeout_secs {
+            config.drain_timeout = duration("drain_timeout_secs", drain_timeout_secs)?;
+        }
+        config.access_tokens = self.access_tokens.clone();
+        config.namespace_limits = self.namespace_limits.clone();
+        config.ipv6_only = self.ipv6_only;
+        config.trusted_proxies = self.trusted_proxies.clone();
+        if let Some(trust_unix_clients) = self.trust_unix_clients {
+            config.trust_unix_clients = trust_unix_clients;
+        }
+        config.allowed_networks = self.allowed_networks.clone();
+        config.denied_networks = self.denied_networks.clone();
+        config.metrics_address = self.metrics_address.clone();
+        config.admin_address = self.admin_address.clone();
+        if let Some(audit_log_path) = &self.audit_log_path {
+            let mut audit_log = AuditLogConfig::new(audit_log_path);
+            if let Some(max_file_size) = self.audit_log_max_file_size {
+                audit_log.max_file_size = max_file_size;
+            }
+            if let Some(max_rotated_files) = self.audit_log_max_rotated_files {
+                audit_log.max_rotated_files = max_rotated_files;
+            }
+            config.audit_log = Some(audit_log);
+        }
+        match (self.cluster_nodes.is_empty(), self.cluster_node_index) {
+            (false, Some(cluster_node_index)) => {
+                config.cluster = Some(ClusterConfig::new(
+                    self.cluster_nodes.clone(),
+                    cluster_node_index,
+                ));
+            }
+            (true, None) => {}
+            _ => {
+                return Err(
+                    "cluster_nodes and cluster_node_index have to be set together".to_string(),
+                )
+            }
+        }
+        if let Some(mailbox_directory) = &self.mailbox_directory {
+            let mut mailbox = MailboxConfig::new(mailbox_directory);
+            if let Some(mailbox_ttl_secs) = self.mailbox_ttl_secs {
+                mailbox.ttl = duration("mailbox_ttl_secs", mailbox_ttl_secs)?;
+            }
+            if let Some(max_size) = self.mailbox_max_size {
+                mailbox.max_size = max_size;
+            }
+            if let Some(max_mailboxes) = self.max_mailboxes {
+                mailbox.max_mailboxes = max_mailboxes;
+            }
+            if let Some(max_total_bytes) = self.mailbox_max_total_bytes {
+                mailbox.max_total_bytes = max_total_bytes;
+            }
+            if let Some(max_transfers) = self.max_mailbox_transfers {
+                mailbox.max_transfers = max_transfers;
+            }
+            config.mailbox = Some(mailbox);
+        }
+        if let Some(webhook_url) = &self.webhook_url {
+            let mut webhook = WebhookConfig::new(webhook_url);
+            if let Some(max_queued_events) = self.webhook_max_queued_events {
+                webhook.max_queued_events = max_queued_events;
+            }
+            if let Some(max_attempts) = self.webhook_max_attempts {
+                webhook.max_attempts = max_attempts;
+            }
+            config.webhook = Some(webhook);
+        }
+        config.validate().map_err(|e| e.to_string())?;
+
+        Ok(other_listen_addresses.iter().fold(
+            RelayBuilder::new(listen_address).config(config),
+            RelayBuilder::listen_on,
+        ))
+    }
+}
+
+fn duration(name: &str, secs: f64) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| format!("{name} must be a non-negative number of seconds"))
+}
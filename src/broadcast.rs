@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use usize_cast::{FromUsize, IntoUsize};
+
+use crate::cipher::{get_cipher, Cipher, CipherType};
+use crate::constants::CHUNK_SIZE;
+use crate::errors::IrisError;
+use crate::files::{FileMetadata, FileType};
+use crate::iris_stream::{EncryptedIrisStream, IrisStream};
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::Namespace;
+use crate::progress::{SenderProgressCommunication, SenderProgressMessage, WorkerMessage};
+use crate::relay_connection::{connect_to_relay, RelayConnectionOptions};
+use crate::room_mapping::RoomIdentifier;
+use crate::sender::{get_complete_file_list_and_total_size, perform_key_exchange};
+use crate::IrisMessage;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BroadcastOptions {
+    /// Most receivers to send to, the relay may allow fewer.
+    pub max_receivers: usize,
+    /// How long the room stays open for more receivers once the first one joined, the relay
+    /// may allow less. The broadcast starts early if the room fills up.
+    pub join_window: Duration,
+}
+
+/// A receiver of the broadcast that has not failed yet.
+struct BroadcastReceiver {
+    /// Which receiver this is in the progress messages, counting from 0.
+    receiver: usize,
+    connection: IrisTcpStream,
+    cipher: Box<dyn Cipher>,
+    /// Where the receiver wants the current file to start from, while it is still being sent.
+    start_pos: Option<u64>,
+}
+
+/// Sends `files` to every receiver that joins the room within the join window.
+///
+/// Every receiver runs its own key exchange and gets the files over its own connection, but
+/// each file is only read once for all the receivers that start it from the same position.
+/// Progress is reported per receiver with [`SenderProgressMessage::ReceiverProgress`], and a
+/// receiver that fails is dropped while the others carry on. Fails with
+/// [`IrisError::BroadcastFailed`] only once there are no receivers left.
+#[allow(clippy::too_many_arguments)]
+pub fn simple_broadcast(
+    server_ip: String,
+    server_port: String,
+    relay_connection_options: &RelayConnectionOptions,
+    cipher_type: CipherType,
+    passphrase: &str,
+    files: Vec<PathBuf>,
+    broadcast_options: BroadcastOptions,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+    server_connection.write_iris_message(IrisMessage::BroadcastSenderConnecting {
+        max_receivers: broadcast_options.max_receivers,
+        join_window_secs: broadcast_options.join_window.as_secs(),
+    })?;
+
+    let room_identifier = match server_connection.read_iris_message()? {
+        IrisMessage::AssignedRoomIdentifier { room_identifier } => room_identifier,
+        IrisMessage::RelayFull => return Err(IrisError::RelayFull),
+        IrisMessage::AccessDenied => return Err(IrisError::RelayAccessDenied),
+        IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            return Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => return Err(IrisError::DailyQuotaExceeded),
+        _ => return Err(IrisError::UnexpectedMessage),
+    };
+    tracing::info!("connect using {room_identifier}-{passphrase}");
+    progress_communication
+        .write(SenderProgressMessage::AssignedRoomIdentifier { room_identifier })?;
+
+    let (total_receivers, token) = match server_connection.read_iris_message()? {
+        IrisMessage::BroadcastStarted {
+            total_receivers,
+            token,
+        } => (total_receivers, token),
+        IrisMessage::RoomExpired => return Err(IrisError::RoomExpired),
+        IrisMessage::RoomClosed => return Err(IrisError::RoomClosed),
+        IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+        _ => return Err(IrisError::UnexpectedMessage),
+    };
+    tracing::info!("broadcasting to {total_receivers} receivers");
+    progress_communication.write(SenderProgressMessage::ReceiversJoined { total_receivers })?;
+
+    // The relay pairs the first connection with the first receiver, and needs one more
+    // connection for each of the others. They are all opened before any key exchange, so that
+    // no receiver is left waiting on the ones before it.
+    let mut connections = vec![(0, server_connection)];
+    for receiver in 1..total_receivers {
+        match connect_to_relay(&server_ip, &server_port, relay_connection_options).and_then(
+            |mut connection| {
+                connection.write_iris_message(IrisMessage::SenderJoining {
+                    room_identifier,
+                    token,
+                })?;
+                Ok(connection)
+            },
+        ) {
+            Ok(connection) => connections.push((receiver, connection)),
+            Err(e) => report_error(progress_communication, receiver, e),
+        }
+    }
+
+    let mut receivers = Vec::with_capacity(connections.len());
+    for (receiver, mut connection) in connections {
+        match start_encryption(
+            &mut connection,
+            relay_connection_options.namespace,
+            room_identifier,
+            passphrase,
+            cipher_type,
+        ) {
+            Ok(cipher) => {
+                report(
+                    progress_communication,
+                    receiver,
+                    SenderProgressMessage::SetCipher { cipher_type },
+                );
+                receivers.push(BroadcastReceiver {
+                    receiver,
+                    connection,
+                    cipher,
+                    start_pos: None,
+                });
+            }
+            Err(e) => report_error(progress_communication, receiver, e),
+        }
+    }
+    if receivers.is_empty() {
+        return Err(IrisError::BroadcastFailed);
+    }
+    tracing::info!("switching over to encrypted communication");
+
+    let complete_file_list = broadcast_transfer_metadata(
+        &mut receivers,
+        room_identifier,
+        files,
+        progress_communication,
+    )?;
+    broadcast_files(&mut receivers, complete_file_list, progress_communication)
+}
+
+/// Waits for the relay to pair `connection` with its receiver and runs the key exchange.
+fn start_encryption(
+    connection: &mut IrisTcpStream,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+    passphrase: &str,
+    cipher_type: CipherType,
+) -> Result<Box<dyn Cipher>, IrisError> {
+    match connection.read_iris_message()? {
+        IrisMessage::ReceiverConnected => {}
+        IrisMessage::BadRoomIdentifier => return Err(IrisError::InvalidPassphrase),
+        IrisMessage::RelayFull => return Err(IrisError::RelayFull),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            return Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => return Err(IrisError::DailyQuotaExceeded),
+        _ => return Err(IrisError::UnexpectedMessage),
+    }
+    connection.write_iris_message(IrisMessage::SetCipherType { cipher_type })?;
+    let key = perform_key_exchange(connection, namespace, room_identifier, passphrase)?;
+    get_cipher(cipher_type, &key)
+}
+
+fn broadcast_transfer_metadata(
+    receivers: &mut Vec<BroadcastReceiver>,
+    room_identifier: RoomIdentifier,
+    files: Vec<PathBuf>,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<Vec<(PathBuf, FileMetadata)>, IrisError> {
+    let (complete_file_list, total_size) = get_complete_file_list_and_total_size(files)?;
+    tracing::info!(
+        "going to broadcast {total_size} bytes distributed among {} files in room #{room_identifier}",
+        complete_file_list.len()
+    );
+    progress_communication.write(SenderProgressMessage::TransferMetadata {
+        total_files: complete_file_list.len(),
+        total_bytes: total_size,
+    })?;
+
+    for_each_receiver(receivers, progress_communication, |receiver| {
+        let iris_message = receiver
+            .connection
+            .read_encrypted_iris_message(&*receiver.cipher)?;
+        if !matches!(iris_message, IrisMessage::ReadyToReceiveMetadata) {
+            return Err(IrisError::UnexpectedMessage);
+        }
+        receiver.connection.write_encrypted_iris_message(
+            &*receiver.cipher,
+            IrisMessage::TransferMetadata {
+                total_files: complete_file_list.len(),
+                total_bytes: total_size,
+            },
+        )
+    })?;
+    for_each_receiver(receivers, progress_communication, |receiver| {
+        let iris_message = receiver
+            .connection
+            .read_encrypted_iris_message(&*receiver.cipher)?;
+        if !matches!(iris_message, IrisMessage::ReadyToReceiveFiles) {
+            return Err(IrisError::UnexpectedMessage);
+        }
+        Ok(())
+    })?;
+    tracing::info!("sending files");
+
+    Ok(complete_file_list)
+}
+
+fn broadcast_files(
+    receivers: &mut Vec<BroadcastReceiver>,
+    complete_file_list: Vec<(PathBuf, FileMetadata)>,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    let mut buffer = vec![0; CHUNK_SIZE.into_usize()];
+
+    for (file_path, file_metadata) in complete_file_list.iter() {
+        tracing::debug!("sending file metadata for {file_path:?}");
+        progress_communication.write(SenderProgressMessage::FileMetadata {
+            filename: file_metadata.get_filename().to_path_buf(),
+            file_size: file_metadata.get_size(),
+        })?;
+        let serialized_file_metadata =
+            serde_json::to_vec(&file_metadata).map_err(|_| IrisError::SerializationError)?;
+        for_each_receiver(receivers, progress_communication, |receiver| {
+            receiver
+                .connection
+                .write_encrypted_message(&*receiver.cipher, &serialized_file_metadata)
+        })?;
+
+        match file_metadata.get_file_type() {
+            FileType::Directory => {
+                for_each_receiver(receivers, progress_communication, |receiver| {
+                    let progress = match receiver
+                        .connection
+                        .read_encrypted_iris_message(&*receiver.cipher)?
+                    {
+                        IrisMessage::DirectoryCreated => SenderProgressMessage::DirectoryCreated,
+                        IrisMessage::FileSkipped => SenderProgressMessage::FileSkipped,
+                        _ => return Err(IrisError::UnexpectedMessage),
+                    };
+                    report(progress_communication, receiver.receiver, progress);
+                    Ok(())
+                })?
+            }
+            FileType::File => {
+                broadcast_file(receivers, file_path, &mut buffer, progress_communication)?
+            }
+        }
+
+        if matches!(progress_communication.read()?, Some(WorkerMessage::Cancel)) {
+            tracing::debug!("exiting as user cancel");
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a file to every receiver, reading it once for every position the receivers want it
+/// to start from.
+fn broadcast_file(
+    receivers: &mut Vec<BroadcastReceiver>,
+    file_path: &Path,
+    buffer: &mut [u8],
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    for_each_receiver(receivers, progress_communication, |receiver| {
+        match receiver
+            .connection
+            .read_encrypted_iris_message(&*receiver.cipher)?
+        {
+            IrisMessage::FileStartAtPos { start_pos } => {
+                receiver.start_pos = Some(start_pos);
+                report(
+                    progress_communication,
+                    receiver.receiver,
+                    SenderProgressMessage::ChunkSent { size: start_pos },
+                );
+            }
+            IrisMessage::FileSkipped => {
+                report(
+                    progress_communication,
+                    receiver.receiver,
+                    SenderProgressMessage::FileSkipped,
+                );
+            }
+            _ => return Err(IrisError::UnexpectedMessage),
+        }
+        Ok(())
+    })?;
+
+    let mut start_positions: Vec<u64> = receivers
+        .iter()
+        .filter_map(|receiver| receiver.start_pos)
+        .collect();
+    start_positions.sort_unstable();
+    start_positions.dedup();
+
+    for start_pos in start_positions {
+        let mut file = File::open(file_path)
+            .map_err(|_| IrisError::PermissionsUserIOError(file_path.display().to_string()))?;
+        file.seek(SeekFrom::Start(start_pos))
+            .map_err(|_| IrisError::PermissionsUserIOError(file_path.display().to_string()))?;
+
+        while let Ok(bytes_read) = file.read(&mut buffer[..]) {
+            if bytes_read == 0 {
+                break;
+            }
+            tracing::debug!("read {bytes_read} bytes");
+            let chunk = &buffer[..bytes_read];
+            // Every receiver gets the chunk before any of them is waited on, so that they
+            // all get to work on it at the same time
+            for_each_receiver(receivers, progress_communication, |receiver| {
+                if receiver.start_pos != Some(start_pos) {
+                    return Ok(());
+                }
+                receiver
+                    .connection
+                    .write_encrypted_message(&*receiver.cipher, chunk)?;
+                report(
+                    progress_communication,
+                    receiver.receiver,
+                    SenderProgressMessage::ChunkSent {
+                        size: u64::from_usize(bytes_read),
+                    },
+                );
+                Ok(())
+            })?;
+            for_each_receiver(receivers, progress_communication, |receiver| {
+                if receiver.start_pos != Some(start_pos) {
+                    return Ok(());
+                }
+                match receiver
+                    .connection
+                    .read_encrypted_iris_message(&*receiver.cipher)?
+                {
+                    IrisMessage::ChunkReceived { is_last } => {
+                        if is_last {
+                            tracing::debug!(
+                                "last chunk received by receiver {}",
+                                receiver.receiver
+                            );
+                            receiver.start_pos = None;
+                        }
+                        Ok(())
+                    }
+                    _ => Err(IrisError::UnexpectedMessage),
+                }
+            })?;
+
+            if !receivers
+                .iter()
+                .any(|receiver| receiver.start_pos == Some(start_pos))
+            {
+                break;
+            }
+
+            if matches!(progress_communication.read()?, Some(WorkerMessage::Cancel)) {
+                tracing::debug!("exiting as user cancel");
+                std::process::exit(1);
+            }
+        }
+
+        for receiver in receivers.iter_mut() {
+            if receiver.start_pos == Some(start_pos) {
+                receiver.start_pos = None;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `step` for every receiver in turn, dropping the ones it fails for and reporting why.
+/// Fails once there are no receivers left.
+fn for_each_receiver(
+    receivers: &mut Vec<BroadcastReceiver>,
+    progress_communication: &SenderProgressCommunication,
+    mut step: impl FnMut(&mut BroadcastReceiver) -> Result<(), IrisError>,
+) -> Result<(), IrisError> {
+    receivers.retain_mut(|receiver| match step(receiver) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(
+                "dropping receiver {} from the broadcast: {e}",
+                receiver.receiver
+            );
+            report_error(progress_communication, receiver.receiver, e);
+            false
+        }
+    });
+
+    if receivers.is_empty() {
+        Err(IrisError::BroadcastFailed)
+    } else {
+        Ok(())
+    }
+}
+
+fn report(
+    progress_communication: &SenderProgressCommunication,
+    receiver: usize,
+    progress: SenderProgressMessage,
+) {
+    // Reporting progress cannot fail, the result is only there to match the other channels
+    let _ = progress_communication.write(SenderProgressMessage::ReceiverProgress {
+        receiver,
+        progress: Box::new(progress),
+    });
+}
+
+fn report_error(
+    progress_communication: &SenderProgressCommunication,
+    receiver: usize,
+    e: IrisError,
+) {
+    report(
+        progress_communication,
+        receiver,
+        SenderProgressMessage::Error(e),
+    );
+}
@@ -45,6 +45,66 @@ pub enum IrisError {
     /// Invalid passphrase may be due to improper format or bad room identifier.
     #[error("invalid passphrase given, please confirm the passphrase with the sender")]
     InvalidPassphrase,
+    /// The relay could not listen on the requested address.
+    #[error("unable to listen on {0}, please confirm the address is valid and not already in use")]
+    ListenerBindError(String),
+    /// The relay could not open its audit log.
+    #[error("unable to open the audit log at {0}, please confirm the path and its permissions")]
+    AuditLogError(String),
+    /// The relay could not use the directory it keeps mailboxes in.
+    #[error(
+        "unable to use the mailbox directory {0}, please confirm the path and its permissions"
+    )]
+    MailboxDirectoryError(String),
+    /// The relay's configuration is inconsistent or out of range.
+    #[error("invalid relay configuration: {0}")]
+    InvalidRelayConfig(String),
+    /// The relay stopped unexpectedly.
+    #[error("the relay stopped unexpectedly, please reach out to the developer")]
+    ServerError,
+    /// The relay restricts access and did not accept the given access token, or none was given.
+    #[error("the relay refused access, please confirm the access token")]
+    RelayAccessDenied,
+    /// The relay has no room left for another sender or transfer.
+    #[error("the relay is full, please try again later")]
+    RelayFull,
+    /// Nobody joined the room before the relay closed it.
+    #[error("nobody joined the room in time and it expired, please try again")]
+    RoomExpired,
+    /// The room was closed before the transfer started, by the relay's operator or, for the
+    /// receivers of a broadcast, by its sender going away.
+    #[error("the room was closed before the transfer started, please try again")]
+    RoomClosed,
+    /// Both ends of the room meant to send, or both meant to receive.
+    #[error("both ends of the room want to send, or both want to receive")]
+    RoleConflict,
+    /// Every receiver of a broadcast failed, the progress messages tell why each of them did.
+    #[error("none of the receivers got the files, please try again")]
+    BroadcastFailed,
+    /// The relay is shutting down and does not take new transfers.
+    #[error("the relay is shutting down, please try again later")]
+    RelayDraining,
+    /// The relay is turning away this address for making too many requests or failing to join
+    /// rooms too often.
+    #[error("the relay is limiting requests from this address, please try again in {0} seconds")]
+    RateLimited(u64),
+    /// The relay ended the transfer as it went over the amount of data a single transfer may
+    /// send.
+    #[error("the transfer is larger than the relay allows, please send fewer files at once")]
+    SessionSizeExceeded,
+    /// The relay ended the transfer as it went on for longer than a single transfer may last.
+    #[error("the transfer took longer than the relay allows, please send fewer files at once")]
+    SessionDurationExceeded,
+    /// The relay ended the transfer as nothing went through it for longer than it allows.
+    #[error("the transfer stalled for longer than the relay allows, please try again")]
+    SessionIdleTimeout,
+    /// The relay ended the transfer, or refused to start it, as this address or the other
+    /// party's used up its quota for the day.
+    #[error("the daily transfer quota on the relay is used up, please try again tomorrow")]
+    DailyQuotaExceeded,
+    /// The relay does not keep files for receivers that are not online.
+    #[error("the relay does not keep files for offline receivers, please use another relay")]
+    MailboxUnavailable,
     /// The parameter for the finish() method is incorrect signaling either a bug or malicious activity.
     #[error("error completing the key exchange, please reach out to the developer")]
     SpakeError(spake2::Error),
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// stands for just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        if prefix_len > max_prefix_len(addr) {
+            return Err(format!("/{prefix_len} is too long a prefix for {addr}"));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients show up as IPv4-mapped IPv6 addresses on dual-stack sockets
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{s} is not an IP address or network"))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .map_err(|_| format!("{s} does not have a valid prefix length"))?,
+            None => max_prefix_len(addr),
+        };
+
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn prefix_matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let remaining_bits = prefix_len % 8;
+    if network[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = u8::MAX << (8 - remaining_bits);
+    network[full_bytes] & mask == addr[full_bytes] & mask
+}
@@ -1,21 +1,25 @@
 use std::io::{BufReader, Read, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::errors::IrisError;
 use crate::iris_stream::{EncryptedIrisStream, IrisStream, IrisStreamEssentials};
+use crate::socket::Socket;
 
 pub struct IrisTcpStream {
-    stream: TcpStream,
-    buffered_stream: BufReader<TcpStream>,
+    // Shared with whoever keeps an eye on the connection, see `IrisTcpStream::socket`
+    stream: Arc<Socket>,
+    buffered_stream: BufReader<Socket>,
 }
 
 impl IrisTcpStream {
-    pub fn new(stream: TcpStream) -> Result<Self, IrisError> {
+    pub fn new(stream: Socket) -> Result<Self, IrisError> {
         let stream_clone = stream
             .try_clone()
             .map_err(|_| IrisError::StreamInitializationError)?;
         Ok(Self {
-            stream,
+            stream: Arc::new(stream),
             buffered_stream: BufReader::new(stream_clone),
         })
     }
@@ -31,8 +35,8 @@ impl IrisTcpStream {
             .map_err(|_| IrisError::StreamInitializationError)?;
 
         Ok(IrisTcpStream {
-            stream,
-            buffered_stream: BufReader::new(stream_clone),
+            stream: Arc::new(Socket::Tcp(stream)),
+            buffered_stream: BufReader::new(Socket::Tcp(stream_clone)),
         })
     }
 
@@ -40,10 +44,40 @@ impl IrisTcpStream {
         let stream = self.stream.try_clone()?;
         let stream_clone = stream.try_clone()?;
         Ok(Self {
-            stream,
+            stream: Arc::new(stream),
             buffered_stream: BufReader::new(stream_clone),
         })
     }
+
+    /// Returns another handle to the underlying socket, e.g. to shut it down from another thread.
+    pub fn try_clone_stream(&self) -> Result<Socket, std::io::Error> {
+        self.stream.try_clone()
+    }
+
+    /// Another handle to the write half, which unlike [`IrisTcpStream::try_clone_stream`] costs
+    /// neither a system call nor a file descriptor.
+    pub fn socket(&self) -> Arc<Socket> {
+        Arc::clone(&self.stream)
+    }
+
+    /// Splits the stream into its buffered read half and its write half. Any bytes already
+    /// buffered by the read half are kept, so nothing read ahead is lost.
+    pub fn into_split(self) -> (BufReader<Socket>, Arc<Socket>) {
+        (self.buffered_stream, self.stream)
+    }
+
+    /// Gives access to the read half, e.g. to parse something other than Iris messages off it.
+    pub fn buffered_reader(&mut self) -> &mut BufReader<Socket> {
+        &mut self.buffered_stream
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.stream.set_write_timeout(timeout)
+    }
 }
 
 impl IrisStreamEssentials for IrisTcpStream {
@@ -57,10 +91,10 @@ impl IrisStreamEssentials for IrisTcpStream {
     }
 
     fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), IrisError> {
-        self.stream
+        (&*self.stream)
             .write_all(bytes)
             .map_err(|_| IrisError::UserConnectionWriteError)?;
-        self.stream
+        (&*self.stream)
             .flush()
             .map_err(|_| IrisError::UserConnectionWriteError)
     }
@@ -1,24 +1,40 @@
+mod access_token;
+mod broadcast;
 mod cipher;
 mod constants;
 mod default_wordlist;
 mod errors;
 mod files;
+mod ip_network;
 #[doc(hidden)]
 pub mod iris_channel_stream;
 pub mod iris_stream;
 mod iris_tcp_stream;
+mod mailbox;
+mod namespace;
+mod pairing;
 mod passphrase;
 mod progress;
 mod receiver;
+mod relay_connection;
 mod room_mapping;
 mod sender;
 mod server;
+mod socket;
 
 use serde::{Deserialize, Serialize};
 
+use crate::access_token::{CHALLENGE_SIZE, RESPONSE_SIZE};
+
+pub use crate::access_token::AccessToken;
+pub use crate::broadcast::{simple_broadcast, BroadcastOptions};
 pub use crate::cipher::CipherType;
 pub use crate::default_wordlist::WORDLIST;
 pub use crate::errors::IrisError;
+pub use crate::ip_network::IpNetwork;
+pub use crate::mailbox::{simple_receive_from_mailbox, simple_send_to_mailbox};
+pub use crate::namespace::{Namespace, NamespaceLimits, MAX_NAMESPACE_LEN};
+pub use crate::pairing::Role;
 pub use crate::passphrase::{
     get_passphrase_from_str_wordlist, get_passphrase_from_string_wordlist,
 };
@@ -28,13 +44,29 @@ pub use crate::progress::{
     SenderProgressCommunication, SenderProgressMessage, SenderWorkerCommunication, WorkerMessage,
 };
 pub use crate::receiver::{receive, simple_receive, ConflictingFileMode};
-use crate::room_mapping::RoomIdentifier;
-pub use crate::sender::{send, simple_send};
-pub use crate::server::serve;
+pub use crate::relay_connection::RelayConnectionOptions;
+use crate::room_mapping::{RoomIdentifier, SENDER_TOKEN_SIZE};
+pub use crate::sender::{send, simple_send, simple_send_reusable, ReusableRoomOptions};
+pub use crate::server::{
+    serve, serve_with_config, AuditLogConfig, ClusterConfig, ListenerAddr, MailboxConfig,
+    RelayBuilder, RelayConfig, RelayHandle, WebhookConfig,
+};
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone, Copy)]
 pub enum IrisMessage {
     Acknowledge,
+    Authenticate,
+    AuthenticationChallenge {
+        challenge: [u8; CHALLENGE_SIZE],
+    },
+    AuthenticationResponse {
+        response: [u8; RESPONSE_SIZE],
+    },
+    Authenticated,
+    AccessDenied,
+    EnteringNamespace {
+        namespace: Namespace,
+    },
     SenderConnecting,
     AssignedRoomIdentifier {
         room_identifier: RoomIdentifier,
@@ -43,6 +75,36 @@ pub enum IrisMessage {
         room_identifier: RoomIdentifier,
     },
     ReceiverConnected,
+    CreatingRoom,
+    JoiningRoom {
+        room_identifier: RoomIdentifier,
+    },
+    PeerConnected {
+        peer_role: Option<Role>,
+    },
+    PeerRole {
+        role: Role,
+    },
+    BroadcastSenderConnecting {
+        max_receivers: usize,
+        join_window_secs: u64,
+    },
+    BroadcastStarted {
+        total_receivers: usize,
+        token: [u8; SENDER_TOKEN_SIZE],
+    },
+    ReusableSenderConnecting {
+        max_uses: u32,
+        lifetime_secs: u64,
+    },
+    ReceiverWaiting {
+        uses_left: u32,
+        token: [u8; SENDER_TOKEN_SIZE],
+    },
+    SenderJoining {
+        room_identifier: RoomIdentifier,
+        token: [u8; SENDER_TOKEN_SIZE],
+    },
     SetCipherType {
         cipher_type: CipherType,
     },
@@ -63,4 +125,28 @@ pub enum IrisMessage {
     UnexpectedMessage,
     ServerError,
     BadRoomIdentifier,
+    RelayFull,
+    RoomExpired,
+    RoomClosed,
+    RelayDraining,
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    SessionSizeExceeded,
+    SessionDurationExceeded,
+    SessionIdleTimeout,
+    DailyQuotaExceeded,
+    AskingHowSessionEnded {
+        room_identifier: RoomIdentifier,
+    },
+    SessionEndUnknown,
+    MailboxUploading,
+    MailboxStored {
+        expires_in_secs: u64,
+    },
+    MailboxDownloading {
+        room_identifier: RoomIdentifier,
+    },
+    MailboxKeyConfirmed,
+    MailboxUnavailable,
 }
@@ -0,0 +1,418 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use usize_cast::{FromUsize, IntoUsize};
+
+use crate::cipher::{get_cipher, Cipher, CipherType};
+use crate::constants::CHUNK_SIZE;
+use crate::errors::IrisError;
+use crate::files::{FileMetadata, FileType};
+use crate::iris_stream::{EncryptedIrisStream, IrisStream};
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::{room_identity, Namespace};
+use crate::progress::{
+    ReceiverProgressCommunication, ReceiverProgressMessage, SenderProgressCommunication,
+    SenderProgressMessage, WorkerMessage,
+};
+use crate::receiver::{create_directory, get_file_and_start_pos, ConflictingFileMode};
+use crate::relay_connection::{connect_to_relay, session_limit_error, RelayConnectionOptions};
+use crate::room_mapping::RoomIdentifier;
+use crate::sender::get_complete_file_list_and_total_size;
+use crate::IrisMessage;
+
+/// Size of the random salt at the start of the key confirmation blob.
+pub(crate) const SALT_SIZE: usize = 16;
+
+/// Size of the password the relay runs the PAKE with, which follows the salt in the key
+/// confirmation blob.
+pub(crate) const PAKE_PASSWORD_SIZE: usize = 32;
+
+/// PBKDF2 rounds the key protecting a mailbox is derived with, to slow down guessing the
+/// passphrase of a mailbox.
+const KEY_DERIVATION_ROUNDS: u32 = 600_000;
+
+/// Uploads `files` to a mailbox on the relay for a receiver to download later on, with
+/// [`simple_receive_from_mailbox`], until the relay lets the mailbox expire.
+///
+/// A key exchange needs both parties online, so the files are encrypted with a random key
+/// instead. The sender leaves that key in a key confirmation blob, wrapped with a key derived
+/// from the passphrase, the namespace and the mailbox's identifier, along with a password
+/// derived the same way. The relay runs the PAKE against that password with the receiver and
+/// only hands out the wrapped key to a receiver that confirms the key it got out of it, so each
+/// guess of the passphrase takes a round trip that counts towards the rate limits of the relay.
+/// The relay never sees the files or their key.
+///
+/// The relay does store the PAKE password, though, and can check guesses of the passphrase
+/// against it offline, without any rate limit. The slow key derivation makes each guess
+/// expensive, but a weak passphrase only keeps the files from an operator of the relay as
+/// long as it takes to guess it. Use a strong passphrase for a relay you do not trust.
+#[allow(clippy::too_many_arguments)]
+pub fn simple_send_to_mailbox(
+    server_ip: String,
+    server_port: String,
+    relay_connection_options: &RelayConnectionOptions,
+    cipher_type: CipherType,
+    passphrase: &str,
+    files: Vec<PathBuf>,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+    server_connection.write_iris_message(IrisMessage::MailboxUploading)?;
+
+    let room_identifier = match server_connection.read_iris_message()? {
+        IrisMessage::AssignedRoomIdentifier { room_identifier } => room_identifier,
+        IrisMessage::MailboxUnavailable => return Err(IrisError::MailboxUnavailable),
+        IrisMessage::RelayFull => return Err(IrisError::RelayFull),
+        IrisMessage::AccessDenied => return Err(IrisError::RelayAccessDenied),
+        IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            return Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => return Err(IrisError::DailyQuotaExceeded),
+        _ => return Err(IrisError::UnexpectedMessage),
+    };
+    tracing::info!("download using {room_identifier}-{passphrase}");
+    progress_communication
+        .write(SenderProgressMessage::AssignedRoomIdentifier { room_identifier })?;
+
+    if let Err(e) = upload(
+        &mut server_connection,
+        relay_connection_options.namespace,
+        room_identifier,
+        cipher_type,
+        passphrase,
+        files,
+        progress_communication,
+    ) {
+        // The relay may have stopped the upload and said why before hanging up
+        return Err(server_connection
+            .read_iris_message()
+            .ok()
+            .and_then(|message| match message {
+                IrisMessage::RelayFull => Some(IrisError::RelayFull),
+                message => session_limit_error(&message),
+            })
+            .unwrap_or(e));
+    }
+
+    match server_connection.read_iris_message()? {
+        IrisMessage::MailboxStored { expires_in_secs } => {
+            tracing::info!("the files are kept for {expires_in_secs} seconds");
+            progress_communication.write(SenderProgressMessage::MailboxStored { expires_in_secs })
+        }
+        IrisMessage::RelayFull => Err(IrisError::RelayFull),
+        IrisMessage::SessionSizeExceeded => Err(IrisError::SessionSizeExceeded),
+        IrisMessage::SessionDurationExceeded => Err(IrisError::SessionDurationExceeded),
+        IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
+        _ => Err(IrisError::UnexpectedMessage),
+    }
+}
+
+/// Writes the whole mailbox: the cipher type and the key confirmation blob, made of the salt,
+/// the PAKE password and the wrapped key, in the clear, the files encrypted, and the empty
+/// message that tells the relay the upload is complete.
+fn upload(
+    server_connection: &mut IrisTcpStream,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+    cipher_type: CipherType,
+    passphrase: &str,
+    files: Vec<PathBuf>,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    server_connection.write_iris_message(IrisMessage::SetCipherType { cipher_type })?;
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let (wrapping_key, pake_password) = derive_keys(passphrase, namespace, room_identifier, &salt);
+    let key_cipher = get_cipher(cipher_type, &wrapping_key)?;
+    let key = key_cipher.generate_key();
+    server_connection.write_size_prefixed_message(
+        &[&salt, &*pake_password, &*key_cipher.encrypt(&key)?].concat(),
+    )?;
+    let cipher = get_cipher(cipher_type, &key)?;
+    progress_communication.write(SenderProgressMessage::SetCipher { cipher_type })?;
+
+    let (complete_file_list, total_size) = get_complete_file_list_and_total_size(files)?;
+    tracing::info!(
+        "going to upload {total_size} bytes distributed among {} files",
+        complete_file_list.len()
+    );
+    progress_communication.write(SenderProgressMessage::TransferMetadata {
+        total_files: complete_file_list.len(),
+        total_bytes: total_size,
+    })?;
+    server_connection.write_encrypted_iris_message(
+        &*cipher,
+        IrisMessage::TransferMetadata {
+            total_files: complete_file_list.len(),
+            total_bytes: total_size,
+        },
+    )?;
+
+    let mut buffer = vec![0; CHUNK_SIZE.into_usize()];
+    for (file_path, file_metadata) in &complete_file_list {
+        tracing::debug!("uploading {file_path:?}");
+        progress_communication.write(SenderProgressMessage::FileMetadata {
+            filename: file_metadata.get_filename().to_path_buf(),
+            file_size: file_metadata.get_size(),
+        })?;
+        let serialized_file_metadata =
+            serde_json::to_vec(&file_metadata).map_err(|_| IrisError::SerializationError)?;
+        server_connection.write_encrypted_message(&*cipher, &serialized_file_metadata)?;
+
+        if let FileType::File = file_metadata.get_file_type() {
+            let mut file = File::open(file_path)
+                .map_err(|_| IrisError::PermissionsUserIOError(file_path.display().to_string()))?;
+            loop {
+                let bytes_read = file.read(&mut buffer).map_err(|_| {
+                    IrisError::PermissionsUserIOError(file_path.display().to_string())
+                })?;
+                if bytes_read == 0 {
+                    break;
+                }
+                server_connection.write_encrypted_message(&*cipher, &buffer[..bytes_read])?;
+                progress_communication.write(SenderProgressMessage::ChunkSent {
+                    size: u64::from_usize(bytes_read),
+                })?;
+            }
+        }
+
+        if matches!(progress_communication.read()?, Some(WorkerMessage::Cancel)) {
+            tracing::debug!("exiting as user cancel");
+            std::process::exit(1);
+        }
+    }
+
+    server_connection.write_size_prefixed_message(&[])
+}
+
+/// Downloads the files in the mailbox `room_identifier_str` that a sender uploaded with
+/// [`simple_send_to_mailbox`].
+pub fn simple_receive_from_mailbox(
+    server_ip: String,
+    server_port: String,
+    relay_connection_options: &RelayConnectionOptions,
+    room_identifier_str: &str,
+    passphrase: &str,
+    conflicting_file_mode: ConflictingFileMode,
+    progress_communication: &ReceiverProgressCommunication,
+) -> Result<(), IrisError> {
+    let room_identifier = room_identifier_str
+        .parse::<RoomIdentifier>()
+        .map_err(|_| IrisError::InvalidPassphrase)?;
+    tracing::debug!("downloading mailbox #{room_identifier}");
+
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+    server_connection.write_iris_message(IrisMessage::MailboxDownloading { room_identifier })?;
+
+    let cipher_type = match server_connection.read_iris_message()? {
+        IrisMessage::SetCipherType { cipher_type } => cipher_type,
+        IrisMessage::BadRoomIdentifier => return Err(IrisError::InvalidPassphrase),
+        IrisMessage::MailboxUnavailable => return Err(IrisError::MailboxUnavailable),
+        IrisMessage::RelayFull => return Err(IrisError::RelayFull),
+        IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+        IrisMessage::AccessDenied => return Err(IrisError::RelayAccessDenied),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            return Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => return Err(IrisError::DailyQuotaExceeded),
+        _ => return Err(IrisError::UnexpectedMessage),
+    };
+    let cipher = confirm_key(
+        &mut server_connection,
+        cipher_type,
+        passphrase,
+        relay_connection_options.namespace,
+        room_identifier,
+    )?;
+    progress_communication.write(ReceiverProgressMessage::SetCipher { cipher_type })?;
+
+    let IrisMessage::TransferMetadata {
+        total_files,
+        total_bytes,
+    } = server_connection.read_encrypted_iris_message(&*cipher)?
+    else {
+        return Err(IrisError::UnexpectedMessage);
+    };
+    tracing::info!("going to download {total_bytes} bytes distributed among {total_files} files");
+    progress_communication.write(ReceiverProgressMessage::TransferMetadata {
+        total_files,
+        total_bytes,
+    })?;
+
+    let mut files_downloaded = 0;
+    loop {
+        let raw_file_metadata = server_connection.read_size_prefixed_message()?;
+        // The relay ends the mailbox with an empty message
+        if raw_file_metadata.is_empty() {
+            break;
+        }
+        let file_metadata =
+            serde_json::from_slice::<FileMetadata>(&cipher.decrypt(&raw_file_metadata)?)
+                .map_err(|_| IrisError::DeserializationError)?;
+        tracing::debug!("received the following metadata: {file_metadata:?}");
+        progress_communication.write(ReceiverProgressMessage::FileMetadata {
+            filename: file_metadata.get_filename().to_path_buf(),
+            file_size: file_metadata.get_size(),
+        })?;
+
+        match file_metadata.get_file_type() {
+            FileType::Directory => {
+                if create_directory(file_metadata.get_filename(), conflicting_file_mode)? {
+                    progress_communication.write(ReceiverProgressMessage::DirectoryCreated)?;
+                } else {
+                    progress_communication.write(ReceiverProgressMessage::FileSkipped)?;
+                }
+            }
+            FileType::File => download_file(
+                &mut server_connection,
+                &*cipher,
+                &file_metadata,
+                conflicting_file_mode,
+                progress_communication,
+            )?,
+        }
+        files_downloaded += 1;
+
+        if matches!(progress_communication.read()?, Some(WorkerMessage::Cancel)) {
+            tracing::debug!("exiting as user cancel");
+            std::process::exit(1);
+        }
+    }
+
+    // Every file was uploaded before the mailbox was stored, so any missing one went missing
+    // on the relay
+    if files_downloaded != total_files {
+        return Err(IrisError::UnexpectedMessage);
+    }
+    Ok(())
+}
+
+/// Reads every chunk of a file, writing out the part the receiver does not have yet, or none of
+/// it if the file is skipped.
+fn download_file(
+    server_connection: &mut IrisTcpStream,
+    cipher: &dyn Cipher,
+    file_metadata: &FileMetadata,
+    conflicting_file_mode: ConflictingFileMode,
+    progress_communication: &ReceiverProgressCommunication,
+) -> Result<(), IrisError> {
+    let mut file_and_start_pos =
+        get_file_and_start_pos(file_metadata.get_filename(), conflicting_file_mode)?;
+    if file_and_start_pos.is_none() {
+        progress_communication.write(ReceiverProgressMessage::FileSkipped)?;
+    }
+
+    let mut pos = 0;
+    while pos < file_metadata.get_size() {
+        let file_chunk = server_connection.read_encrypted_message(cipher)?;
+        let chunk_size = u64::from_usize(file_chunk.len());
+        if let Some((file, start_pos)) = &mut file_and_start_pos {
+            let skipped_bytes = start_pos.saturating_sub(pos).min(chunk_size);
+            file.write_chunk(&file_chunk[skipped_bytes.into_usize()..])?;
+            progress_communication
+                .write(ReceiverProgressMessage::ChunkReceived { size: chunk_size })?;
+        }
+        pos += chunk_size;
+    }
+    Ok(())
+}
+
+/// Proves to the relay that the receiver knows the passphrase, through the PAKE the relay runs
+/// against the key confirmation blob, and unwraps the key the files were encrypted with that the
+/// relay hands out in return.
+fn confirm_key(
+    server_connection: &mut IrisTcpStream,
+    cipher_type: CipherType,
+    passphrase: &str,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+) -> Result<Box<dyn Cipher>, IrisError> {
+    let salt = server_connection.read_size_prefixed_message()?;
+    let (wrapping_key, pake_password) = derive_keys(passphrase, namespace, room_identifier, &salt);
+
+    let (spake, outbound_message) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(&pake_password),
+        &Identity::new(pake_identity(namespace, room_identifier).as_bytes()),
+    );
+    server_connection.write_size_prefixed_message(&outbound_message)?;
+    let relay_message = server_connection.read_size_prefixed_message()?;
+    let key = spake
+        .finish(&relay_message)
+        .map_err(IrisError::SpakeError)?;
+    server_connection
+        .write_size_prefixed_message(&key_confirmation_mac(&key).finalize().into_bytes())?;
+
+    match server_connection.read_iris_message()? {
+        IrisMessage::MailboxKeyConfirmed => {}
+        IrisMessage::BadRoomIdentifier => return Err(IrisError::InvalidPassphrase),
+        _ => return Err(IrisError::UnexpectedMessage),
+    }
+    let wrapped_key = server_connection.read_size_prefixed_message()?;
+    let key = get_cipher(cipher_type, &wrapping_key)?
+        .decrypt(&wrapped_key)
+        .map_err(|_| IrisError::InvalidPassphrase)?;
+    get_cipher(cipher_type, &key)
+}
+
+/// The identity both ends of the PAKE of a mailbox use.
+pub(crate) fn pake_identity(
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+) -> String {
+    room_identity("iris-mailbox", namespace, room_identifier)
+}
+
+/// The MAC with which a receiver proves that it got the same key out of the PAKE as the relay.
+pub(crate) fn key_confirmation_mac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length, so this cannot fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"iris-mailbox-key-confirmation");
+    mac
+}
+
+/// The key the key of the files is wrapped with and the password of the PAKE, both derived
+/// from the passphrase with [`derive_key`] but neither giving away the other.
+fn derive_keys(
+    passphrase: &str,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+    salt: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let master_key = derive_key(passphrase, namespace, room_identifier, salt);
+    let subkey = |label: &[u8]| {
+        // HMAC accepts keys of any length, so this cannot fail
+        let mut mac = Hmac::<Sha256>::new_from_slice(&master_key).unwrap();
+        mac.update(label);
+        mac.finalize().into_bytes().to_vec()
+    };
+    (subkey(b"wrapping-key"), subkey(b"pake-password"))
+}
+
+/// PBKDF2-HMAC-SHA256 of the passphrase, salted with `salt`, the namespace and the mailbox's
+/// identifier so that the key confirmation blob is tied to the mailbox it was made for.
+fn derive_key(
+    passphrase: &str,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+    salt: &[u8],
+) -> Vec<u8> {
+    let salt = [salt, pake_identity(namespace, room_identifier).as_bytes()].concat();
+    let mut key = [0; 32];
+    pbkdf2_hmac::<Sha256>(
+        passphrase.as_bytes(),
+        &salt,
+        KEY_DERIVATION_ROUNDS,
+        &mut key,
+    );
+    key.to_vec()
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::room_mapping::RoomIdentifier;
+
+/// Longest namespace, in bytes.
+pub const MAX_NAMESPACE_LEN: usize = 32;
+
+/// Keeps the rooms of an application apart from those of every other application sharing the
+/// relay, e.g. `ci-artifacts`.
+///
+/// Namespaces are made of lowercase ASCII letters, digits, `-`, `_` and `.`, and are at most
+/// [`MAX_NAMESPACE_LEN`] bytes long, which keeps them fixed-size on the wire.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Namespace {
+    bytes: [u8; MAX_NAMESPACE_LEN],
+    len: u8,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Result<Self, String> {
+        if name.is_empty() || name.len() > MAX_NAMESPACE_LEN {
+            return Err(format!(
+                "namespace {name:?} must be between 1 and {MAX_NAMESPACE_LEN} bytes long"
+            ));
+        }
+        if !name.bytes().all(|byte| {
+            byte.is_ascii_lowercase() || byte.is_ascii_digit() || b"-_.".contains(&byte)
+        }) {
+            return Err(format!(
+                "namespace {name:?} may only contain lowercase letters, digits, '-', '_' and '.'"
+            ));
+        }
+
+        let mut bytes = [0; MAX_NAMESPACE_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever made from ASCII
+        std::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap()
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Namespace {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(&s)
+    }
+}
+
+impl From<Namespace> for String {
+    fn from(namespace: Namespace) -> Self {
+        namespace.as_str().to_string()
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Limits a relay holds the rooms of a single namespace to, on top of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceLimits {
+    pub namespace: Namespace,
+    /// Maximum number of rooms of the namespace waiting for their peer at the same time.
+    pub max_waiting_rooms: Option<usize>,
+    /// Number of transfers of the namespace being relayed at the same time, mailbox uploads and
+    /// downloads included, after which new rooms, pairs and mailbox transfers of the namespace
+    /// are turned away.
+    pub max_sessions: Option<usize>,
+}
+
+impl NamespaceLimits {
+    /// No limits beyond those of the relay, until some are set.
+    pub fn new(namespace: Namespace) -> Self {
+        Self {
+            namespace,
+            max_waiting_rooms: None,
+            max_sessions: None,
+        }
+    }
+}
+
+/// What both ends of a room bind their keys to, `prefix` telling what the keys are for.
+///
+/// Clients in different namespaces end up with different keys even if they were somehow
+/// paired. Without a namespace, the identity is the one clients used before namespaces
+/// existed, so that they still agree with each other.
+pub fn room_identity(
+    prefix: &str,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+) -> String {
+    match namespace {
+        // A namespace never contains '/', so no two namespaces can make up the same identity
+        Some(namespace) => format!("{prefix}/{namespace}/{room_identifier}"),
+        None => format!("{prefix}-{room_identifier}"),
+    }
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+use crate::errors::IrisError;
+use crate::iris_stream::IrisStream;
+use crate::IrisMessage;
+
+/// The part a client plays in a transfer.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone, Copy)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Waits for the relay to pair a client that created or joined a room regardless of roles, and
+/// makes sure that its peer plays the other part.
+pub fn wait_for_peer(connection: &mut dyn IrisStream, role: Role) -> Result<(), IrisError> {
+    match connection.read_iris_message()? {
+        IrisMessage::PeerConnected { peer_role } => settle_roles(connection, role, peer_role),
+        IrisMessage::BadRoomIdentifier => Err(IrisError::InvalidPassphrase),
+        IrisMessage::RoomExpired => Err(IrisError::RoomExpired),
+        IrisMessage::RoomClosed => Err(IrisError::RoomClosed),
+        IrisMessage::RelayFull => Err(IrisError::RelayFull),
+        IrisMessage::RelayDraining => Err(IrisError::RelayDraining),
+        IrisMessage::AccessDenied => Err(IrisError::RelayAccessDenied),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
+        _ => Err(IrisError::UnexpectedMessage),
+    }
+}
+
+/// `peer_role` is what the relay said the peer is. A peer that asked for the room as a sender or
+/// a receiver has nothing more to say, otherwise both ends tell each other their role.
+fn settle_roles(
+    connection: &mut dyn IrisStream,
+    role: Role,
+    peer_role: Option<Role>,
+) -> Result<(), IrisError> {
+    let peer_role = match peer_role {
+        Some(peer_role) => peer_role,
+        None => {
+            connection.write_iris_message(IrisMessage::PeerRole { role })?;
+            match connection.read_iris_message()? {
+                IrisMessage::PeerRole { role } => role,
+                _ => return Err(IrisError::UnexpectedMessage),
+            }
+        }
+    };
+
+    if peer_role == role {
+        return Err(IrisError::RoleConflict);
+    }
+    Ok(())
+}
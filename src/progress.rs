@@ -27,10 +27,31 @@ pub enum SenderProgressMessage {
     DirectoryCreated,
     FileSkipped,
     Error(IrisError),
+    /// A receiver joined a reusable room, which may be used this many more times.
+    ReceiverWaiting {
+        uses_left: u32,
+    },
+    /// A broadcast started with this many receivers.
+    ReceiversJoined {
+        total_receivers: usize,
+    },
+    /// Progress of a single receiver of a broadcast, numbered from 0.
+    ReceiverProgress {
+        receiver: usize,
+        progress: Box<SenderProgressMessage>,
+    },
+    /// The relay stored the files in a mailbox, which it keeps for this many seconds.
+    MailboxStored {
+        expires_in_secs: u64,
+    },
 }
 
 #[derive(Debug)]
 pub enum ReceiverProgressMessage {
+    /// The receiver created a room for a sender to join.
+    AssignedRoomIdentifier {
+        room_identifier: RoomIdentifier,
+    },
     SetCipher {
         cipher_type: CipherType,
     },
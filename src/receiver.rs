@@ -9,8 +9,10 @@ use crate::constants::CHUNK_SIZE;
 use crate::errors::IrisError;
 use crate::files::{File, FileMetadata, FileType};
 use crate::iris_stream::{EncryptedIrisStream, IrisStream};
-use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::{room_identity, Namespace};
+use crate::pairing::{wait_for_peer, Role};
 use crate::progress::{ReceiverProgressCommunication, ReceiverProgressMessage, WorkerMessage};
+use crate::relay_connection::{connect_to_relay, explain_disconnect, RelayConnectionOptions};
 use crate::room_mapping::RoomIdentifier;
 use crate::IrisMessage;
 
@@ -24,33 +26,91 @@ pub enum ConflictingFileMode {
     Error,
 }
 
+/// Receives files from the sender in the room `room_identifier_str`, or, if it is not given,
+/// creates a room for a sender to join and send files to.
 pub fn simple_receive(
     server_ip: String,
     server_port: String,
-    room_identifier_str: &str,
+    relay_connection_options: &RelayConnectionOptions,
+    room_identifier_str: Option<&str>,
     passphrase: &str,
     conflicting_file_mode: ConflictingFileMode,
     progress_communication: &ReceiverProgressCommunication,
 ) -> Result<(), IrisError> {
+    let Some(room_identifier_str) = room_identifier_str else {
+        let mut server_connection =
+            connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+        let room_identifier = create_room(&mut server_connection)?;
+        tracing::info!("connect using {room_identifier}-{passphrase}");
+        progress_communication
+            .write(ReceiverProgressMessage::AssignedRoomIdentifier { room_identifier })?;
+        wait_for_peer(&mut server_connection, Role::Receiver)?;
+        return receive(
+            &mut server_connection,
+            relay_connection_options.namespace,
+            room_identifier,
+            passphrase,
+            conflicting_file_mode,
+            progress_communication,
+        )
+        .map_err(|e| {
+            explain_disconnect(
+                &server_ip,
+                &server_port,
+                relay_connection_options,
+                room_identifier,
+                e,
+            )
+        });
+    };
+
     let room_identifier = room_identifier_str
         .parse::<RoomIdentifier>()
         .map_err(|_| IrisError::InvalidPassphrase)?;
     tracing::debug!("connecting to room #{room_identifier}");
 
-    let mut server_connection = IrisTcpStream::connect(format!("{server_ip}:{server_port}"))?;
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
     server_connection.write_iris_message(IrisMessage::ReceiverConnecting { room_identifier })?;
 
     receive(
         &mut server_connection,
+        relay_connection_options.namespace,
         room_identifier,
         passphrase,
         conflicting_file_mode,
         progress_communication,
     )
+    .map_err(|e| {
+        explain_disconnect(
+            &server_ip,
+            &server_port,
+            relay_connection_options,
+            room_identifier,
+            e,
+        )
+    })
+}
+
+/// Asks the relay for a room that a sender can join, regardless of roles.
+fn create_room(server_connection: &mut dyn IrisStream) -> Result<RoomIdentifier, IrisError> {
+    server_connection.write_iris_message(IrisMessage::CreatingRoom)?;
+    match server_connection.read_iris_message()? {
+        IrisMessage::AssignedRoomIdentifier { room_identifier } => Ok(room_identifier),
+        IrisMessage::RelayFull => Err(IrisError::RelayFull),
+        IrisMessage::AccessDenied => Err(IrisError::RelayAccessDenied),
+        IrisMessage::RelayDraining => Err(IrisError::RelayDraining),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
+        _ => Err(IrisError::UnexpectedMessage),
+    }
 }
 
 pub fn receive(
     server_connection: &mut dyn EncryptedIrisStream,
+    namespace: Option<Namespace>,
     room_identifier: RoomIdentifier,
     passphrase: &str,
     conflicting_file_mode: ConflictingFileMode,
@@ -59,7 +119,8 @@ pub fn receive(
     match server_connection.read_iris_message()? {
         IrisMessage::SetCipherType { cipher_type } => {
             tracing::debug!("using cipher: {cipher_type:?}");
-            let key = perform_key_exchange(server_connection, room_identifier, passphrase)?;
+            let key =
+                perform_key_exchange(server_connection, namespace, room_identifier, passphrase)?;
             progress_communication.write(ReceiverProgressMessage::SetCipher { cipher_type })?;
             tracing::info!("switching over to encrypted communication");
 
@@ -78,18 +139,28 @@ pub fn receive(
             )
         }
         IrisMessage::BadRoomIdentifier => Err(IrisError::InvalidPassphrase),
+        IrisMessage::RoomExpired => Err(IrisError::RoomExpired),
+        IrisMessage::RoomClosed => Err(IrisError::RoomClosed),
+        IrisMessage::RelayFull => Err(IrisError::RelayFull),
+        IrisMessage::RelayDraining => Err(IrisError::RelayDraining),
+        IrisMessage::AccessDenied => Err(IrisError::RelayAccessDenied),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
         _ => Err(IrisError::UnexpectedMessage),
     }
 }
 
 fn perform_key_exchange(
     server_connection: &mut dyn EncryptedIrisStream,
+    namespace: Option<Namespace>,
     room_identifier: RoomIdentifier,
     passphrase: &str,
 ) -> Result<Vec<u8>, IrisError> {
     let (s2, outbound_msg) = Spake2::<Ed25519Group>::start_symmetric(
         &Password::new(passphrase.as_bytes()),
-        &Identity::new(format!("iris-{room_identifier}").as_bytes()),
+        &Identity::new(room_identity("iris", namespace, room_identifier).as_bytes()),
     );
     server_connection.write_size_prefixed_message(&outbound_msg)?;
 
@@ -177,35 +248,40 @@ fn process_directory(
     conflicting_file_mode: ConflictingFileMode,
     progress_communication: &ReceiverProgressCommunication,
 ) -> Result<(), IrisError> {
+    if !create_directory(file_metadata.get_filename(), conflicting_file_mode)? {
+        progress_communication.write(ReceiverProgressMessage::FileSkipped)?;
+        return server_connection.write_encrypted_iris_message(cipher, IrisMessage::FileSkipped);
+    }
+
+    tracing::debug!("created directory");
+    progress_communication.write(ReceiverProgressMessage::DirectoryCreated)?;
+    server_connection.write_encrypted_iris_message(cipher, IrisMessage::DirectoryCreated)?;
+    Ok(())
+}
+
+/// Creates the directory `filename`, dealing with an existing one as `conflicting_file_mode`
+/// says, and returns whether it was created rather than skipped.
+pub(crate) fn create_directory(
+    filename: &Path,
+    conflicting_file_mode: ConflictingFileMode,
+) -> Result<bool, IrisError> {
     match conflicting_file_mode {
         ConflictingFileMode::Overwrite => {
-            let _ = std::fs::remove_dir_all(file_metadata.get_filename());
-            std::fs::create_dir(file_metadata.get_filename()).map_err(|_| {
-                IrisError::PermissionsUserIOError(
-                    file_metadata.get_filename().display().to_string(),
-                )
-            })?;
+            let _ = std::fs::remove_dir_all(filename);
+            std::fs::create_dir(filename)
+                .map_err(|_| IrisError::PermissionsUserIOError(filename.display().to_string()))?;
         }
         ConflictingFileMode::Skip | ConflictingFileMode::Resume => {
-            if std::fs::create_dir(file_metadata.get_filename()).is_err() {
-                progress_communication.write(ReceiverProgressMessage::FileSkipped)?;
-                return server_connection
-                    .write_encrypted_iris_message(cipher, IrisMessage::FileSkipped);
+            if std::fs::create_dir(filename).is_err() {
+                return Ok(false);
             }
         }
         ConflictingFileMode::Error => {
-            std::fs::create_dir(file_metadata.get_filename()).map_err(|_| {
-                IrisError::AlreadyExistsUserIOError(
-                    file_metadata.get_filename().display().to_string(),
-                )
-            })?;
+            std::fs::create_dir(filename)
+                .map_err(|_| IrisError::AlreadyExistsUserIOError(filename.display().to_string()))?;
         }
     }
-
-    tracing::debug!("created directory");
-    progress_communication.write(ReceiverProgressMessage::DirectoryCreated)?;
-    server_connection.write_encrypted_iris_message(cipher, IrisMessage::DirectoryCreated)?;
-    Ok(())
+    Ok(true)
 }
 
 fn process_file(
@@ -271,7 +347,7 @@ fn process_file(
     Ok(())
 }
 
-fn get_file_and_start_pos(
+pub(crate) fn get_file_and_start_pos(
     filename: &Path,
     conflicting_file_mode: ConflictingFileMode,
 ) -> Result<Option<(File, u64)>, IrisError> {
@@ -0,0 +1,102 @@
+use crate::access_token::compute_response;
+use crate::errors::IrisError;
+use crate::iris_stream::IrisStream;
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::Namespace;
+use crate::room_mapping::RoomIdentifier;
+use crate::socket::join_host_port;
+use crate::IrisMessage;
+
+/// Settings used by both senders and receivers when connecting to a relay.
+#[derive(Debug, Clone, Default)]
+pub struct RelayConnectionOptions {
+    /// Pre-shared token to authenticate with, required by relays that restrict access.
+    pub access_token: Option<String>,
+    /// Namespace of the application using the relay, keeping its rooms apart from those of
+    /// other applications. Both ends of a room have to use the same one.
+    pub namespace: Option<Namespace>,
+}
+
+pub fn connect_to_relay(
+    server_ip: &str,
+    server_port: &str,
+    options: &RelayConnectionOptions,
+) -> Result<IrisTcpStream, IrisError> {
+    let mut server_connection = IrisTcpStream::connect(join_host_port(server_ip, server_port))?;
+    if let Some(access_token) = &options.access_token {
+        authenticate(&mut server_connection, access_token)?;
+    }
+    if let Some(namespace) = options.namespace {
+        server_connection.write_iris_message(IrisMessage::EnteringNamespace { namespace })?;
+    }
+
+    Ok(server_connection)
+}
+
+/// Asks the relay how the session of `room_identifier` ended, for when the connection broke
+/// off in the middle of a transfer. The relay passes on the clients' bytes as they are, so
+/// cutting the connections is all it can do to a session going over its limits, and this is
+/// how the clients find out. Returns `error` unless that is what happened.
+pub(crate) fn explain_disconnect(
+    server_ip: &str,
+    server_port: &str,
+    options: &RelayConnectionOptions,
+    room_identifier: RoomIdentifier,
+    error: IrisError,
+) -> IrisError {
+    if !matches!(
+        error,
+        IrisError::UserConnectionReadError | IrisError::UserConnectionWriteError
+    ) {
+        return error;
+    }
+    connect_to_relay(server_ip, server_port, options)
+        .and_then(|mut server_connection| {
+            server_connection
+                .write_iris_message(IrisMessage::AskingHowSessionEnded { room_identifier })?;
+            server_connection.read_iris_message()
+        })
+        .ok()
+        .as_ref()
+        .and_then(session_limit_error)
+        .unwrap_or(error)
+}
+
+/// The error for a message of the relay saying that it ended a session for going over one of
+/// its limits.
+pub(crate) fn session_limit_error(message: &IrisMessage) -> Option<IrisError> {
+    match message {
+        IrisMessage::SessionSizeExceeded => Some(IrisError::SessionSizeExceeded),
+        IrisMessage::SessionDurationExceeded => Some(IrisError::SessionDurationExceeded),
+        IrisMessage::SessionIdleTimeout => Some(IrisError::SessionIdleTimeout),
+        IrisMessage::DailyQuotaExceeded => Some(IrisError::DailyQuotaExceeded),
+        _ => None,
+    }
+}
+
+pub(crate) fn authenticate(
+    server_connection: &mut IrisTcpStream,
+    access_token: &str,
+) -> Result<(), IrisError> {
+    server_connection.write_iris_message(IrisMessage::Authenticate)?;
+
+    match server_connection.read_iris_message()? {
+        IrisMessage::AuthenticationChallenge { challenge } => {
+            let response = compute_response(access_token, &challenge);
+            server_connection
+                .write_iris_message(IrisMessage::AuthenticationResponse { response })?;
+
+            match server_connection.read_iris_message()? {
+                IrisMessage::Authenticated => Ok(()),
+                IrisMessage::AccessDenied => Err(IrisError::RelayAccessDenied),
+                _ => Err(IrisError::UnexpectedMessage),
+            }
+        }
+        // The relay does not restrict access
+        IrisMessage::Authenticated => Ok(()),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            Err(IrisError::RateLimited(retry_after_secs))
+        }
+        _ => Err(IrisError::UnexpectedMessage),
+    }
+}
@@ -1,40 +1,460 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use rand::Rng;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
 
-use crate::iris_stream::EncryptedIrisStream;
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::Namespace;
+use crate::server::PeerAddr;
+use crate::socket::Socket;
+use crate::IrisMessage;
 
-pub type RoomIdentifier = u16;
+pub type RoomIdentifier = u64;
+
+/// Room identifiers longer than this do not fit in a [`RoomIdentifier`].
+pub const MAX_ROOM_IDENTIFIER_DIGITS: u32 = 19;
+
+/// Size of the secret a sender proves that the receivers of its room are its own with, when it
+/// connects once more for each of them.
+pub const SENDER_TOKEN_SIZE: usize = 16;
+
+pub type SenderToken = [u8; SENDER_TOKEN_SIZE];
+
+pub fn generate_sender_token() -> SenderToken {
+    let mut token = [0; SENDER_TOKEN_SIZE];
+    OsRng.fill_bytes(&mut token);
+    token
+}
+
+/// Compares the tokens in constant time, so that how long it takes does not give away how much
+/// of a guess was right.
+pub fn is_same_sender_token(token: &SenderToken, other: &SenderToken) -> bool {
+    token
+        .iter()
+        .zip(other)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// A sender waiting for its receiver, or for its receivers when broadcasting or reusing the
+/// room.
+pub struct Room {
+    pub socket: IrisTcpStream,
+    /// Where the sender connected from.
+    pub sender_addr: PeerAddr,
+    /// Namespace the sender connected in, only clients in the same one may join the room.
+    pub namespace: Option<Namespace>,
+    pub mode: RoomMode,
+    created_at: Instant,
+}
+
+/// How a room pairs its sender with receivers.
+pub enum RoomMode {
+    /// The sender is paired with the first receiver to join, which closes the room.
+    Single,
+    /// Like [`RoomMode::Single`], but the room was created regardless of roles and its two
+    /// clients settle which of them sends once they are paired.
+    RoleIndependent,
+    /// The sender waits for several receivers and sends to them all at once.
+    Broadcast(Broadcast),
+    /// The sender is paired with every receiver that joins, one after another.
+    Reusable(Reusable),
+}
+
+impl Room {
+    /// How long the sender has been waiting.
+    pub fn age(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    /// Whether receivers may still join the room.
+    pub fn is_open(&self) -> bool {
+        match &self.mode {
+            RoomMode::Reusable(reusable) => reusable.uses_left > 0,
+            _ => true,
+        }
+    }
+
+    /// Takes out the receivers that joined the room but were not paired with its sender yet.
+    pub fn take_receivers(&mut self) -> Vec<(IrisTcpStream, PeerAddr)> {
+        match &mut self.mode {
+            RoomMode::Single | RoomMode::RoleIndependent => Vec::new(),
+            RoomMode::Broadcast(broadcast) => std::mem::take(&mut broadcast.receivers),
+            RoomMode::Reusable(reusable) => std::mem::take(&mut reusable.receivers).into(),
+        }
+    }
+
+    fn is_expired(&self, ttl: Duration) -> bool {
+        match &self.mode {
+            RoomMode::Single | RoomMode::RoleIndependent => self.created_at.elapsed() >= ttl,
+            RoomMode::Broadcast(broadcast) => {
+                broadcast.receivers.is_empty() && self.created_at.elapsed() >= ttl
+            }
+            RoomMode::Reusable(reusable) => Instant::now() >= reusable.expires_at,
+        }
+    }
+}
+
+/// The receivers joining a broadcast room.
+///
+/// The room waits as long as any other room for its first receiver, after which the others
+/// have `join_window` to join. The broadcast starts once the window is over or the room is
+/// full, whichever comes first.
+pub struct Broadcast {
+    pub max_receivers: usize,
+    pub join_window: Duration,
+    /// Every receiver that joined, with the address it connected from.
+    pub receivers: Vec<(IrisTcpStream, PeerAddr)>,
+    join_deadline: Option<Instant>,
+}
+
+impl Broadcast {
+    pub fn new(max_receivers: usize, join_window: Duration) -> Self {
+        Self {
+            max_receivers,
+            join_window,
+            receivers: Vec::new(),
+            join_deadline: None,
+        }
+    }
+
+    /// Adds a receiver to the room and returns whether that filled it up.
+    pub fn add_receiver(&mut self, socket: IrisTcpStream, receiver_addr: PeerAddr) -> bool {
+        self.join_deadline
+            .get_or_insert_with(|| Instant::now() + self.join_window);
+        self.receivers.push((socket, receiver_addr));
+        self.receivers.len() >= self.max_receivers
+    }
+
+    fn is_closed(&self) -> bool {
+        self.join_deadline
+            .is_some_and(|join_deadline| Instant::now() >= join_deadline)
+    }
+}
+
+/// A room that stays open after its sender was paired, for up to `uses_left` more receivers
+/// until it expires.
+///
+/// Receivers that join are queued until the sender connects once more to be paired with them,
+/// presenting the room's token, so that the sender serves them one after another.
+pub struct Reusable {
+    pub uses_left: u32,
+    pub token: SenderToken,
+    pub notices: Arc<SenderNotices>,
+    receivers: VecDeque<(IrisTcpStream, PeerAddr)>,
+    expires_at: Instant,
+}
+
+impl Reusable {
+    pub fn new(max_uses: u32, lifetime: Duration) -> Self {
+        Self {
+            uses_left: max_uses,
+            token: generate_sender_token(),
+            notices: Arc::default(),
+            receivers: VecDeque::new(),
+            expires_at: Instant::now() + lifetime,
+        }
+    }
+
+    /// Queues a receiver for the sender, using up one of the uses of the room.
+    pub fn add_receiver(&mut self, socket: IrisTcpStream, receiver_addr: PeerAddr) {
+        self.uses_left = self.uses_left.saturating_sub(1);
+        self.receivers.push_back((socket, receiver_addr));
+    }
+
+    /// Hands out the receiver that has been queued the longest, or `None` if `token` is not the
+    /// token of the room or no receiver is queued.
+    pub fn claim(&mut self, token: &SenderToken) -> Option<(IrisTcpStream, PeerAddr)> {
+        if !is_same_sender_token(&self.token, token) {
+            return None;
+        }
+        self.receivers.pop_front()
+    }
+
+    /// Whether every use of the room was handed out to the sender.
+    pub fn is_used_up(&self) -> bool {
+        self.uses_left == 0 && self.receivers.is_empty()
+    }
+}
+
+/// Messages for the sender of a reusable room, queued while the room is locked and written in
+/// that order once it is not, so that a sender that stops reading only holds up whoever is
+/// writing to it.
+#[derive(Default)]
+pub struct SenderNotices {
+    /// The messages waiting to be written, and whether a thread is writing them.
+    queue: Mutex<(VecDeque<IrisMessage>, bool)>,
+}
+
+impl SenderNotices {
+    pub fn push(&self, message: IrisMessage) {
+        self.queue.lock().unwrap().0.push_back(message);
+    }
+
+    /// Writes the queued messages to `socket`, unless another thread already is, in which case
+    /// that thread writes them as well. Messages are dropped if the sender went away, its room
+    /// is closed once that is noticed.
+    pub fn flush(&self, mut socket: &Socket) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.1 {
+            return;
+        }
+        queue.1 = true;
+        while let Some(message) = queue.0.pop_front() {
+            drop(queue);
+            if let Ok(serialized_message) = serde_json::to_vec(&message) {
+                let size = u32::try_from(serialized_message.len()).unwrap_or(u32::MAX);
+                let _ = socket
+                    .write_all(&[&size.to_be_bytes(), serialized_message.as_slice()].concat());
+            }
+            queue = self.queue.lock().unwrap();
+        }
+        queue.1 = false;
+    }
+}
+
+/// The room identifiers a relay hands out, those that leave a remainder of `index` when divided
+/// by `count`, so that the relays of a cluster never hand out the same identifier and can tell
+/// which of them a room is on from its identifier alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// Every room identifier, for a relay of its own.
+    pub const WHOLE: Shard = Shard { index: 0, count: 1 };
+
+    /// The index of the shard `room_identifier` belongs to.
+    pub fn index_of(&self, room_identifier: RoomIdentifier) -> u64 {
+        room_identifier % self.count
+    }
+
+    /// The smallest identifier of the shard with the given number of digits and how many
+    /// identifiers of that length the shard has.
+    pub fn room_identifier_range(&self, digits: u32) -> (RoomIdentifier, u64) {
+        let (min, max) = room_identifier_range(digits);
+        let first = min + (self.index + self.count - min % self.count) % self.count;
+        if first > max {
+            (first, 0)
+        } else {
+            (first, (max - first) / self.count + 1)
+        }
+    }
+}
 
-#[derive(Debug, Default)]
 pub struct RoomMapping {
-    rooms: HashMap<RoomIdentifier, Box<dyn EncryptedIrisStream + Send>>,
+    rooms: HashMap<RoomIdentifier, Room>,
+    /// Number of rooms in every namespace that has any.
+    namespace_rooms: HashMap<Namespace, usize>,
+    shard: Shard,
+    max_rooms: usize,
+    min_digits: u32,
+    max_digits: u32,
+    max_occupancy: f64,
 }
 
 impl RoomMapping {
-    pub fn new() -> Self {
-        Self::default()
+    /// Creates an empty mapping holding at most `max_rooms` waiting senders at once.
+    ///
+    /// Room identifiers are taken from `shard` and are between `min_digits` and `max_digits`
+    /// digits long. The shortest length is used for as long as no more than `max_occupancy` of
+    /// the identifiers of that length would be in use, so that guessing a room stays unlikely
+    /// while the codes stay short on a quiet relay.
+    pub fn new(
+        shard: Shard,
+        max_rooms: usize,
+        min_digits: u32,
+        max_digits: u32,
+        max_occupancy: f64,
+    ) -> Self {
+        let max_digits = max_digits.clamp(1, MAX_ROOM_IDENTIFIER_DIGITS);
+        Self {
+            rooms: HashMap::new(),
+            namespace_rooms: HashMap::new(),
+            shard,
+            max_rooms,
+            min_digits: min_digits.clamp(1, max_digits),
+            max_digits,
+            max_occupancy,
+        }
+    }
+
+    /// Number of rooms waiting for their receiver.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
     }
 
+    /// Number of rooms of `namespace` waiting for their receiver.
+    pub fn len_in(&self, namespace: Namespace) -> usize {
+        self.namespace_rooms.get(&namespace).copied().unwrap_or(0)
+    }
+
+    /// Every room waiting for its receiver, in no particular order.
+    pub fn rooms(&self) -> impl Iterator<Item = (RoomIdentifier, &Room)> {
+        self.rooms
+            .iter()
+            .map(|(room_identifier, room)| (*room_identifier, room))
+    }
+
+    /// Parks the sender's socket in a new room of `namespace` and returns the identifier of
+    /// that room, or `None` if the relay is at capacity.
+    ///
+    /// Identifiers are unique across namespaces, so that the rooms of different namespaces
+    /// never collide.
     pub fn insert_socket(
         &mut self,
-        socket: impl EncryptedIrisStream + Send + 'static,
-    ) -> RoomIdentifier {
-        let mut rng = rand::thread_rng();
+        socket: IrisTcpStream,
+        sender_addr: PeerAddr,
+        namespace: Option<Namespace>,
+        mode: RoomMode,
+    ) -> Option<RoomIdentifier> {
+        if self.rooms.len() >= self.max_rooms {
+            return None;
+        }
+        let (first_room_identifier, room_identifiers) = self.pick_room_identifier_range()?;
+
+        // Start at a random identifier and probe from there so that we are guaranteed to find
+        // a free identifier, even when almost all of them are taken.
+        let mut position = rand::thread_rng().gen_range(0..room_identifiers);
         loop {
-            let room_identifier = rng.gen_range(1000..=9999);
+            let room_identifier = first_room_identifier + position * self.shard.count;
             if let Entry::Vacant(entry) = self.rooms.entry(room_identifier) {
-                entry.insert(Box::new(socket));
-                return room_identifier;
+                entry.insert(Room {
+                    socket,
+                    sender_addr,
+                    namespace,
+                    mode,
+                    created_at: Instant::now(),
+                });
+                if let Some(namespace) = namespace {
+                    *self.namespace_rooms.entry(namespace).or_default() += 1;
+                }
+                return Some(room_identifier);
             }
+
+            position = (position + 1) % room_identifiers;
         }
     }
 
-    pub fn get_and_remove_socket(
+    /// Picks the shortest identifier length that keeps the occupancy below the limit, falling
+    /// back to the longest length as long as it still has a free identifier. Returns the
+    /// smallest identifier of the shard with that length and how many there are.
+    fn pick_room_identifier_range(&self) -> Option<(RoomIdentifier, u64)> {
+        let rooms_after_insert = self.rooms.len() as f64 + 1.0;
+        let digits = (self.min_digits..=self.max_digits)
+            .find(|digits| {
+                let (_, room_identifiers) = self.shard.room_identifier_range(*digits);
+                rooms_after_insert <= room_identifiers as f64 * self.max_occupancy
+            })
+            .unwrap_or(self.max_digits);
+
+        let (first, room_identifiers) = self.shard.room_identifier_range(digits);
+        // Fewer rooms than identifiers of this length means at least one of them is free
+        ((self.rooms.len() as u64) < room_identifiers).then_some((first, room_identifiers))
+    }
+
+    pub fn get_room_mut(&mut self, room_identifier: RoomIdentifier) -> Option<&mut Room> {
+        self.rooms.get_mut(&room_identifier)
+    }
+
+    pub fn get_and_remove_room(&mut self, room_identifier: RoomIdentifier) -> Option<Room> {
+        let room = self.rooms.remove(&room_identifier)?;
+        if let Some(namespace) = room.namespace {
+            if let Entry::Occupied(mut entry) = self.namespace_rooms.entry(namespace) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        Some(room)
+    }
+
+    /// Removes every room and hands them back.
+    pub fn remove_all_rooms(&mut self) -> Vec<(RoomIdentifier, Room)> {
+        self.namespace_rooms.clear();
+        self.rooms.drain().collect()
+    }
+
+    /// Removes every room that has been waiting for longer than `ttl`, or reusable room that
+    /// is past its lifetime, and hands them back so that the senders can be told about it.
+    /// Broadcast rooms that a receiver already joined are left to start instead.
+    pub fn remove_expired_rooms(&mut self, ttl: Duration) -> Vec<(RoomIdentifier, Room)> {
+        self.remove_rooms_where(|room| room.is_expired(ttl))
+    }
+
+    /// Removes every broadcast room whose join window is over and hands them back so that the
+    /// broadcast can start.
+    pub fn remove_closed_broadcasts(&mut self) -> Vec<(RoomIdentifier, Room)> {
+        self.remove_rooms_where(|room| match &room.mode {
+            RoomMode::Broadcast(broadcast) => broadcast.is_closed(),
+            _ => false,
+        })
+    }
+
+    fn remove_rooms_where(
+        &mut self,
+        predicate: impl Fn(&Room) -> bool,
+    ) -> Vec<(RoomIdentifier, Room)> {
+        let room_identifiers: Vec<RoomIdentifier> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| predicate(room))
+            .map(|(room_identifier, _)| *room_identifier)
+            .collect();
+
+        room_identifiers
+            .into_iter()
+            .filter_map(|room_identifier| {
+                self.get_and_remove_room(room_identifier)
+                    .map(|room| (room_identifier, room))
+            })
+            .collect()
+    }
+
+    /// The sockets of every room's sender, to check whether they hung up without holding on to
+    /// the rooms, see [`RoomMapping::remove_abandoned_rooms`].
+    pub fn sender_sockets(&self) -> Vec<(RoomIdentifier, Arc<Socket>)> {
+        self.rooms
+            .iter()
+            .map(|(room_identifier, room)| (*room_identifier, room.socket.socket()))
+            .collect()
+    }
+
+    /// Removes the rooms whose sender hung up and hands them back. Rooms that were closed in
+    /// the meantime, and whose identifier may already be used by another room, are left alone.
+    pub fn remove_abandoned_rooms(
         &mut self,
-        room_identifier: RoomIdentifier,
-    ) -> Option<Box<dyn EncryptedIrisStream + Send>> {
-        self.rooms.remove(&room_identifier)
+        abandoned: Vec<(RoomIdentifier, Arc<Socket>)>,
+    ) -> Vec<(RoomIdentifier, Room)> {
+        let abandoned_room_identifiers: Vec<RoomIdentifier> = abandoned
+            .into_iter()
+            .filter(|(room_identifier, socket)| {
+                self.rooms
+                    .get(room_identifier)
+                    .is_some_and(|room| Arc::ptr_eq(&room.socket.socket(), socket))
+            })
+            .map(|(room_identifier, _)| room_identifier)
+            .collect();
+
+        abandoned_room_identifiers
+            .into_iter()
+            .filter_map(|room_identifier| {
+                self.get_and_remove_room(room_identifier)
+                    .map(|room| (room_identifier, room))
+            })
+            .collect()
     }
 }
+
+/// The smallest and largest room identifier with the given number of digits.
+fn room_identifier_range(digits: u32) -> (RoomIdentifier, RoomIdentifier) {
+    let min = 10u64.pow(digits - 1);
+    (min, min * 10 - 1)
+}
@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use jwalk::WalkDirGeneric;
 use spake2::{Ed25519Group, Identity, Password, Spake2};
@@ -11,20 +12,56 @@ use crate::constants::CHUNK_SIZE;
 use crate::errors::IrisError;
 use crate::files::{FileMetadata, FileType};
 use crate::iris_stream::{EncryptedIrisStream, IrisStream};
-use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::{room_identity, Namespace};
+use crate::pairing::{wait_for_peer, Role};
 use crate::progress::{SenderProgressCommunication, SenderProgressMessage, WorkerMessage};
+use crate::relay_connection::{connect_to_relay, explain_disconnect, RelayConnectionOptions};
 use crate::room_mapping::RoomIdentifier;
 use crate::IrisMessage;
 
+/// Sends `files` to a receiver, in a new room unless `room_identifier_str` is given, in which
+/// case it joins the room a receiver created for them.
+#[allow(clippy::too_many_arguments)]
 pub fn simple_send(
     server_ip: String,
     server_port: String,
+    relay_connection_options: &RelayConnectionOptions,
+    room_identifier_str: Option<&str>,
     cipher_type: CipherType,
     passphrase: &str,
     files: Vec<PathBuf>,
     progress_communication: &SenderProgressCommunication,
 ) -> Result<(), IrisError> {
-    let mut server_connection = IrisTcpStream::connect(format!("{server_ip}:{server_port}"))?;
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+
+    if let Some(room_identifier_str) = room_identifier_str {
+        let room_identifier = room_identifier_str
+            .parse::<RoomIdentifier>()
+            .map_err(|_| IrisError::InvalidPassphrase)?;
+        tracing::debug!("joining room #{room_identifier}");
+        server_connection.write_iris_message(IrisMessage::JoiningRoom { room_identifier })?;
+        wait_for_peer(&mut server_connection, Role::Sender)?;
+        return send(
+            &mut server_connection,
+            relay_connection_options.namespace,
+            room_identifier,
+            passphrase,
+            cipher_type,
+            files,
+            progress_communication,
+        )
+        .map_err(|e| {
+            explain_disconnect(
+                &server_ip,
+                &server_port,
+                relay_connection_options,
+                room_identifier,
+                e,
+            )
+        });
+    }
+
     server_connection.write_iris_message(IrisMessage::SenderConnecting)?;
 
     match server_connection.read_iris_message()? {
@@ -32,29 +69,154 @@ pub fn simple_send(
             tracing::info!("connect using {room_identifier}-{passphrase}");
             progress_communication
                 .write(SenderProgressMessage::AssignedRoomIdentifier { room_identifier })?;
-            if matches!(
-                server_connection.read_iris_message()?,
-                IrisMessage::ReceiverConnected
-            ) {
-                send(
+            match server_connection.read_iris_message()? {
+                IrisMessage::ReceiverConnected => send(
                     &mut server_connection,
+                    relay_connection_options.namespace,
                     room_identifier,
                     passphrase,
                     cipher_type,
                     files,
                     progress_communication,
                 )
-            } else {
-                Err(IrisError::UnexpectedMessage)
+                .map_err(|e| {
+                    explain_disconnect(
+                        &server_ip,
+                        &server_port,
+                        relay_connection_options,
+                        room_identifier,
+                        e,
+                    )
+                }),
+                IrisMessage::RoomExpired => Err(IrisError::RoomExpired),
+                IrisMessage::RoomClosed => Err(IrisError::RoomClosed),
+                IrisMessage::RelayDraining => Err(IrisError::RelayDraining),
+                IrisMessage::RelayFull => Err(IrisError::RelayFull),
+                _ => Err(IrisError::UnexpectedMessage),
             }
         }
+        IrisMessage::RelayFull => Err(IrisError::RelayFull),
+        IrisMessage::AccessDenied => Err(IrisError::RelayAccessDenied),
+        IrisMessage::RelayDraining => Err(IrisError::RelayDraining),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
         IrisMessage::ServerError => unreachable!(),
         _ => Err(IrisError::UnexpectedMessage),
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct ReusableRoomOptions {
+    /// Most receivers the room may be used by, the relay may allow fewer.
+    pub max_uses: u32,
+    /// How long the room stays open, the relay may allow less.
+    pub lifetime: Duration,
+}
+
+/// Sends `files` to every receiver that joins the room, one after another, until the room is
+/// used up or expires.
+///
+/// Every receiver gets its own key exchange over a connection of its own. A receiver that
+/// fails is reported with [`SenderProgressMessage::Error`] and the room stays open for the
+/// next one. Succeeds once the room is closed by the relay if at least one receiver joined.
+#[allow(clippy::too_many_arguments)]
+pub fn simple_send_reusable(
+    server_ip: String,
+    server_port: String,
+    relay_connection_options: &RelayConnectionOptions,
+    cipher_type: CipherType,
+    passphrase: &str,
+    files: Vec<PathBuf>,
+    reusable_room_options: ReusableRoomOptions,
+    progress_communication: &SenderProgressCommunication,
+) -> Result<(), IrisError> {
+    let mut server_connection =
+        connect_to_relay(&server_ip, &server_port, relay_connection_options)?;
+    server_connection.write_iris_message(IrisMessage::ReusableSenderConnecting {
+        max_uses: reusable_room_options.max_uses,
+        lifetime_secs: reusable_room_options.lifetime.as_secs(),
+    })?;
+
+    let room_identifier = match server_connection.read_iris_message()? {
+        IrisMessage::AssignedRoomIdentifier { room_identifier } => room_identifier,
+        IrisMessage::RelayFull => return Err(IrisError::RelayFull),
+        IrisMessage::AccessDenied => return Err(IrisError::RelayAccessDenied),
+        IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+        IrisMessage::RateLimited { retry_after_secs } => {
+            return Err(IrisError::RateLimited(retry_after_secs))
+        }
+        IrisMessage::DailyQuotaExceeded => return Err(IrisError::DailyQuotaExceeded),
+        _ => return Err(IrisError::UnexpectedMessage),
+    };
+    tracing::info!("connect using {room_identifier}-{passphrase}");
+    progress_communication
+        .write(SenderProgressMessage::AssignedRoomIdentifier { room_identifier })?;
+
+    let mut has_been_used = false;
+    loop {
+        let (uses_left, token) = match server_connection.read_iris_message()? {
+            IrisMessage::ReceiverWaiting { uses_left, token } => (uses_left, token),
+            IrisMessage::RoomExpired if has_been_used => return Ok(()),
+            IrisMessage::RoomExpired => return Err(IrisError::RoomExpired),
+            IrisMessage::RoomClosed => return Err(IrisError::RoomClosed),
+            IrisMessage::RelayDraining => return Err(IrisError::RelayDraining),
+            _ => return Err(IrisError::UnexpectedMessage),
+        };
+        has_been_used = true;
+        tracing::info!("a receiver joined, the room can be used {uses_left} more times");
+        progress_communication.write(SenderProgressMessage::ReceiverWaiting { uses_left })?;
+
+        let result = connect_to_relay(&server_ip, &server_port, relay_connection_options).and_then(
+            |mut receiver_connection| {
+                receiver_connection.write_iris_message(IrisMessage::SenderJoining {
+                    room_identifier,
+                    token,
+                })?;
+                match receiver_connection.read_iris_message()? {
+                    IrisMessage::ReceiverConnected => send(
+                        &mut receiver_connection,
+                        relay_connection_options.namespace,
+                        room_identifier,
+                        passphrase,
+                        cipher_type,
+                        files.clone(),
+                        progress_communication,
+                    )
+                    .map_err(|e| {
+                        explain_disconnect(
+                            &server_ip,
+                            &server_port,
+                            relay_connection_options,
+                            room_identifier,
+                            e,
+                        )
+                    }),
+                    IrisMessage::BadRoomIdentifier => Err(IrisError::InvalidPassphrase),
+                    IrisMessage::RelayFull => Err(IrisError::RelayFull),
+                    IrisMessage::RateLimited { retry_after_secs } => {
+                        Err(IrisError::RateLimited(retry_after_secs))
+                    }
+                    IrisMessage::DailyQuotaExceeded => Err(IrisError::DailyQuotaExceeded),
+                    _ => Err(IrisError::UnexpectedMessage),
+                }
+            },
+        );
+        if let Err(e) = result {
+            tracing::warn!("failed to send to a receiver of room #{room_identifier}: {e}");
+            progress_communication.write(SenderProgressMessage::Error(e))?;
+        }
+
+        if uses_left == 0 {
+            return Ok(());
+        }
+    }
+}
+
 pub fn send(
     server_connection: &mut dyn EncryptedIrisStream,
+    namespace: Option<Namespace>,
     room_identifier: RoomIdentifier,
     passphrase: &str,
     cipher_type: CipherType,
@@ -62,7 +224,7 @@ pub fn send(
     progress_communication: &SenderProgressCommunication,
 ) -> Result<(), IrisError> {
     server_connection.write_iris_message(IrisMessage::SetCipherType { cipher_type })?;
-    let key = perform_key_exchange(server_connection, room_identifier, passphrase)?;
+    let key = perform_key_exchange(server_connection, namespace, room_identifier, passphrase)?;
     progress_communication.write(SenderProgressMessage::SetCipher { cipher_type })?;
     tracing::info!("switching over to encrypted communication");
 
@@ -82,14 +244,15 @@ pub fn send(
     )
 }
 
-fn perform_key_exchange(
+pub fn perform_key_exchange(
     server_connection: &mut dyn EncryptedIrisStream,
+    namespace: Option<Namespace>,
     room_identifier: RoomIdentifier,
     passphrase: &str,
 ) -> Result<Vec<u8>, IrisError> {
     let (s1, outbound_msg) = Spake2::<Ed25519Group>::start_symmetric(
         &Password::new(passphrase.as_bytes()),
-        &Identity::new(format!("iris-{room_identifier}").as_bytes()),
+        &Identity::new(room_identity("iris", namespace, room_identifier).as_bytes()),
     );
     let receiver_code = server_connection.read_size_prefixed_message()?;
     let key = s1.finish(&receiver_code).map_err(IrisError::SpakeError)?;
@@ -250,7 +413,7 @@ fn process_file(
     }
 }
 
-fn get_complete_file_list_and_total_size(
+pub fn get_complete_file_list_and_total_size(
     files: Vec<PathBuf>,
 ) -> Result<(Vec<(PathBuf, FileMetadata)>, u64), IrisError> {
     let mut complete_file_list = Vec::new();
@@ -1,110 +1,2574 @@
-use std::net::TcpListener;
+mod address_filter;
+mod admin;
+mod audit;
+mod broadcast;
+mod cluster;
+mod ended_sessions;
+mod http;
+mod listener;
+mod mailbox;
+mod metrics;
+mod pipe;
+mod proxy_protocol;
+mod quota;
+mod rate_limit;
+mod sessions;
+mod throttle;
+mod webhook;
 
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
 use threadpool::ThreadPool;
 
+use crate::access_token::{verify_response, AccessToken, CHALLENGE_SIZE};
 use crate::errors::IrisError;
-use crate::iris_stream::{EncryptedIrisStream, IrisStream};
+use crate::ip_network::IpNetwork;
+use crate::iris_stream::{IrisStream, IrisStreamEssentials};
 use crate::iris_tcp_stream::IrisTcpStream;
-use crate::room_mapping::RoomMapping;
-use crate::IrisMessage;
+use crate::mailbox::pake_identity;
+use crate::namespace::{Namespace, NamespaceLimits};
+use crate::relay_connection::authenticate as authenticate_to_node;
+use crate::room_mapping::{
+    Broadcast, Reusable, Room, RoomIdentifier, RoomMapping, RoomMode, SenderToken, Shard,
+    MAX_ROOM_IDENTIFIER_DIGITS,
+};
+use crate::socket::join_host_port;
+use crate::{IrisMessage, Role};
+
+pub use self::audit::AuditLogConfig;
+pub use self::cluster::ClusterConfig;
+pub use self::listener::ListenerAddr;
+pub(crate) use self::listener::PeerAddr;
+pub use self::mailbox::MailboxConfig;
+pub use self::webhook::WebhookConfig;
+
+use self::address_filter::{AddressFilter, Rejection};
+use self::audit::{AuditLog, AuditRecord, Termination, Timestamp};
+use self::broadcast::{PendingBroadcast, PendingBroadcasts};
+use self::cluster::Cluster;
+use self::ended_sessions::EndedSessions;
+use self::listener::{is_local_address, Listener};
+use self::mailbox::{Mailboxes, TransferFailure};
+use self::metrics::{HandshakeFailure, Metrics, Snapshot};
+use self::pipe::Direction;
+use self::proxy_protocol::ProxyHeader;
+use self::quota::{QuotaConfig, Quotas};
+use self::rate_limit::{FailedJoinOutcome, RateLimitConfig, RateLimiter};
+use self::sessions::{SessionGuard, Sessions};
+use self::throttle::Throttle;
+use self::webhook::{EventKind, Webhook, WebhookEvent};
+
+/// The smallest limit on handshake messages that still fits every message of the handshake.
+const MIN_HANDSHAKE_MESSAGE_SIZE: u32 = 256;
+
+/// How often waiting rooms are checked for expiry and for senders that went away.
+const ROOM_REAPER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a draining relay checks whether its transfers have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the accept loop sleeps when there is no connection waiting to be accepted.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tunables for the relay.
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Whether listeners on IPv6 addresses only accept IPv6 clients, so that IPv4 can be
+    /// listened on separately on the same port. Leave unset to go with the operating system's
+    /// default, which on most systems lets `[::]` accept IPv4 clients as well.
+    pub ipv6_only: Option<bool>,
+    /// Maximum number of paired transfers relayed at the same time. Additional pairs are
+    /// turned away with [`IrisMessage::RelayFull`].
+    pub max_concurrent_relays: usize,
+    /// Maximum number of connections whose first message is being read at the same time.
+    pub max_concurrent_handshakes: usize,
+    /// Maximum number of new connections waiting for a handshake worker. Further connections
+    /// are turned away with [`IrisMessage::RelayFull`] as soon as they are accepted.
+    pub max_queued_handshakes: usize,
+    /// How long a new connection has to send its first message before it is dropped, counted
+    /// from when a handshake worker picks it up.
+    pub handshake_timeout: Duration,
+    /// The largest message a client may send before it is paired, in bytes. The default is
+    /// plenty for any handshake message while keeping a misbehaving client from making the
+    /// relay allocate arbitrary amounts of memory.
+    pub max_handshake_message_size: u32,
+    /// Maximum number of senders waiting for a receiver at the same time. Once reached, new
+    /// senders are turned away with [`IrisMessage::RelayFull`].
+    pub max_waiting_rooms: usize,
+    /// How long a sender may wait for its receiver before the room is closed.
+    pub room_ttl: Duration,
+    /// Shortest room identifier handed out, in digits.
+    pub min_room_identifier_digits: u32,
+    /// Longest room identifier handed out, in digits, at most 19.
+    pub max_room_identifier_digits: u32,
+    /// Largest fraction of the room identifiers of a given length that may be in use before
+    /// longer room identifiers are handed out. This bounds the chance of somebody guessing a
+    /// waiting room.
+    pub max_room_identifier_occupancy: f64,
+    /// Most receivers a single broadcast room may gather. Senders asking for more get this
+    /// many. At most [`RelayConfig::max_concurrent_relays`], as every receiver of a broadcast is
+    /// relayed at the same time as the others.
+    pub max_broadcast_receivers: usize,
+    /// Most receivers a single reusable room may be used by. Senders asking for more get this
+    /// many. Reusable rooms stay open for at most [`RelayConfig::room_ttl`].
+    pub max_room_uses: u32,
+    /// Failed room joins after which the address they come from is banned, 0 turns off the
+    /// limits on joining rooms. Failed authentications count as failed joins, and one failure
+    /// is forgiven every [`RelayConfig::ban_duration`] divided by this many.
+    pub max_failed_joins: u32,
+    /// How long an address has to wait after its first failed room join before it may try
+    /// again, doubled with every further failure.
+    pub failed_join_backoff: Duration,
+    /// How long an address is banned for after too many failed room joins.
+    pub ban_duration: Duration,
+    /// Rooms a single address may create per minute, 0 turns off the limit.
+    pub max_room_creations_per_minute: u32,
+    /// Bytes per second a single paired room may forward, in both directions combined.
+    pub room_bandwidth_limit: Option<u64>,
+    /// Bytes per second forwarded by the whole relay, shared equally between the paired rooms.
+    pub global_bandwidth_limit: Option<u64>,
+    /// Bytes a single paired transfer may forward, in both directions combined. The transfer
+    /// is cut off once it goes over, and its clients are told with
+    /// [`IrisMessage::SessionSizeExceeded`] when they ask why with
+    /// [`IrisMessage::AskingHowSessionEnded`].
+    pub max_session_bytes: Option<u64>,
+    /// How long a single paired transfer may last before it is cut off, which its clients are
+    /// told about with [`IrisMessage::SessionDurationExceeded`].
+    pub max_session_duration: Option<Duration>,
+    /// How long a paired transfer may go without forwarding anything, in either direction,
+    /// before it is cut off, which its clients are told about with
+    /// [`IrisMessage::SessionIdleTimeout`]. It is also how long the relay keeps trying to
+    /// forward to a client that stopped reading.
+    pub session_idle_timeout: Duration,
+    /// Bytes a single address may have relayed per day, counting every transfer it takes part
+    /// in as either sender or receiver. Transfers are cut off once one of their clients uses up
+    /// its quota, which they are told about with [`IrisMessage::DailyQuotaExceeded`], and the
+    /// address is turned away until the next day starts at midnight UTC.
+    pub daily_quota_bytes: Option<u64>,
+    /// How long [`serve`] lets paired transfers finish after receiving SIGTERM before cutting
+    /// them off.
+    pub drain_timeout: Duration,
+    /// Tokens that clients have to present before they may use the relay. Leave empty to let
+    /// anyone use the relay.
+    pub access_tokens: Vec<AccessToken>,
+    /// Load balancers allowed to prepend a PROXY protocol header, version 1 or 2, to the
+    /// connections they forward, so that the relay sees the address of the actual client.
+    /// Connections from anywhere else that start with such a header are rejected, while
+    /// connections without one are accepted from anywhere.
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Whether clients on a Unix domain socket may send a PROXY protocol header and are exempt
+    /// from the rate limits, for when only a local load balancer can reach the socket.
+    /// Otherwise they all share the rate limits of a single address, as they cannot be told
+    /// apart.
+    pub trust_unix_clients: bool,
+    /// Networks that may connect to the relay, checked as soon as a connection is accepted and
+    /// again against the actual client once a PROXY protocol header says who it is. Leave
+    /// empty to let in every address that is not denied. Load balancers and the other nodes of
+    /// a cluster have to be allowed as well.
+    pub allowed_networks: Vec<IpNetwork>,
+    /// Networks that may not connect to the relay, even if they are part of an allowed
+    /// network.
+    pub denied_networks: Vec<IpNetwork>,
+    /// Address of an HTTP listener exposing the relay's metrics in the Prometheus text format
+    /// under `/metrics`, e.g. `"127.0.0.1:9090"`. Leave empty to not expose metrics.
+    pub metrics_address: Option<String>,
+    /// Address of an HTTP listener for the relay's operators to list the waiting rooms and
+    /// paired transfers, close them, and turn drain mode on and off, see the routes below. As
+    /// there is no authentication, it has to be a Unix domain socket, e.g.
+    /// `"unix:/run/iris/admin.sock"`, or a loopback address, e.g. `"127.0.0.1:9091"`. Leave
+    /// empty to not expose it.
+    ///
+    /// * `GET /rooms` lists the rooms waiting for their receiver and how long they have waited.
+    /// * `DELETE /rooms/<room identifier>` closes a waiting room, its sender getting
+    ///   [`IrisMessage::RoomClosed`].
+    /// * `GET /sessions` lists the paired transfers and how many bytes they forwarded so far.
+    /// * `DELETE /sessions/<session identifier>` cuts off a paired transfer.
+    /// * `GET /drain`, `POST /drain` and `DELETE /drain` respectively tell whether the relay is
+    ///   draining, start draining and stop draining. A draining relay turns away new clients
+    ///   and closes its waiting rooms, but keeps relaying the transfers already paired.
+    pub admin_address: Option<String>,
+    /// Where to keep a record of every room, as JSON Lines: who took part, when it was paired
+    /// and ended, how much was relayed each way and how it ended, but nothing of what was
+    /// sent. Leave empty to not keep one.
+    pub audit_log: Option<AuditLogConfig>,
+    /// Makes the relay a node of a cluster sharing its rooms with the other nodes, so that a
+    /// sender and its receiver may connect to different nodes. Leave empty for a relay of its
+    /// own.
+    pub cluster: Option<ClusterConfig>,
+    /// Lets senders leave files on the relay for receivers that are not online, see
+    /// [`crate::simple_send_to_mailbox`]. Leave empty to only relay between clients that are
+    /// online at the same time.
+    pub mailbox: Option<MailboxConfig>,
+    /// Where to POST an event every time a room is created, paired and ended, along with the
+    /// addresses of its clients, how much was relayed each way and how it ended. Leave empty
+    /// to not send any.
+    pub webhook: Option<WebhookConfig>,
+    /// Limits on the rooms of single namespaces, for relays shared by several applications.
+    /// Clients only ever meet peers in their own namespace, but every namespace is held to the
+    /// limits of the relay as a whole unless it is listed here.
+    pub namespace_limits: Vec<NamespaceLimits>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            ipv6_only: None,
+            max_concurrent_relays: 64,
+            max_concurrent_handshakes: 16,
+            max_queued_handshakes: 256,
+            handshake_timeout: Duration::from_secs(10),
+            max_handshake_message_size: 4 * 1024,
+            max_waiting_rooms: 100_000,
+            room_ttl: Duration::from_secs(60 * 60),
+            min_room_identifier_digits: 4,
+            max_room_identifier_digits: 12,
+            max_room_identifier_occupancy: 0.01,
+            max_broadcast_receivers: 16,
+            max_room_uses: 100,
+            max_failed_joins: 8,
+            failed_join_backoff: Duration::from_secs(1),
+            ban_duration: Duration::from_secs(15 * 60),
+            max_room_creations_per_minute: 60,
+            room_bandwidth_limit: None,
+            global_bandwidth_limit: None,
+            max_session_bytes: None,
+            max_session_duration: None,
+            session_idle_timeout: Duration::from_secs(10 * 60),
+            daily_quota_bytes: None,
+            drain_timeout: Duration::from_secs(5 * 60),
+            access_tokens: Vec::new(),
+            trusted_proxies: Vec::new(),
+            trust_unix_clients: false,
+            allowed_networks: Vec::new(),
+            denied_networks: Vec::new(),
+            metrics_address: None,
+            admin_address: None,
+            audit_log: None,
+            cluster: None,
+            mailbox: None,
+            webhook: None,
+            namespace_limits: Vec::new(),
+        }
+    }
+}
+
+impl RelayConfig {
+    /// Checks that the tunables make sense together, [`RelayBuilder::spawn`] refuses to start
+    /// a relay with a configuration that does not.
+    pub fn validate(&self) -> Result<(), IrisError> {
+        let invalid = |reason: &str| Err(IrisError::InvalidRelayConfig(reason.to_string()));
+
+        if self.max_concurrent_relays == 0 {
+            return invalid("max_concurrent_relays must be at least 1");
+        }
+        if self.max_concurrent_handshakes == 0 {
+            return invalid("max_concurrent_handshakes must be at least 1");
+        }
+        if self.handshake_timeout.is_zero() {
+            return invalid("handshake_timeout must not be zero");
+        }
+        if self.max_handshake_message_size < MIN_HANDSHAKE_MESSAGE_SIZE {
+            return invalid(&format!(
+                "max_handshake_message_size must be at least {MIN_HANDSHAKE_MESSAGE_SIZE} bytes"
+            ));
+        }
+        if self.max_waiting_rooms == 0 {
+            return invalid("max_waiting_rooms must be at least 1");
+        }
+        if self.room_ttl.is_zero() {
+            return invalid("room_ttl must not be zero");
+        }
+        if self.session_idle_timeout.is_zero() {
+            return invalid("session_idle_timeout must not be zero");
+        }
+        if self.min_room_identifier_digits == 0
+            || self.min_room_identifier_digits > self.max_room_identifier_digits
+            || self.max_room_identifier_digits > MAX_ROOM_IDENTIFIER_DIGITS
+        {
+            return invalid(&format!(
+                "room identifiers must be between 1 and {MAX_ROOM_IDENTIFIER_DIGITS} digits long, with the minimum no larger than the maximum"
+            ));
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.node_index >= cluster.nodes.len() {
+                return invalid("the cluster's node_index must be the index of one of its nodes");
+            }
+            // Every remainder comes up among the longest identifiers if there are at least as
+            // many of them as there are nodes
+            if cluster.nodes.len() as u64 > 9 * 10u64.pow(self.max_room_identifier_digits - 1) {
+                return invalid(
+                    "the cluster has more nodes than there are room identifiers of the longest length",
+                );
+            }
+        }
+        if self.max_broadcast_receivers == 0
+            || self.max_broadcast_receivers > self.max_concurrent_relays
+        {
+            return invalid(
+                "max_broadcast_receivers must be at least 1 and at most max_concurrent_relays",
+            );
+        }
+        if self.max_room_uses == 0 {
+            return invalid("max_room_uses must not be zero");
+        }
+        if !(self.max_room_identifier_occupancy > 0.0 && self.max_room_identifier_occupancy <= 1.0)
+        {
+            return invalid("max_room_identifier_occupancy must be above 0 and at most 1");
+        }
+        if self.room_bandwidth_limit == Some(0) || self.global_bandwidth_limit == Some(0) {
+            return invalid("bandwidth limits must be above 0, leave them unset for no limit");
+        }
+        if self.max_session_bytes == Some(0)
+            || self.max_session_duration == Some(Duration::ZERO)
+            || self.daily_quota_bytes == Some(0)
+        {
+            return invalid(
+                "session limits and quotas must be above 0, leave them unset for no limit",
+            );
+        }
+        if self
+            .access_tokens
+            .iter()
+            .any(|access_token| access_token.token.is_empty())
+        {
+            return invalid("access tokens must not be empty");
+        }
+        if self
+            .admin_address
+            .as_ref()
+            .is_some_and(|admin_address| !is_local_address(admin_address))
+        {
+            return invalid("admin_address must be a Unix domain socket or a loopback address");
+        }
+        if self
+            .audit_log
+            .as_ref()
+            .is_some_and(|audit_log| audit_log.max_file_size == 0)
+        {
+            return invalid("the audit log's max_file_size must be above 0");
+        }
+        if let Some(mailbox) = &self.mailbox {
+            if mailbox.ttl.is_zero()
+                || mailbox.max_size == 0
+                || mailbox.max_mailboxes == 0
+                || mailbox.max_total_bytes == 0
+                || mailbox.max_transfers == 0
+            {
+                return invalid(
+                    "the mailbox's ttl, max_size, max_mailboxes, max_total_bytes and \
+                     max_transfers must be above 0",
+                );
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if webhook.endpoint().is_none() {
+                return invalid("the webhook's url must be a plain http:// URL");
+            }
+            if webhook.max_queued_events == 0
+                || webhook.max_attempts == 0
+                || webhook.timeout.is_zero()
+            {
+                return invalid(
+                    "the webhook's max_queued_events, max_attempts and timeout must be above 0",
+                );
+            }
+        }
+
+        for (index, limits) in self.namespace_limits.iter().enumerate() {
+            if self.namespace_limits[..index]
+                .iter()
+                .any(|other| other.namespace == limits.namespace)
+            {
+                return invalid(&format!(
+                    "the limits of namespace {} are given more than once",
+                    limits.namespace
+                ));
+            }
+            if limits.max_waiting_rooms == Some(0) || limits.max_sessions == Some(0) {
+                return invalid(&format!(
+                    "the limits of namespace {} must be above 0, leave them unset for no limit",
+                    limits.namespace
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The limits of `namespace`, if it has any of its own.
+    fn limits_of(&self, namespace: Option<Namespace>) -> Option<&NamespaceLimits> {
+        let namespace = namespace?;
+        self.namespace_limits
+            .iter()
+            .find(|limits| limits.namespace == namespace)
+    }
+}
 
 pub fn serve(ip_address: String, port: String) -> Result<(), IrisError> {
-    let pool = ThreadPool::new(4);
-
-    let listener = TcpListener::bind(format!("{ip_address}:{port}")).unwrap();
-    tracing::info!("listening on {ip_address}:{port}");
-
-    let mut room_mapping = RoomMapping::new();
-    loop {
-        if let Ok((socket, addr)) = listener.accept() {
-            // If we cannot convert the socket to a IrisTcpStream, we got a massive
-            // problem so the server should return the error and stop.
-            let mut socket = IrisTcpStream::new(socket)?;
-
-            if let Ok(message) = socket.read_iris_message() {
-                match message {
-                    IrisMessage::SenderConnecting => {
-                        tracing::debug!("sender #{addr} is connected");
-                        if let Ok(mut sender_socket) = socket.try_clone() {
-                            let room_identifier = room_mapping.insert_socket(socket);
-
-                            if sender_socket
-                                .write_iris_message(IrisMessage::AssignedRoomIdentifier {
-                                    room_identifier,
-                                })
-                                .is_err()
-                            {
-                                room_mapping.get_and_remove_socket(room_identifier);
-                            }
-                        } else {
-                            tracing::error!("failed to clone the socket");
-                            // Ignore the error if sender disconnected, we do not want to bring
-                            // down the server as well
-                            let _ = socket.write_iris_message(IrisMessage::ServerError);
+    serve_with_config(ip_address, port, RelayConfig::default())
+}
+
+/// Runs a relay until it fails or the process receives SIGTERM, in which case the relay is
+/// drained for up to [`RelayConfig::drain_timeout`] before returning. A second SIGTERM exits
+/// the process right away.
+pub fn serve_with_config(
+    ip_address: String,
+    port: String,
+    config: RelayConfig,
+) -> Result<(), IrisError> {
+    RelayBuilder::new(join_host_port(&ip_address, &port))
+        .config(config)
+        .run()
+}
+
+/// Builds a relay that runs in the background, see [`RelayBuilder::spawn`].
+#[derive(Debug, Clone)]
+pub struct RelayBuilder {
+    addresses: Vec<String>,
+    config: RelayConfig,
+}
+
+impl RelayBuilder {
+    /// Starts building a relay listening on `address`, e.g. `"0.0.0.0:8080"`, `"[::]:8080"` or
+    /// `"unix:/run/iris/relay.sock"`. Use port 0 to let the operating system pick a free port
+    /// and [`RelayHandle::local_addr`] to find out which.
+    ///
+    /// Whether `[::]` accepts IPv4 clients as well depends on [`RelayConfig::ipv6_only`].
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            addresses: vec![address.into()],
+            config: RelayConfig::default(),
+        }
+    }
+
+    /// Listens on another address as well. Clients share the rooms no matter which address
+    /// they connect to, so a sender and its receiver may use different ones.
+    pub fn listen_on(mut self, address: impl Into<String>) -> Self {
+        self.addresses.push(address.into());
+        self
+    }
+
+    /// Makes listeners on IPv6 addresses accept IPv4 clients as well, or not, instead of going
+    /// with the operating system's default.
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.config.ipv6_only = Some(ipv6_only);
+        self
+    }
+
+    /// Replaces every tunable at once.
+    pub fn config(mut self, config: RelayConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn max_concurrent_relays(mut self, max_concurrent_relays: usize) -> Self {
+        self.config.max_concurrent_relays = max_concurrent_relays;
+        self
+    }
+
+    pub fn max_concurrent_handshakes(mut self, max_concurrent_handshakes: usize) -> Self {
+        self.config.max_concurrent_handshakes = max_concurrent_handshakes;
+        self
+    }
+
+    pub fn max_queued_handshakes(mut self, max_queued_handshakes: usize) -> Self {
+        self.config.max_queued_handshakes = max_queued_handshakes;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn max_handshake_message_size(mut self, max_handshake_message_size: u32) -> Self {
+        self.config.max_handshake_message_size = max_handshake_message_size;
+        self
+    }
+
+    pub fn max_waiting_rooms(mut self, max_waiting_rooms: usize) -> Self {
+        self.config.max_waiting_rooms = max_waiting_rooms;
+        self
+    }
+
+    pub fn room_ttl(mut self, room_ttl: Duration) -> Self {
+        self.config.room_ttl = room_ttl;
+        self
+    }
+
+    pub fn room_identifier_digits(mut self, min_digits: u32, max_digits: u32) -> Self {
+        self.config.min_room_identifier_digits = min_digits;
+        self.config.max_room_identifier_digits = max_digits;
+        self
+    }
+
+    pub fn max_room_identifier_occupancy(mut self, max_room_identifier_occupancy: f64) -> Self {
+        self.config.max_room_identifier_occupancy = max_room_identifier_occupancy;
+        self
+    }
+
+    pub fn max_broadcast_receivers(mut self, max_broadcast_receivers: usize) -> Self {
+        self.config.max_broadcast_receivers = max_broadcast_receivers;
+        self
+    }
+
+    pub fn max_room_uses(mut self, max_room_uses: u32) -> Self {
+        self.config.max_room_uses = max_room_uses;
+        self
+    }
+
+    pub fn max_failed_joins(mut self, max_failed_joins: u32) -> Self {
+        self.config.max_failed_joins = max_failed_joins;
+        self
+    }
+
+    pub fn failed_join_backoff(mut self, failed_join_backoff: Duration) -> Self {
+        self.config.failed_join_backoff = failed_join_backoff;
+        self
+    }
+
+    pub fn ban_duration(mut self, ban_duration: Duration) -> Self {
+        self.config.ban_duration = ban_duration;
+        self
+    }
+
+    pub fn max_room_creations_per_minute(mut self, max_room_creations_per_minute: u32) -> Self {
+        self.config.max_room_creations_per_minute = max_room_creations_per_minute;
+        self
+    }
+
+    pub fn room_bandwidth_limit(mut self, room_bandwidth_limit: Option<u64>) -> Self {
+        self.config.room_bandwidth_limit = room_bandwidth_limit;
+        self
+    }
+
+    pub fn global_bandwidth_limit(mut self, global_bandwidth_limit: Option<u64>) -> Self {
+        self.config.global_bandwidth_limit = global_bandwidth_limit;
+        self
+    }
+
+    pub fn max_session_bytes(mut self, max_session_bytes: Option<u64>) -> Self {
+        self.config.max_session_bytes = max_session_bytes;
+        self
+    }
+
+    pub fn max_session_duration(mut self, max_session_duration: Option<Duration>) -> Self {
+        self.config.max_session_duration = max_session_duration;
+        self
+    }
+
+    pub fn session_idle_timeout(mut self, session_idle_timeout: Duration) -> Self {
+        self.config.session_idle_timeout = session_idle_timeout;
+        self
+    }
+
+    pub fn daily_quota_bytes(mut self, daily_quota_bytes: Option<u64>) -> Self {
+        self.config.daily_quota_bytes = daily_quota_bytes;
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.config.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Restricts the relay to clients presenting this token, can be called several times to
+    /// accept several tokens.
+    pub fn access_token(mut self, label: impl Into<String>, token: impl Into<String>) -> Self {
+        self.config.access_tokens.push(AccessToken {
+            label: label.into(),
+            token: token.into(),
+        });
+        self
+    }
+
+    /// Accepts PROXY protocol headers from `network`, can be called several times to trust
+    /// several networks.
+    pub fn trusted_proxy(mut self, network: IpNetwork) -> Self {
+        self.config.trusted_proxies.push(network);
+        self
+    }
+
+    /// Trusts clients on a Unix domain socket like a load balancer, see
+    /// [`RelayConfig::trust_unix_clients`].
+    pub fn trust_unix_clients(mut self, trust_unix_clients: bool) -> Self {
+        self.config.trust_unix_clients = trust_unix_clients;
+        self
+    }
+
+    /// Lets `network` connect, can be called several times to allow several networks. Once a
+    /// network is allowed, every other address is turned away.
+    pub fn allowed_network(mut self, network: IpNetwork) -> Self {
+        self.config.allowed_networks.push(network);
+        self
+    }
+
+    /// Turns away `network`, can be called several times to deny several networks.
+    pub fn denied_network(mut self, network: IpNetwork) -> Self {
+        self.config.denied_networks.push(network);
+        self
+    }
+
+    pub fn metrics_address(mut self, metrics_address: impl Into<String>) -> Self {
+        self.config.metrics_address = Some(metrics_address.into());
+        self
+    }
+
+    pub fn admin_address(mut self, admin_address: impl Into<String>) -> Self {
+        self.config.admin_address = Some(admin_address.into());
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: AuditLogConfig) -> Self {
+        self.config.audit_log = Some(audit_log);
+        self
+    }
+
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.config.cluster = Some(cluster);
+        self
+    }
+
+    pub fn mailbox(mut self, mailbox: MailboxConfig) -> Self {
+        self.config.mailbox = Some(mailbox);
+        self
+    }
+
+    pub fn webhook(mut self, webhook: WebhookConfig) -> Self {
+        self.config.webhook = Some(webhook);
+        self
+    }
+
+    /// Holds a namespace to `limits`, can be called once for every namespace.
+    pub fn namespace_limits(mut self, limits: NamespaceLimits) -> Self {
+        self.config.namespace_limits.push(limits);
+        self
+    }
+
+    /// Binds the listeners and starts relaying in the background.
+    ///
+    /// The accept loop never reads from the clients itself. Every accepted connection is queued
+    /// for a handshake worker that reads its first message under a deadline, and paired
+    /// transfers run on their own pool, so a slow or silent client only ever ties up a single
+    /// handshake worker for at most [`RelayConfig::handshake_timeout`]. Senders waiting for a
+    /// receiver do not hold on to a thread at all. Connections beyond what the handshake
+    /// workers and their queue can take are turned away straight away.
+    pub fn spawn(self) -> Result<RelayHandle, IrisError> {
+        self.config.validate()?;
+
+        let mut listeners = Vec::new();
+        let mut listener_addrs = Vec::new();
+        for address in &self.addresses {
+            let bind = || {
+                let listener = Listener::bind(address, self.config.ipv6_only)?;
+                // Accept without blocking so that the accept loop notices a shutdown request and
+                // can take turns between the listeners
+                listener.set_nonblocking(true)?;
+                let listener_addr = listener.local_addr()?;
+                Ok::<_, std::io::Error>((listener, listener_addr))
+            };
+            let (listener, listener_addr) =
+                bind().map_err(|_| IrisError::ListenerBindError(address.clone()))?;
+            tracing::info!("listening on {listener_addr}");
+            listeners.push(listener);
+            listener_addrs.push(listener_addr);
+        }
+
+        let metrics_listener = match &self.config.metrics_address {
+            Some(metrics_address) => {
+                let metrics_listener = Listener::bind(metrics_address, self.config.ipv6_only)
+                    .map_err(|_| IrisError::ListenerBindError(metrics_address.clone()))?;
+                tracing::info!("exposing metrics on {metrics_address}");
+                Some(metrics_listener)
+            }
+            None => None,
+        };
+        let metrics_addr = metrics_listener.as_ref().and_then(|metrics_listener| {
+            match metrics_listener.local_addr() {
+                Ok(ListenerAddr::Tcp(addr)) => Some(addr),
+                _ => None,
+            }
+        });
+
+        let admin_listener = match &self.config.admin_address {
+            Some(admin_address) => {
+                let admin_listener = Listener::bind(admin_address, self.config.ipv6_only)
+                    .map_err(|_| IrisError::ListenerBindError(admin_address.clone()))?;
+                tracing::info!("exposing the admin API on {admin_address}");
+                Some(admin_listener)
+            }
+            None => None,
+        };
+        let admin_addr = admin_listener
+            .as_ref()
+            .and_then(|admin_listener| admin_listener.local_addr().ok());
+
+        let audit_log = match &self.config.audit_log {
+            Some(audit_log) => {
+                let path = audit_log.path.display().to_string();
+                let audit_log = AuditLog::open(audit_log.clone())
+                    .map_err(|_| IrisError::AuditLogError(path.clone()))?;
+                tracing::info!("keeping an audit log in {path}");
+                Some(audit_log)
+            }
+            None => None,
+        };
+
+        // A node forwards as many connections at once as it relays sessions of its own
+        let cluster = self
+            .config
+            .cluster
+            .as_ref()
+            .map(|cluster| Cluster::new(cluster, self.config.max_concurrent_relays))
+            .transpose()?;
+        if let Some(cluster_config) = &self.config.cluster {
+            tracing::info!(
+                "running as node {} of a cluster of {}",
+                cluster_config.node_index,
+                cluster_config.nodes.len()
+            );
+        }
+
+        let shard = self
+            .config
+            .cluster
+            .as_ref()
+            .map_or(Shard::WHOLE, ClusterConfig::shard);
+        let mailboxes = match &self.config.mailbox {
+            Some(mailbox) => {
+                let directory = mailbox.directory.display().to_string();
+                let mailboxes = Mailboxes::open(
+                    mailbox.clone(),
+                    shard,
+                    self.config.max_room_identifier_digits,
+                )
+                .map_err(|_| IrisError::MailboxDirectoryError(directory.clone()))?;
+                tracing::info!(
+                    "keeping mailboxes in {directory}, {} of them stored already",
+                    mailboxes.len()
+                );
+                Some(mailboxes)
+            }
+            None => None,
+        };
+
+        let (webhook, webhook_queue) = match &self.config.webhook {
+            Some(webhook) => {
+                tracing::info!("sending events to the webhook at {}", webhook.url);
+                let (webhook, webhook_queue) = Webhook::new(webhook.clone());
+                (Some(webhook), Some(webhook_queue))
+            }
+            None => (None, None),
+        };
+
+        let relay = Arc::new(Relay {
+            room_mapping: Mutex::new(RoomMapping::new(
+                shard,
+                self.config.max_waiting_rooms,
+                self.config.min_room_identifier_digits,
+                self.config.max_room_identifier_digits,
+                self.config.max_room_identifier_occupancy,
+            )),
+            handshake_pool: ThreadPool::new(self.config.max_concurrent_handshakes.max(1)),
+            pending_handshakes: AtomicUsize::new(0),
+            relay_pool: ThreadPool::new(self.config.max_concurrent_relays.max(1)),
+            rate_limiter: RateLimiter::new(RateLimitConfig {
+                max_failed_joins: self.config.max_failed_joins,
+                failed_join_backoff: self.config.failed_join_backoff,
+                ban_duration: self.config.ban_duration,
+                max_room_creations_per_minute: self.config.max_room_creations_per_minute,
+                trust_local_clients: self.config.trust_unix_clients,
+            }),
+            quotas: Quotas::new(QuotaConfig {
+                max_session_bytes: self.config.max_session_bytes,
+                max_session_duration: self.config.max_session_duration,
+                session_idle_timeout: self.config.session_idle_timeout,
+                daily_quota_bytes: self.config.daily_quota_bytes,
+            }),
+            broadcasts: PendingBroadcasts::default(),
+            sessions: Sessions::default(),
+            ended_sessions: EndedSessions::default(),
+            throttle: Throttle::new(
+                self.config.room_bandwidth_limit,
+                self.config.global_bandwidth_limit,
+            ),
+            metrics: Metrics::default(),
+            audit_log,
+            cluster,
+            mailboxes,
+            webhook,
+            address_filter: RwLock::new(AddressFilter::new(
+                self.config.allowed_networks.clone(),
+                self.config.denied_networks.clone(),
+            )),
+            is_draining: AtomicBool::new(false),
+            is_shutting_down: AtomicBool::new(false),
+            config: self.config,
+        });
+
+        let mut background_threads = Vec::new();
+        {
+            let relay = Arc::clone(&relay);
+            background_threads.push(thread::spawn(move || {
+                while !relay.is_shutting_down.load(Ordering::Relaxed) {
+                    thread::sleep(ROOM_REAPER_INTERVAL);
+                    reap_rooms(&relay);
+                    if let Some(mailboxes) = &relay.mailboxes {
+                        for room_identifier in mailboxes.remove_expired() {
+                            tracing::info!("deleted mailbox #{room_identifier} as it expired");
                         }
                     }
-                    IrisMessage::ReceiverConnecting { room_identifier } => {
-                        tracing::debug!("receiver #{addr} is connected");
-                        let mut receiver_socket = socket;
-                        if let Some(mut sender_socket) =
-                            room_mapping.get_and_remove_socket(room_identifier)
-                        {
-                            pool.execute(move || {
-                                // Notify sender that receiver is connected
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                sender_socket
-                                    .write_iris_message(IrisMessage::ReceiverConnected)
-                                    .unwrap();
-
-                                // Forward the SetCipher message
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                sender_socket.forward_message(&mut receiver_socket).unwrap();
-
-                                // Perform the key exchange
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                receiver_socket
-                                    .forward_message(sender_socket.as_mut())
-                                    .unwrap();
-                                sender_socket.forward_message(&mut receiver_socket).unwrap();
-
-                                // Forward the ReadyToReceiveTransfermetadata message
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                receiver_socket
-                                    .forward_message(sender_socket.as_mut())
-                                    .unwrap();
-
-                                // Forward the total files and size
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                sender_socket.forward_message(&mut receiver_socket).unwrap();
-
-                                // Forward the ReadyToReceiveFiles message
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                receiver_socket
-                                    .forward_message(sender_socket.as_mut())
-                                    .unwrap();
-
-                                // Relay the files
-                                // Fail the entire transaction if sender/receiver is disconnected via unwrap.
-                                while sender_socket.forward_message(&mut receiver_socket).is_ok() {
-                                    receiver_socket
-                                        .forward_message(sender_socket.as_mut())
-                                        .unwrap();
-                                }
-
-                                tracing::debug!("done relaying");
-                            });
-                        } else {
-                            // Ignore the error if receiver is disconnected, we do not want to bring
-                            // down the server as well
-                            let _ =
-                                receiver_socket.write_iris_message(IrisMessage::BadRoomIdentifier);
-                        }
+                    relay.rate_limiter.prune();
+                    relay.quotas.prune();
+                    relay.ended_sessions.prune();
+                }
+            }));
+        }
+        if let Some(webhook_queue) = webhook_queue {
+            let relay = Arc::clone(&relay);
+            background_threads.push(thread::spawn(move || {
+                webhook_queue.deliver(&relay.is_shutting_down);
+            }));
+        }
+        if let Some(metrics_listener) = metrics_listener {
+            let relay = Arc::clone(&relay);
+            background_threads.push(thread::spawn(move || {
+                let result = http::serve(&metrics_listener, &relay.is_shutting_down, |request| {
+                    serve_metrics(request, &relay)
+                });
+                if let Err(e) = result {
+                    tracing::error!("stopped exposing metrics: {e}");
+                }
+            }));
+        }
+        if let Some(admin_listener) = admin_listener {
+            let relay = Arc::clone(&relay);
+            background_threads.push(thread::spawn(move || {
+                let result = http::serve(&admin_listener, &relay.is_shutting_down, |request| {
+                    admin::handle(request, &relay)
+                });
+                if let Err(e) = result {
+                    tracing::error!("stopped exposing the admin API: {e}");
+                }
+            }));
+        }
+        let accept_thread = {
+            let relay = Arc::clone(&relay);
+            thread::spawn(move || accept_connections(&listeners, &relay))
+        };
+
+        Ok(RelayHandle {
+            listener_addrs,
+            metrics_addr,
+            admin_addr,
+            relay,
+            accept_thread,
+            background_threads,
+        })
+    }
+
+    /// Runs the relay in the foreground until it fails or the process receives SIGTERM, see
+    /// [`serve_with_config`].
+    pub fn run(self) -> Result<(), IrisError> {
+        self.run_with_reload(|_| {})
+    }
+
+    /// Runs the relay like [`RelayBuilder::run`], calling `reload` every time the process
+    /// receives SIGHUP, e.g. to apply a changed configuration file.
+    pub fn run_with_reload(self, mut reload: impl FnMut(&RelayHandle)) -> Result<(), IrisError> {
+        let drain_timeout = self.config.drain_timeout;
+        let relay = self.spawn()?;
+
+        let terminate = Arc::new(AtomicBool::new(false));
+        let hang_up = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        {
+            use signal_hook::consts::{SIGHUP, SIGTERM};
+            use signal_hook::flag;
+
+            flag::register_conditional_shutdown(SIGTERM, 1, Arc::clone(&terminate))
+                .and_then(|_| flag::register(SIGTERM, Arc::clone(&terminate)))
+                .and_then(|_| flag::register(SIGHUP, Arc::clone(&hang_up)))
+                .map_err(|_| IrisError::ServerError)?;
+        }
+
+        while !terminate.load(Ordering::Relaxed) && !relay.is_finished() {
+            if hang_up.swap(false, Ordering::Relaxed) {
+                reload(&relay);
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        if terminate.load(Ordering::Relaxed) {
+            relay.drain(drain_timeout);
+        }
+        relay.join()
+    }
+}
+
+/// Controls a relay started with [`RelayBuilder::spawn`].
+pub struct RelayHandle {
+    listener_addrs: Vec<ListenerAddr>,
+    metrics_addr: Option<SocketAddr>,
+    admin_addr: Option<ListenerAddr>,
+    relay: Arc<Relay>,
+    accept_thread: JoinHandle<Result<(), IrisError>>,
+    background_threads: Vec<JoinHandle<()>>,
+}
+
+impl RelayHandle {
+    /// The network address the relay is actually listening on, the first one if there are
+    /// several.
+    ///
+    /// # Panics
+    ///
+    /// If the relay only listens on Unix domain sockets.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener_addrs
+            .iter()
+            .find_map(|listener_addr| match listener_addr {
+                ListenerAddr::Tcp(addr) => Some(*addr),
+                #[cfg(unix)]
+                ListenerAddr::Unix(_) => None,
+            })
+            .expect("the relay does not listen on a network address")
+    }
+
+    /// Every address the relay is actually listening on, in the order they were given.
+    pub fn listener_addrs(&self) -> &[ListenerAddr] {
+        &self.listener_addrs
+    }
+
+    /// The address the metrics endpoint is actually listening on, if enabled on a network
+    /// address.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// The address the admin API is actually listening on, if enabled.
+    pub fn admin_addr(&self) -> Option<&ListenerAddr> {
+        self.admin_addr.as_ref()
+    }
+
+    /// Stops accepting new connections and closes the rooms that are still waiting for a
+    /// receiver. Transfers that are already paired are left to finish.
+    pub fn shutdown(&self) {
+        self.relay.is_shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    /// Gracefully stops the relay.
+    ///
+    /// New senders and receivers are turned away with [`IrisMessage::RelayDraining`] and the
+    /// rooms still waiting for a receiver are closed. Transfers that are already paired, whether
+    /// or not a relay worker has picked them up yet, get up to `timeout` to finish before they
+    /// are cut off, after which the relay shuts down. Blocks until then.
+    pub fn drain(&self, timeout: Duration) {
+        tracing::info!(
+            "draining, giving {} transfers up to {timeout:?} to finish",
+            self.relay.sessions.len()
+        );
+        set_draining(&self.relay, true);
+
+        let other_transfers = || {
+            self.relay
+                .cluster
+                .as_ref()
+                .map_or(0, Cluster::forwarded_connections)
+                + self
+                    .relay
+                    .mailboxes
+                    .as_ref()
+                    .map_or(0, Mailboxes::transfers)
+        };
+        let deadline = Instant::now() + timeout;
+        while self.relay.sessions.len() + other_transfers() > 0 && Instant::now() < deadline {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+
+        let terminated_sessions = self.relay.sessions.terminate_all();
+        if terminated_sessions > 0 {
+            tracing::warn!("cut off {terminated_sessions} transfers that did not finish in time");
+        }
+        self.shutdown();
+    }
+
+    /// Changes how many bytes per second a single paired room may forward, `None` lifting the
+    /// limit. Applies to the rooms already being relayed as well.
+    pub fn set_room_bandwidth_limit(&self, room_bandwidth_limit: Option<u64>) {
+        tracing::info!("room bandwidth limit set to {room_bandwidth_limit:?} bytes per second");
+        self.relay.throttle.set_room_limit(room_bandwidth_limit);
+    }
+
+    /// Changes how many bytes per second the whole relay may forward, `None` lifting the limit.
+    /// Applies to the rooms already being relayed as well.
+    pub fn set_global_bandwidth_limit(&self, global_bandwidth_limit: Option<u64>) {
+        tracing::info!("global bandwidth limit set to {global_bandwidth_limit:?} bytes per second");
+        self.relay.throttle.set_global_limit(global_bandwidth_limit);
+    }
+
+    /// Replaces the networks that may and may not connect to the relay, see
+    /// [`RelayConfig::allowed_networks`]. Only applies to new connections.
+    pub fn set_address_lists(
+        &self,
+        allowed_networks: Vec<IpNetwork>,
+        denied_networks: Vec<IpNetwork>,
+    ) {
+        tracing::info!(
+            "now allowing {} networks and denying {} networks",
+            allowed_networks.len(),
+            denied_networks.len()
+        );
+        *self.relay.address_filter.write().unwrap() =
+            AddressFilter::new(allowed_networks, denied_networks);
+    }
+
+    /// Whether the relay has stopped accepting connections.
+    pub fn is_finished(&self) -> bool {
+        self.accept_thread.is_finished()
+    }
+
+    /// Waits for the relay to stop accepting connections, which only happens after
+    /// [`RelayHandle::shutdown`] or if accepting fails.
+    pub fn join(self) -> Result<(), IrisError> {
+        let result = self
+            .accept_thread
+            .join()
+            .unwrap_or(Err(IrisError::ServerError));
+        for background_thread in self.background_threads {
+            let _ = background_thread.join();
+        }
+        result
+    }
+}
+
+/// State shared between the accept loop, the handshake workers and the relay workers.
+struct Relay {
+    config: RelayConfig,
+    room_mapping: Mutex<RoomMapping>,
+    handshake_pool: ThreadPool,
+    /// Connections queued for or going through the handshake.
+    pending_handshakes: AtomicUsize,
+    relay_pool: ThreadPool,
+    rate_limiter: RateLimiter,
+    quotas: Quotas,
+    broadcasts: PendingBroadcasts,
+    sessions: Sessions,
+    ended_sessions: EndedSessions,
+    throttle: Throttle,
+    metrics: Metrics,
+    audit_log: Option<AuditLog>,
+    cluster: Option<Cluster>,
+    mailboxes: Option<Mailboxes>,
+    webhook: Option<Webhook>,
+    address_filter: RwLock<AddressFilter>,
+    is_draining: AtomicBool,
+    is_shutting_down: AtomicBool,
+}
+
+fn accept_connections(listeners: &[Listener], relay: &Arc<Relay>) -> Result<(), IrisError> {
+    while !relay.is_shutting_down.load(Ordering::Relaxed) {
+        let mut accepted_any = false;
+        for listener in listeners {
+            match listener.accept() {
+                Ok((socket, addr)) => {
+                    accepted_any = true;
+                    if !is_address_allowed(addr, relay) {
+                        continue;
                     }
-                    _ => tracing::warn!("detected an unexpected connection"),
+                    // Some platforms hand out sockets that inherit the listener's non-blocking
+                    // mode, the rest of the relay expects blocking sockets. Until the clients
+                    // are paired, the relay only ever writes short messages to them, so one
+                    // that does not read them is given up on as quickly as one that does not
+                    // write.
+                    if socket
+                        .set_nonblocking(false)
+                        .and_then(|_| {
+                            socket.set_write_timeout(Some(relay.config.handshake_timeout))
+                        })
+                        .is_err()
+                    {
+                        tracing::error!("failed to configure the socket for #{addr}");
+                        continue;
+                    }
+                    // If we cannot convert the socket to a IrisTcpStream, we got a massive
+                    // problem so the server should return the error and stop.
+                    let mut socket = IrisTcpStream::new(socket)?;
+
+                    let capacity =
+                        relay.config.max_concurrent_handshakes + relay.config.max_queued_handshakes;
+                    if relay.pending_handshakes.fetch_add(1, Ordering::Relaxed) >= capacity {
+                        relay.pending_handshakes.fetch_sub(1, Ordering::Relaxed);
+                        tracing::warn!("turning away #{addr} as too many handshakes are queued");
+                        // Nothing was written to the socket yet, so this fits in its send buffer
+                        // and does not hold up the accept loop
+                        turn_away_relay_full(&mut socket, relay);
+                        continue;
+                    }
+                    let handshake_relay = Arc::clone(relay);
+                    relay.handshake_pool.execute(move || {
+                        // Counted from here, so that connections do not run out of time while
+                        // they are queued
+                        let deadline = Instant::now() + handshake_relay.config.handshake_timeout;
+                        handle_connection(socket, addr, deadline, &handshake_relay);
+                        handshake_relay
+                            .pending_handshakes
+                            .fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => tracing::error!("failed to accept a connection: {e}"),
+            }
+        }
+        if !accepted_any {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+    }
+
+    tracing::info!("stopped accepting connections");
+    close_waiting_rooms(relay);
+    Ok(())
+}
+
+/// Turns drain mode on or off. Turning it on closes the rooms still waiting for a receiver and
+/// turns away new clients, while leaving the paired transfers to finish.
+fn set_draining(relay: &Relay, is_draining: bool) {
+    let was_draining = {
+        // Switched while the rooms are locked, so that no room is created once the waiting
+        // rooms have been closed
+        let _room_mapping = relay.room_mapping.lock().unwrap();
+        relay.is_draining.swap(is_draining, Ordering::Relaxed)
+    };
+    if is_draining {
+        close_waiting_rooms(relay);
+    }
+    if was_draining != is_draining {
+        tracing::info!(
+            "{} drain mode",
+            if is_draining { "entered" } else { "left" }
+        );
+    }
+}
+
+/// Closes every room that is still waiting for its receiver, along with the broadcasts still
+/// waiting for their sender, letting the clients know that the relay is going away.
+fn close_waiting_rooms(relay: &Relay) {
+    let waiting_rooms = relay.room_mapping.lock().unwrap().remove_all_rooms();
+    for (room_identifier, room) in waiting_rooms {
+        tracing::debug!("closing room #{room_identifier}");
+        close_room(
+            relay,
+            room_identifier,
+            room,
+            Some(IrisMessage::RelayDraining),
+            Termination::Drained,
+        );
+    }
+    for broadcast in relay.broadcasts.remove_all() {
+        tracing::debug!("closing broadcast room #{}", broadcast.room_identifier);
+        close_receivers(
+            relay,
+            broadcast.room_identifier,
+            broadcast.sender_addr,
+            broadcast.receivers,
+            Some(IrisMessage::RelayDraining),
+            Termination::Drained,
+        );
+    }
+}
+
+/// Closes a room that is still waiting, telling its sender, and the receivers that joined but
+/// were not paired yet, with `message` if there is anyone left to tell.
+fn close_room(
+    relay: &Relay,
+    room_identifier: RoomIdentifier,
+    mut room: Room,
+    message: Option<IrisMessage>,
+    termination: Termination,
+) {
+    let receivers = room.take_receivers();
+    let mut sender_socket = room.socket;
+    if let Some(message) = message {
+        // Ignore the error if sender disconnected, the room is gone either way
+        let _ = sender_socket.write_iris_message(message);
+    }
+    if receivers.is_empty() {
+        audit(
+            relay,
+            AuditRecord::unpaired(room_identifier, Some(room.sender_addr), None, termination),
+        );
+    }
+    close_receivers(
+        relay,
+        room_identifier,
+        room.sender_addr,
+        receivers,
+        message,
+        termination,
+    );
+}
+
+/// Lets go of receivers that never got paired with their sender.
+fn close_receivers(
+    relay: &Relay,
+    room_identifier: RoomIdentifier,
+    sender_addr: PeerAddr,
+    receivers: Vec<(IrisTcpStream, PeerAddr)>,
+    message: Option<IrisMessage>,
+    termination: Termination,
+) {
+    for (mut receiver_socket, receiver_addr) in receivers {
+        if let Some(message) = message {
+            // Ignore the error if receiver disconnected, it is being let go regardless
+            let _ = receiver_socket.write_iris_message(message);
+        }
+        audit(
+            relay,
+            AuditRecord::unpaired(
+                room_identifier,
+                Some(sender_addr),
+                Some(receiver_addr),
+                termination,
+            ),
+        );
+    }
+}
+
+fn handle_connection(
+    mut socket: IrisTcpStream,
+    addr: PeerAddr,
+    deadline: Instant,
+    relay: &Arc<Relay>,
+) {
+    let Some(addr) = read_proxy_header(&mut socket, addr, deadline, relay) else {
+        return;
+    };
+
+    let message = match read_handshake_message(
+        &mut socket,
+        deadline,
+        relay.config.max_handshake_message_size,
+    ) {
+        Ok(IrisMessage::Authenticate) => {
+            // Checked before the challenge, so that a banned address cannot keep guessing tokens
+            if let Err(retry_after) = relay.rate_limiter.check_banned(addr.ip()) {
+                tracing::debug!("turning away #{addr} as it is banned for another {retry_after:?}");
+                turn_away_rate_limited(&mut socket, relay, retry_after);
+                return;
+            }
+            match authenticate(&mut socket, deadline, relay) {
+                Ok(Some(label)) => {
+                    tracing::debug!("#{addr} authenticated as {label}");
+                    read_handshake_message(
+                        &mut socket,
+                        deadline,
+                        relay.config.max_handshake_message_size,
+                    )
+                }
+                Ok(None) => {
+                    tracing::warn!("#{addr} failed to authenticate");
+                    relay
+                        .metrics
+                        .record_handshake_failure(HandshakeFailure::AccessDenied);
+                    record_failed_attempt(relay, addr);
+                    let _ = socket.write_iris_message(IrisMessage::AccessDenied);
+                    return;
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Ok(_) if !relay.config.access_tokens.is_empty() => {
+            tracing::warn!("#{addr} did not authenticate");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::AccessDenied);
+            let _ = socket.write_iris_message(IrisMessage::AccessDenied);
+            return;
+        }
+        message => message,
+    };
+    // Clients without a namespace share the rooms that are in none
+    let (namespace, message) = match message {
+        Ok(IrisMessage::EnteringNamespace { namespace }) => (
+            Some(namespace),
+            read_handshake_message(
+                &mut socket,
+                deadline,
+                relay.config.max_handshake_message_size,
+            ),
+        ),
+        message => (None, message),
+    };
+    let message = match message {
+        Ok(message) => message,
+        Err(_) => {
+            tracing::error!("failed to read message from #{addr} before the handshake deadline");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::ReadFailed);
+            return;
+        }
+    };
+
+    if let Err(retry_after) = relay.rate_limiter.check_banned(addr.ip()) {
+        tracing::debug!("turning away #{addr} as it is banned for another {retry_after:?}");
+        turn_away_rate_limited(&mut socket, relay, retry_after);
+        return;
+    }
+
+    // The deadline only applies to the handshake, waiting in a room or relaying may take as
+    // long as the clients need.
+    if socket.set_read_timeout(None).is_err() {
+        tracing::error!("failed to clear the read timeout for #{addr}");
+        return;
+    }
+
+    // Clients cut off during the drain may still ask why
+    if relay.is_draining.load(Ordering::Relaxed)
+        && !matches!(message, IrisMessage::AskingHowSessionEnded { .. })
+    {
+        tracing::debug!("turning away #{addr} as the relay is draining");
+        relay
+            .metrics
+            .record_handshake_failure(HandshakeFailure::RelayDraining);
+        // Ignore the error if the client disconnected, it is being turned away regardless
+        let _ = socket.write_iris_message(IrisMessage::RelayDraining);
+        return;
+    }
+
+    if let IrisMessage::ReceiverConnecting { room_identifier }
+    | IrisMessage::JoiningRoom { room_identifier }
+    | IrisMessage::SenderJoining {
+        room_identifier, ..
+    }
+    | IrisMessage::MailboxDownloading { room_identifier }
+    | IrisMessage::AskingHowSessionEnded { room_identifier } = message
+    {
+        if let Some(cluster) = &relay.cluster {
+            if let Some(node) = cluster.owner_of(room_identifier) {
+                if cluster.can_forward(addr, room_identifier) {
+                    tracing::debug!(
+                        "forwarding #{addr} to {node}, which room #{room_identifier} is on"
+                    );
+                    forward_connection(socket, addr, namespace, message, node, deadline, relay);
+                    return;
+                }
+                tracing::debug!(
+                    "keeping #{addr} here, as {node}, which room #{room_identifier} is on, \
+                     cannot be told where it is"
+                );
+            }
+        }
+    }
+
+    match message {
+        IrisMessage::SenderConnecting
+        | IrisMessage::CreatingRoom
+        | IrisMessage::BroadcastSenderConnecting { .. }
+        | IrisMessage::ReusableSenderConnecting { .. } => {
+            tracing::debug!("sender #{addr} is connected");
+            if let Err(retry_after) = relay.rate_limiter.check_room_creation(addr.ip()) {
+                tracing::warn!("turning away sender #{addr} as it is creating rooms too quickly");
+                turn_away_rate_limited(&mut socket, relay, retry_after);
+                return;
+            }
+            if relay.quotas.is_exhausted(addr.ip()) {
+                tracing::debug!("turning away sender #{addr} as it used up its daily quota");
+                turn_away_over_quota(&mut socket, relay);
+                return;
+            }
+            let mode = match message {
+                IrisMessage::BroadcastSenderConnecting {
+                    max_receivers,
+                    join_window_secs,
+                } => RoomMode::Broadcast(Broadcast::new(
+                    max_receivers.clamp(1, relay.config.max_broadcast_receivers),
+                    Duration::from_secs(join_window_secs).min(relay.config.room_ttl),
+                )),
+                IrisMessage::ReusableSenderConnecting {
+                    max_uses,
+                    lifetime_secs,
+                } => RoomMode::Reusable(Reusable::new(
+                    max_uses.clamp(1, relay.config.max_room_uses),
+                    Duration::from_secs(lifetime_secs).min(relay.config.room_ttl),
+                )),
+                IrisMessage::CreatingRoom => RoomMode::RoleIndependent,
+                _ => RoomMode::Single,
+            };
+            if let Ok(mut sender_socket) = socket.try_clone() {
+                let mut room_mapping = relay.room_mapping.lock().unwrap();
+                // Checked again now that the rooms are locked, as the waiting rooms may have
+                // been closed since the handshake
+                if relay.is_draining.load(Ordering::Relaxed)
+                    || relay.is_shutting_down.load(Ordering::Relaxed)
+                {
+                    drop(room_mapping);
+                    tracing::debug!("turning away sender #{addr} as the relay is draining");
+                    relay
+                        .metrics
+                        .record_handshake_failure(HandshakeFailure::RelayDraining);
+                    let _ = sender_socket.write_iris_message(IrisMessage::RelayDraining);
+                    return;
+                }
+                if is_namespace_full(relay, &room_mapping, namespace) {
+                    drop(room_mapping);
+                    tracing::warn!("turning away sender #{addr} as its namespace is full");
+                    relay
+                        .metrics
+                        .record_handshake_failure(HandshakeFailure::RelayFull);
+                    let _ = sender_socket.write_iris_message(IrisMessage::RelayFull);
+                    return;
                 }
+                let room_identifier = room_mapping.insert_socket(socket, addr, namespace, mode);
+                drop(room_mapping);
+                let Some(room_identifier) = room_identifier else {
+                    tracing::warn!("turning away sender #{addr} as the relay is full");
+                    relay
+                        .metrics
+                        .record_handshake_failure(HandshakeFailure::RelayFull);
+                    let _ = sender_socket.write_iris_message(IrisMessage::RelayFull);
+                    return;
+                };
+
+                if sender_socket
+                    .write_iris_message(IrisMessage::AssignedRoomIdentifier { room_identifier })
+                    .is_err()
+                {
+                    relay
+                        .room_mapping
+                        .lock()
+                        .unwrap()
+                        .get_and_remove_room(room_identifier);
+                    return;
+                }
+                notify(
+                    relay,
+                    WebhookEvent::new(EventKind::Created, room_identifier, Some(addr), None),
+                );
             } else {
-                tracing::error!("failed to read message");
+                tracing::error!("failed to clone the socket");
+                // Ignore the error if sender disconnected, we do not want to bring
+                // down the server as well
+                let _ = socket.write_iris_message(IrisMessage::ServerError);
+            }
+        }
+        IrisMessage::ReceiverConnecting { room_identifier }
+        | IrisMessage::JoiningRoom { room_identifier } => {
+            tracing::debug!("receiver #{addr} is connected");
+            let pairing = match message {
+                IrisMessage::JoiningRoom { .. } => Pairing::RoleIndependent,
+                _ => Pairing::Receiver,
+            };
+            let mut receiver_socket = socket;
+            if let Err(retry_after) = relay.rate_limiter.check_join(addr.ip()) {
+                tracing::debug!("turning away receiver #{addr} for another {retry_after:?}");
+                turn_away_rate_limited(&mut receiver_socket, relay, retry_after);
+                return;
+            }
+            if relay.quotas.is_exhausted(addr.ip()) {
+                tracing::debug!("turning away receiver #{addr} as it used up its daily quota");
+                turn_away_over_quota(&mut receiver_socket, relay);
+                return;
+            }
+            let mut room_mapping = relay.room_mapping.lock().unwrap();
+            let Some(room) = room_mapping
+                .get_room_mut(room_identifier)
+                .filter(|room| room.is_open() && room.namespace == namespace)
+                // Broadcasting and reusing a room only make sense for its sender
+                .filter(|room| {
+                    pairing == Pairing::Receiver
+                        || matches!(room.mode, RoomMode::Single | RoomMode::RoleIndependent)
+                })
+            else {
+                drop(room_mapping);
+                tracing::debug!("receiver #{addr} asked for unknown room #{room_identifier}");
+                turn_away_bad_room(&mut receiver_socket, addr, relay);
+                audit(
+                    relay,
+                    AuditRecord::unpaired(room_identifier, None, Some(addr), Termination::BadRoom),
+                );
+                return;
+            };
+            match &mut room.mode {
+                RoomMode::Broadcast(broadcast) => {
+                    tracing::debug!("receiver #{addr} joined broadcast room #{room_identifier}");
+                    if broadcast.add_receiver(receiver_socket, addr) {
+                        let room = room_mapping.get_and_remove_room(room_identifier);
+                        drop(room_mapping);
+                        if let Some(room) = room {
+                            start_broadcast(relay, room_identifier, room);
+                        }
+                    }
+                }
+                RoomMode::Reusable(reusable) => {
+                    reusable.add_receiver(receiver_socket, addr);
+                    let uses_left = reusable.uses_left;
+                    tracing::info!(
+                        "receiver #{addr} joined reusable room #{room_identifier}, {uses_left} uses left"
+                    );
+                    // Queued while the room is locked so that the sender learns about its
+                    // receivers in the order they joined, but written once it is not
+                    reusable.notices.push(IrisMessage::ReceiverWaiting {
+                        uses_left,
+                        token: reusable.token,
+                    });
+                    let notices = Arc::clone(&reusable.notices);
+                    let sender_socket = room.socket.socket();
+                    drop(room_mapping);
+                    notices.flush(&sender_socket);
+                }
+                RoomMode::Single | RoomMode::RoleIndependent => {
+                    let room = room_mapping.get_and_remove_room(room_identifier);
+                    drop(room_mapping);
+                    if let Some(room) = room {
+                        let sender_pairing = match room.mode {
+                            RoomMode::RoleIndependent => Pairing::RoleIndependent,
+                            _ => Pairing::Sender,
+                        };
+                        spawn_session(
+                            relay,
+                            room_identifier,
+                            room.namespace,
+                            (room.socket, room.sender_addr),
+                            (receiver_socket, addr),
+                            (sender_pairing, pairing),
+                        );
+                    }
+                }
+            }
+        }
+        IrisMessage::SenderJoining {
+            room_identifier,
+            token,
+        } => {
+            tracing::debug!(
+                "sender #{addr} is connected for a receiver of room #{room_identifier}"
+            );
+            let mut sender_socket = socket;
+            if let Err(retry_after) = relay.rate_limiter.check_join(addr.ip()) {
+                tracing::debug!("turning away sender #{addr} for another {retry_after:?}");
+                turn_away_rate_limited(&mut sender_socket, relay, retry_after);
+                return;
+            }
+            if relay.quotas.is_exhausted(addr.ip()) {
+                tracing::debug!("turning away sender #{addr} as it used up its daily quota");
+                turn_away_over_quota(&mut sender_socket, relay);
+                return;
+            }
+            let receiver = relay
+                .broadcasts
+                .claim(room_identifier, namespace, &token)
+                .or_else(|| {
+                    claim_reusable_room_receiver(relay, room_identifier, namespace, &token)
+                });
+            match receiver {
+                Some(receiver) => {
+                    spawn_session(
+                        relay,
+                        room_identifier,
+                        namespace,
+                        (sender_socket, addr),
+                        receiver,
+                        (Pairing::Sender, Pairing::Receiver),
+                    );
+                }
+                None => {
+                    tracing::debug!(
+                        "sender #{addr} asked for a receiver of unknown room #{room_identifier}"
+                    );
+                    turn_away_bad_room(&mut sender_socket, addr, relay);
+                    audit(
+                        relay,
+                        AuditRecord::unpaired(
+                            room_identifier,
+                            Some(addr),
+                            None,
+                            Termination::BadRoom,
+                        ),
+                    );
+                }
+            }
+        }
+        IrisMessage::MailboxUploading => {
+            tracing::debug!("sender #{addr} is connected to leave files in a mailbox");
+            let Some(mailboxes) = &relay.mailboxes else {
+                let _ = socket.write_iris_message(IrisMessage::MailboxUnavailable);
+                return;
+            };
+            if let Err(retry_after) = relay.rate_limiter.check_room_creation(addr.ip()) {
+                tracing::warn!("turning away sender #{addr} as it is creating rooms too quickly");
+                turn_away_rate_limited(&mut socket, relay, retry_after);
+                return;
+            }
+            if relay.quotas.is_exhausted(addr.ip()) {
+                tracing::debug!("turning away sender #{addr} as it used up its daily quota");
+                turn_away_over_quota(&mut socket, relay);
+                return;
+            }
+            let room_mapping = relay.room_mapping.lock().unwrap();
+            let is_full = is_namespace_full(relay, &room_mapping, namespace);
+            drop(room_mapping);
+            if is_full {
+                tracing::warn!("turning away sender #{addr} as its namespace is full");
+                turn_away_relay_full(&mut socket, relay);
+                return;
+            }
+            let Some(transfer) = mailboxes.start_transfer(namespace) else {
+                tracing::warn!("turning away sender #{addr} as the mailbox workers are busy");
+                turn_away_relay_full(&mut socket, relay);
+                return;
+            };
+            let (room_identifier, file) = match mailboxes.create(namespace) {
+                Some(Ok(mailbox)) => mailbox,
+                Some(Err(e)) => {
+                    tracing::error!("failed to create a mailbox for #{addr}: {e}");
+                    let _ = socket.write_iris_message(IrisMessage::ServerError);
+                    return;
+                }
+                None => {
+                    tracing::warn!("turning away sender #{addr} as the mailboxes are full");
+                    relay
+                        .metrics
+                        .record_handshake_failure(HandshakeFailure::RelayFull);
+                    let _ = socket.write_iris_message(IrisMessage::RelayFull);
+                    return;
+                }
+            };
+            if socket
+                .write_iris_message(IrisMessage::AssignedRoomIdentifier { room_identifier })
+                .is_err()
+            {
+                mailboxes.abandon(room_identifier);
+                return;
+            }
+            notify(
+                relay,
+                WebhookEvent::new(EventKind::Created, room_identifier, Some(addr), None),
+            );
+            let upload_relay = Arc::clone(relay);
+            mailboxes.execute(transfer, move || {
+                store_mailbox(&upload_relay, room_identifier, file, (socket, addr));
+            });
+        }
+        IrisMessage::MailboxDownloading { room_identifier } => {
+            tracing::debug!("receiver #{addr} is connected for mailbox #{room_identifier}");
+            let Some(mailboxes) = &relay.mailboxes else {
+                let _ = socket.write_iris_message(IrisMessage::MailboxUnavailable);
+                return;
+            };
+            if let Err(retry_after) = relay.rate_limiter.check_join(addr.ip()) {
+                tracing::debug!("turning away receiver #{addr} for another {retry_after:?}");
+                turn_away_rate_limited(&mut socket, relay, retry_after);
+                return;
+            }
+            if relay.quotas.is_exhausted(addr.ip()) {
+                tracing::debug!("turning away receiver #{addr} as it used up its daily quota");
+                turn_away_over_quota(&mut socket, relay);
+                return;
+            }
+            if is_namespace_busy(relay, namespace) {
+                tracing::warn!("turning away receiver #{addr} as its namespace is busy");
+                turn_away_relay_full(&mut socket, relay);
+                return;
+            }
+            let Some(transfer) = mailboxes.start_transfer(namespace) else {
+                tracing::warn!("turning away receiver #{addr} as the mailbox workers are busy");
+                turn_away_relay_full(&mut socket, relay);
+                return;
+            };
+            let Some(file) = mailboxes.open_stored(namespace, room_identifier) else {
+                tracing::debug!("receiver #{addr} asked for unknown mailbox #{room_identifier}");
+                turn_away_bad_room(&mut socket, addr, relay);
+                audit(
+                    relay,
+                    AuditRecord::unpaired(room_identifier, None, Some(addr), Termination::BadRoom),
+                );
+                return;
+            };
+            let download_relay = Arc::clone(relay);
+            mailboxes.execute(transfer, move || {
+                serve_mailbox(
+                    &download_relay,
+                    namespace,
+                    room_identifier,
+                    file,
+                    (socket, addr),
+                );
+            });
+        }
+        IrisMessage::AskingHowSessionEnded { room_identifier } => {
+            let answer = match relay
+                .ended_sessions
+                .lookup(namespace, room_identifier, addr.ip())
+            {
+                Some(limit) => limit.message(),
+                None => IrisMessage::SessionEndUnknown,
+            };
+            tracing::debug!("telling #{addr} how room #{room_identifier} ended: {answer:?}");
+            let _ = socket.write_iris_message(answer);
+        }
+        _ => {
+            tracing::warn!("detected an unexpected connection");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::UnexpectedMessage);
+        }
+    }
+}
+
+/// Reads the PROXY protocol header the connection may start with and returns the address of
+/// the actual client, or `None` if the connection has to be dropped.
+fn read_proxy_header(
+    socket: &mut IrisTcpStream,
+    addr: PeerAddr,
+    deadline: Instant,
+    relay: &Relay,
+) -> Option<PeerAddr> {
+    if set_remaining_read_timeout(socket, deadline).is_err() {
+        relay
+            .metrics
+            .record_handshake_failure(HandshakeFailure::ReadFailed);
+        return None;
+    }
+
+    match proxy_protocol::read_header(socket.buffered_reader()) {
+        Ok(None) => Some(addr),
+        Ok(Some(_)) if !is_trusted_proxy(addr, relay) => {
+            tracing::warn!("rejecting a PROXY protocol header from untrusted #{addr}");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::UntrustedProxy);
+            None
+        }
+        Ok(Some(ProxyHeader::Proxied(client_addr))) => {
+            tracing::debug!("#{client_addr} is connected through #{addr}");
+            let client_addr = PeerAddr::Tcp(client_addr);
+            is_address_allowed(client_addr, relay).then_some(client_addr)
+        }
+        Ok(Some(ProxyHeader::Local)) => Some(addr),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            tracing::warn!("received an invalid PROXY protocol header from #{addr}");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::InvalidProxyHeader);
+            None
+        }
+        Err(_) => {
+            tracing::error!("failed to read from #{addr} before the handshake deadline");
+            relay
+                .metrics
+                .record_handshake_failure(HandshakeFailure::ReadFailed);
+            None
+        }
+    }
+}
+
+/// Checks `addr` against the allow and deny lists, counting the connection as rejected if it
+/// is not let in.
+fn is_address_allowed(addr: PeerAddr, relay: &Relay) -> bool {
+    let Err(rejection) = relay.address_filter.read().unwrap().check(addr.ip()) else {
+        return true;
+    };
+    match rejection {
+        Rejection::NotAllowed => {
+            tracing::info!("rejected #{addr} as it is not on an allowed network")
+        }
+        Rejection::Denied => tracing::info!("rejected #{addr} as it is on a denied network"),
+    }
+    relay.metrics.record_rejected_connection(rejection);
+    false
+}
+
+fn is_trusted_proxy(addr: PeerAddr, relay: &Relay) -> bool {
+    match addr.ip() {
+        Some(ip) => {
+            relay
+                .config
+                .trusted_proxies
+                .iter()
+                .any(|network| network.contains(ip))
+                || relay
+                    .cluster
+                    .as_ref()
+                    .is_some_and(|cluster| cluster.is_node(ip))
+        }
+        None => relay.config.trust_unix_clients,
+    }
+}
+
+/// Hands a client over to the node of the cluster its room is on, passing on its namespace and
+/// the message it opened with, and then relays between the two of them until either hangs up.
+///
+/// The node the room is on deals with the client as if it had connected there directly,
+/// turning it away or holding it to its limits, so this node gets out of the way as soon as
+/// the client is handed over. The client is turned away with [`IrisMessage::RelayFull`] if
+/// this node is forwarding as many connections as it may already.
+fn forward_connection(
+    mut socket: IrisTcpStream,
+    addr: PeerAddr,
+    namespace: Option<Namespace>,
+    message: IrisMessage,
+    node: &str,
+    deadline: Instant,
+    relay: &Arc<Relay>,
+) {
+    let Some(cluster) = &relay.cluster else {
+        return;
+    };
+    let Some(forwarding) = cluster.start_forwarding() else {
+        tracing::warn!("turning away #{addr} as every forwarding worker is busy");
+        turn_away_relay_full(&mut socket, relay);
+        return;
+    };
+
+    let hand_over = || {
+        let mut node_socket = cluster::connect(node, addr, deadline)?;
+        if let Some(access_token) = relay.config.access_tokens.first() {
+            set_remaining_read_timeout(&node_socket, deadline)?;
+            authenticate_to_node(&mut node_socket, &access_token.token)?;
+            node_socket
+                .set_read_timeout(None)
+                .map_err(|_| IrisError::UserConnectionReadError)?;
+        }
+        if let Some(namespace) = namespace {
+            node_socket.write_iris_message(IrisMessage::EnteringNamespace { namespace })?;
+        }
+        node_socket.write_iris_message(message)?;
+        Ok::<_, IrisError>(node_socket)
+    };
+    let node_socket = match hand_over() {
+        Ok(node_socket) => node_socket,
+        Err(e) => {
+            tracing::error!("failed to forward #{addr} to {node}: {e}");
+            // Ignore the error if the client disconnected, it is being turned away regardless
+            let _ = socket.write_iris_message(IrisMessage::ServerError);
+            return;
+        }
+    };
+
+    let idle_timeout = Some(relay.config.session_idle_timeout);
+    let set_write_timeouts = || {
+        socket.try_clone_stream()?.set_write_timeout(idle_timeout)?;
+        node_socket
+            .try_clone_stream()?
+            .set_write_timeout(idle_timeout)
+    };
+    if set_write_timeouts().is_err() {
+        tracing::error!("failed to set the write timeouts of #{addr}");
+        return;
+    }
+
+    let forwarding_relay = Arc::clone(relay);
+    cluster.execute(forwarding, move || {
+        let Some(cluster) = &forwarding_relay.cluster else {
+            return;
+        };
+        let result = pipe::join(
+            socket,
+            node_socket,
+            &forwarding_relay.throttle.room(),
+            &cluster.quotas().session([None, None]),
+            &|_, _| {},
+            &|_| {},
+        );
+        if let Err(e) = result {
+            tracing::debug!("stopped forwarding #{addr}: {e}");
+        }
+    });
+}
+
+/// Stores what the sender of a mailbox uploads, and tells it when the mailbox expires once the
+/// upload is complete. The sender is let go with the reason if the upload goes over the limits
+/// of the relay or the mailboxes fill up, and the mailbox is thrown away.
+fn store_mailbox(
+    relay: &Relay,
+    room_identifier: RoomIdentifier,
+    mut file: File,
+    (mut socket, addr): (IrisTcpStream, PeerAddr),
+) {
+    let Some(mailboxes) = &relay.mailboxes else {
+        return;
+    };
+    let started_at = Timestamp::now();
+    let quota = relay.quotas.session([addr.ip(), None]);
+
+    let result = socket
+        .set_read_timeout(Some(relay.config.session_idle_timeout))
+        .map_err(TransferFailure::Io)
+        .and_then(|_| {
+            mailbox::receive_upload(
+                socket.buffered_reader(),
+                &mut file,
+                (mailboxes, room_identifier),
+                &relay.throttle.room(),
+                &quota,
+            )
+        });
+    drop(file);
+
+    let (stored_bytes, termination) = match result {
+        Ok(stored_bytes) => match mailboxes.commit(room_identifier) {
+            Ok(_) => {
+                tracing::info!("stored mailbox #{room_identifier} of {stored_bytes} bytes");
+                let expires_in_secs = mailboxes.ttl().as_secs();
+                // Ignore the error if sender disconnected, the mailbox is stored either way
+                let _ = socket.write_iris_message(IrisMessage::MailboxStored { expires_in_secs });
+                (stored_bytes, Termination::Normal)
+            }
+            Err(e) => {
+                tracing::error!("failed to store mailbox #{room_identifier}: {e}");
+                let _ = socket.write_iris_message(IrisMessage::ServerError);
+                (0, Termination::Error)
+            }
+        },
+        Err(failure) => {
+            mailboxes.abandon(room_identifier);
+            match failure {
+                TransferFailure::Limit(limit) => {
+                    tracing::info!(
+                        "threw away mailbox #{room_identifier} as it went over its {} limit",
+                        limit.label()
+                    );
+                    relay.metrics.record_session_limited(limit);
+                    let _ = socket.write_iris_message(limit.message());
+                    (0, limit.termination())
+                }
+                TransferFailure::Full => {
+                    tracing::warn!(
+                        "threw away mailbox #{room_identifier} as the mailboxes are full"
+                    );
+                    turn_away_relay_full(&mut socket, relay);
+                    (0, Termination::RelayFull)
+                }
+                failure => {
+                    tracing::debug!("stopped storing mailbox #{room_identifier}: {failure}");
+                    (0, Termination::Error)
+                }
             }
         }
+    };
+    audit(
+        relay,
+        AuditRecord {
+            room_identifier,
+            sender_address: Some(addr),
+            receiver_address: None,
+            paired_at: Some(started_at),
+            ended_at: Timestamp::now(),
+            sender_to_receiver_bytes: stored_bytes,
+            receiver_to_sender_bytes: 0,
+            termination,
+        },
+    );
+}
+
+/// Sends a stored mailbox to a receiver once it confirmed the key of the mailbox, which is cut
+/// off if the download goes over the limits of the relay. Failing to confirm the key counts as
+/// a failed join, so that the passphrase cannot be guessed any faster than a room's.
+fn serve_mailbox(
+    relay: &Relay,
+    namespace: Option<Namespace>,
+    room_identifier: RoomIdentifier,
+    mut file: File,
+    (mut socket, addr): (IrisTcpStream, PeerAddr),
+) {
+    let started_at = Timestamp::now();
+    let quota = relay.quotas.session([addr.ip(), None]);
+
+    let writer = socket.socket();
+    let result = writer
+        .set_read_timeout(Some(relay.config.session_idle_timeout))
+        .and_then(|_| writer.set_write_timeout(Some(relay.config.session_idle_timeout)))
+        .map_err(TransferFailure::Io)
+        .and_then(|_| {
+            mailbox::confirm_key(
+                &mut file,
+                socket.buffered_reader(),
+                &writer,
+                &pake_identity(namespace, room_identifier),
+            )
+        })
+        .and_then(|_| mailbox::send_download(&mut file, &writer, &relay.throttle.room(), &quota));
+
+    let (sent_bytes, termination) = match result {
+        Ok(sent_bytes) => {
+            tracing::info!("served mailbox #{room_identifier} to #{addr}");
+            (sent_bytes, Termination::Normal)
+        }
+        Err(TransferFailure::Limit(limit)) => {
+            tracing::info!(
+                "cut off the download of mailbox #{room_identifier} as it went over its {} limit",
+                limit.label()
+            );
+            relay.metrics.record_session_limited(limit);
+            (0, limit.termination())
+        }
+        Err(TransferFailure::KeyNotConfirmed) => {
+            tracing::debug!("receiver #{addr} failed to confirm the key of #{room_identifier}");
+            turn_away_bad_room(&mut socket, addr, relay);
+            (0, Termination::BadRoom)
+        }
+        Err(failure) => {
+            tracing::debug!("stopped serving mailbox #{room_identifier}: {failure}");
+            (0, Termination::Error)
+        }
+    };
+    audit(
+        relay,
+        AuditRecord {
+            room_identifier,
+            sender_address: None,
+            receiver_address: Some(addr),
+            paired_at: Some(started_at),
+            ended_at: Timestamp::now(),
+            sender_to_receiver_bytes: sent_bytes,
+            receiver_to_sender_bytes: 0,
+            termination,
+        },
+    );
+}
+
+fn turn_away_rate_limited(socket: &mut IrisTcpStream, relay: &Relay, retry_after: Duration) {
+    relay
+        .metrics
+        .record_handshake_failure(HandshakeFailure::RateLimited);
+    // Round up so that a client waiting as long as it is told does not get turned away again
+    let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    // Ignore the error if the client disconnected, it is being turned away regardless
+    let _ = socket.write_iris_message(IrisMessage::RateLimited { retry_after_secs });
+}
+
+/// Turns away a client that asked for a room that does not exist, which counts as a failed
+/// join towards the limits of its address.
+fn turn_away_bad_room(socket: &mut IrisTcpStream, addr: PeerAddr, relay: &Relay) {
+    relay
+        .metrics
+        .record_handshake_failure(HandshakeFailure::BadRoomIdentifier);
+    record_failed_attempt(relay, addr);
+    // Ignore the error if the client disconnected, it is being turned away regardless
+    let _ = socket.write_iris_message(IrisMessage::BadRoomIdentifier);
+}
+
+/// Counts a failed room join, or a failed authentication, towards the limits of the address it
+/// came from.
+fn record_failed_attempt(relay: &Relay, addr: PeerAddr) {
+    if let FailedJoinOutcome::Banned(ban_duration) =
+        relay.rate_limiter.record_failed_join(addr.ip())
+    {
+        tracing::warn!(
+            "banning the address of #{addr} for {ban_duration:?} after too many failed attempts"
+        );
+        relay.metrics.record_ban();
+    }
+}
+
+fn turn_away_relay_full(socket: &mut IrisTcpStream, relay: &Relay) {
+    relay
+        .metrics
+        .record_handshake_failure(HandshakeFailure::RelayFull);
+    // Ignore the error if the client disconnected, it is being turned away regardless
+    let _ = socket.write_iris_message(IrisMessage::RelayFull);
+}
+
+fn turn_away_over_quota(socket: &mut IrisTcpStream, relay: &Relay) {
+    relay
+        .metrics
+        .record_handshake_failure(HandshakeFailure::QuotaExceeded);
+    // Ignore the error if the client disconnected, it is being turned away regardless
+    let _ = socket.write_iris_message(IrisMessage::DailyQuotaExceeded);
+}
+
+/// Challenges the client to prove that it knows one of the relay's access tokens and returns
+/// the label of the matching token, or `None` if the client's response matches none of them.
+/// A relay without access tokens lets everyone in.
+fn authenticate(
+    socket: &mut IrisTcpStream,
+    deadline: Instant,
+    relay: &Relay,
+) -> Result<Option<String>, IrisError> {
+    if relay.config.access_tokens.is_empty() {
+        socket.write_iris_message(IrisMessage::Authenticated)?;
+        return Ok(Some("anonymous".to_string()));
+    }
+
+    let mut challenge = [0; CHALLENGE_SIZE];
+    OsRng.fill_bytes(&mut challenge);
+    socket.write_iris_message(IrisMessage::AuthenticationChallenge { challenge })?;
+
+    let IrisMessage::AuthenticationResponse { response } =
+        read_handshake_message(socket, deadline, relay.config.max_handshake_message_size)?
+    else {
+        return Ok(None);
+    };
+    let Some(access_token) = relay
+        .config
+        .access_tokens
+        .iter()
+        .find(|access_token| verify_response(&access_token.token, &challenge, &response))
+    else {
+        return Ok(None);
+    };
+
+    socket.write_iris_message(IrisMessage::Authenticated)?;
+    Ok(Some(access_token.label.clone()))
+}
+
+/// Closes the rooms that waited too long for their receiver, letting their senders know, and
+/// forgets about the rooms whose sender already left. Starts the broadcasts whose join window
+/// is over, and lets go of the receivers of broadcasts whose sender did not come for them.
+fn reap_rooms(relay: &Arc<Relay>) {
+    let (expired_rooms, closed_broadcasts, sender_sockets) = {
+        let mut room_mapping = relay.room_mapping.lock().unwrap();
+        (
+            room_mapping.remove_expired_rooms(relay.config.room_ttl),
+            room_mapping.remove_closed_broadcasts(),
+            room_mapping.sender_sockets(),
+        )
+    };
+    // Checked without holding on to the rooms, so that joins and new rooms do not wait for
+    // every sender to be looked at
+    let abandoned_senders: Vec<_> = sender_sockets
+        .into_iter()
+        .filter(|(_, socket)| socket.is_peer_disconnected())
+        .collect();
+    let abandoned_rooms = if abandoned_senders.is_empty() {
+        Vec::new()
+    } else {
+        relay
+            .room_mapping
+            .lock()
+            .unwrap()
+            .remove_abandoned_rooms(abandoned_senders)
+    };
+
+    for (room_identifier, room) in expired_rooms {
+        tracing::info!(
+            "closing room #{room_identifier} as no receiver joined within {:?}",
+            relay.config.room_ttl
+        );
+        close_room(
+            relay,
+            room_identifier,
+            room,
+            Some(IrisMessage::RoomExpired),
+            Termination::Timeout,
+        );
+    }
+    for (room_identifier, room) in abandoned_rooms {
+        tracing::debug!("sender of room #{room_identifier} disconnected, closing the room");
+        close_room(
+            relay,
+            room_identifier,
+            room,
+            Some(IrisMessage::RoomClosed),
+            Termination::Abandoned,
+        );
+    }
+    for (room_identifier, room) in closed_broadcasts {
+        start_broadcast(relay, room_identifier, room);
+    }
+    for PendingBroadcast {
+        room_identifier,
+        sender_addr,
+        receivers,
+        ..
+    } in relay.broadcasts.remove_expired()
+    {
+        tracing::info!(
+            "letting go of {} receivers of broadcast room #{room_identifier} as its sender did not come for them",
+            receivers.len()
+        );
+        close_receivers(
+            relay,
+            room_identifier,
+            sender_addr,
+            receivers,
+            Some(IrisMessage::RoomClosed),
+            Termination::Abandoned,
+        );
+    }
+}
+
+/// Starts a broadcast whose room filled up or whose join window is over.
+///
+/// The sender is told how many receivers joined and given the token to come for them with,
+/// and its connection is paired with the first of them.
+fn start_broadcast(relay: &Arc<Relay>, room_identifier: RoomIdentifier, mut room: Room) {
+    let mut receivers = room.take_receivers();
+    let Some(first_receiver) = receivers.pop() else {
+        return;
+    };
+    let total_receivers = receivers.len() + 1;
+    tracing::info!("starting broadcast room #{room_identifier} to {total_receivers} receivers");
+
+    let token =
+        relay
+            .broadcasts
+            .insert(room_identifier, room.sender_addr, room.namespace, receivers);
+    let mut sender_socket = room.socket;
+    // Ignore the error if sender disconnected, pairing it with the first receiver fails the
+    // same way and the other receivers are let go once nobody comes for them
+    let _ = sender_socket.write_iris_message(IrisMessage::BroadcastStarted {
+        total_receivers,
+        token,
+    });
+    spawn_session(
+        relay,
+        room_identifier,
+        room.namespace,
+        (sender_socket, room.sender_addr),
+        first_receiver,
+        (Pairing::Sender, Pairing::Receiver),
+    );
+}
+
+/// Hands the sender of a reusable room the receiver that has been waiting the longest, closing
+/// the room once its last use is handed out.
+fn claim_reusable_room_receiver(
+    relay: &Relay,
+    room_identifier: RoomIdentifier,
+    namespace: Option<Namespace>,
+    token: &SenderToken,
+) -> Option<(IrisTcpStream, PeerAddr)> {
+    let mut room_mapping = relay.room_mapping.lock().unwrap();
+    let room = room_mapping
+        .get_room_mut(room_identifier)
+        .filter(|room| room.namespace == namespace)?;
+    let RoomMode::Reusable(reusable) = &mut room.mode else {
+        return None;
+    };
+    let receiver = reusable.claim(token)?;
+    if reusable.is_used_up() {
+        tracing::info!("closing reusable room #{room_identifier} as it has no uses left");
+        room_mapping.get_and_remove_room(room_identifier);
+    }
+    Some(receiver)
+}
+
+/// Whether `namespace` already has as many rooms waiting, or transfers being relayed, as its
+/// limits allow.
+fn is_namespace_full(
+    relay: &Relay,
+    room_mapping: &RoomMapping,
+    namespace: Option<Namespace>,
+) -> bool {
+    let Some(limits) = relay.config.limits_of(namespace) else {
+        return false;
+    };
+    limits
+        .max_waiting_rooms
+        .is_some_and(|max_waiting_rooms| room_mapping.len_in(limits.namespace) >= max_waiting_rooms)
+        || is_namespace_busy(relay, namespace)
+}
+
+/// Whether the namespace has as many transfers underway as it may, counting its mailbox uploads
+/// and downloads along with its sessions.
+fn is_namespace_busy(relay: &Relay, namespace: Option<Namespace>) -> bool {
+    let Some(limits) = relay.config.limits_of(namespace) else {
+        return false;
+    };
+    limits.max_sessions.is_some_and(|max_sessions| {
+        let mailbox_transfers = relay
+            .mailboxes
+            .as_ref()
+            .map_or(0, |mailboxes| mailboxes.transfers_in(limits.namespace));
+        relay.sessions.len_in(limits.namespace) + mailbox_transfers >= max_sessions
+    })
+}
+
+/// Records how a room ended, in the audit log and to the webhook.
+fn audit(relay: &Relay, record: AuditRecord) {
+    if let Some(audit_log) = &relay.audit_log {
+        audit_log.record(&record);
+    }
+    notify(relay, WebhookEvent::ended(&record));
+}
+
+fn notify(relay: &Relay, event: WebhookEvent) {
+    if let Some(webhook) = &relay.webhook {
+        webhook.notify(event);
+    }
+}
+
+fn serve_metrics(request: &http::Request, relay: &Relay) -> http::Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let waiting_rooms = relay.room_mapping.lock().unwrap().len();
+            http::Response::new(
+                200,
+                "text/plain; version=0.0.4",
+                relay.metrics.render(&Snapshot {
+                    waiting_rooms,
+                    active_sessions: relay.sessions.len(),
+                    banned_sources: relay.rate_limiter.banned_sources(),
+                    room_bandwidth_limit: relay.throttle.room_limit(),
+                    global_bandwidth_limit: relay.throttle.global_limit(),
+                    throttled_time: relay.throttle.throttled_time(),
+                }),
+            )
+        }
+        _ => http::Response::not_found(),
+    }
+}
+
+/// Reads the first message of a connection, giving up once `deadline` has passed no matter
+/// how slowly the client trickles in its bytes.
+fn read_handshake_message(
+    socket: &mut IrisTcpStream,
+    deadline: Instant,
+    max_size: u32,
+) -> Result<IrisMessage, IrisError> {
+    set_remaining_read_timeout(socket, deadline)?;
+    let size_as_bytes = socket.read_bytes(u32::BITS / 8)?;
+    let size = u32::from_be_bytes(size_as_bytes.try_into().unwrap());
+    if size > max_size {
+        return Err(IrisError::UnexpectedMessage);
+    }
+
+    set_remaining_read_timeout(socket, deadline)?;
+    let serialized_message = socket.read_bytes(size)?;
+    serde_json::from_slice(&serialized_message).map_err(|_| IrisError::DeserializationError)
+}
+
+fn set_remaining_read_timeout(socket: &IrisTcpStream, deadline: Instant) -> Result<(), IrisError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(IrisError::UserConnectionReadError);
+    }
+    socket
+        .set_read_timeout(Some(remaining))
+        .map_err(|_| IrisError::UserConnectionReadError)
+}
+
+/// How a client asked to be paired, which decides what it is told once its peer arrived.
+///
+/// Whatever part they end up playing, the relay calls the client that created a room its
+/// sender and the client that joined it its receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pairing {
+    /// Created the room as a sender, and is told when its receiver arrived.
+    Sender,
+    /// Joined the room as a receiver, and is told nothing as the sender speaks first.
+    Receiver,
+    /// Created or joined the room regardless of roles, and is told when its peer arrived along
+    /// with the role of the peer if the peer asked for one.
+    RoleIndependent,
+}
+
+impl Pairing {
+    fn role(self) -> Option<Role> {
+        match self {
+            Pairing::Sender => Some(Role::Sender),
+            Pairing::Receiver => Some(Role::Receiver),
+            Pairing::RoleIndependent => None,
+        }
+    }
+
+    /// The message telling the client that it was paired with a peer that asked to be paired
+    /// as `peer`, if it is told anything.
+    fn notice(self, peer: Pairing) -> Option<IrisMessage> {
+        match self {
+            Pairing::Sender => Some(IrisMessage::ReceiverConnected),
+            Pairing::Receiver => None,
+            Pairing::RoleIndependent => Some(IrisMessage::PeerConnected {
+                peer_role: peer.role(),
+            }),
+        }
+    }
+}
+
+fn spawn_session(
+    relay: &Arc<Relay>,
+    room_identifier: RoomIdentifier,
+    namespace: Option<Namespace>,
+    sender: (IrisTcpStream, PeerAddr),
+    receiver: (IrisTcpStream, PeerAddr),
+    pairings: (Pairing, Pairing),
+) {
+    let ((mut sender_socket, sender_addr), (mut receiver_socket, receiver_addr)) =
+        (sender, receiver);
+    let (Ok(sender_stream), Ok(receiver_stream)) = (
+        sender_socket.try_clone_stream(),
+        receiver_socket.try_clone_stream(),
+    ) else {
+        tracing::error!("failed to clone the sockets of room #{room_identifier}");
+        audit(
+            relay,
+            AuditRecord::unpaired(
+                room_identifier,
+                Some(sender_addr),
+                Some(receiver_addr),
+                Termination::Error,
+            ),
+        );
+        return;
+    };
+    // Registered before it gets a relay worker, so that the pairs waiting for one are limited
+    // along with the ones being relayed
+    let session = if is_namespace_busy(relay, namespace) {
+        None
+    } else {
+        relay.sessions.register(
+            room_identifier,
+            namespace,
+            (sender_stream, sender_addr),
+            (receiver_stream, receiver_addr),
+            relay.config.max_concurrent_relays,
+        )
+    };
+    let Some(session) = session else {
+        tracing::warn!(
+            "turning away room #{room_identifier} as every relay worker, or every one its \
+             namespace may have, is busy"
+        );
+        turn_away_relay_full(&mut sender_socket, relay);
+        turn_away_relay_full(&mut receiver_socket, relay);
+        audit(
+            relay,
+            AuditRecord::unpaired(
+                room_identifier,
+                Some(sender_addr),
+                Some(receiver_addr),
+                Termination::RelayFull,
+            ),
+        );
+        return;
+    };
+
+    let session_relay = Arc::clone(relay);
+    relay.relay_pool.execute(move || {
+        relay_session(
+            &session_relay,
+            room_identifier,
+            namespace,
+            session,
+            (sender_socket, sender_addr),
+            (receiver_socket, receiver_addr),
+            pairings,
+        );
+    });
+}
+
+/// Tells the clients that they were paired and then gets out of the way, relaying raw bytes
+/// between the two of them until either side hangs up or the session goes over its limits.
+/// Both clients come with the address they connected from.
+fn relay_session(
+    relay: &Relay,
+    room_identifier: RoomIdentifier,
+    namespace: Option<Namespace>,
+    session: SessionGuard,
+    (mut sender_socket, sender_addr): (IrisTcpStream, PeerAddr),
+    (mut receiver_socket, receiver_addr): (IrisTcpStream, PeerAddr),
+    (sender_pairing, receiver_pairing): (Pairing, Pairing),
+) {
+    let paired_at = Timestamp::now();
+    let audit_session = |forwarded_bytes: [u64; 2], termination| {
+        audit(
+            relay,
+            AuditRecord {
+                room_identifier,
+                sender_address: Some(sender_addr),
+                receiver_address: Some(receiver_addr),
+                paired_at: Some(paired_at),
+                ended_at: Timestamp::now(),
+                sender_to_receiver_bytes: forwarded_bytes[Direction::SenderToReceiver as usize],
+                receiver_to_sender_bytes: forwarded_bytes[Direction::ReceiverToSender as usize],
+                termination,
+            },
+        )
+    };
+
+    let idle_timeout = Some(relay.config.session_idle_timeout);
+    if sender_socket
+        .set_write_timeout(idle_timeout)
+        .and_then(|_| receiver_socket.set_write_timeout(idle_timeout))
+        .is_err()
+    {
+        tracing::error!("failed to set the write timeouts of room #{room_identifier}");
+        audit_session([0, 0], Termination::Error);
+        return;
+    }
+
+    notify(
+        relay,
+        WebhookEvent::new(
+            EventKind::Paired,
+            room_identifier,
+            Some(sender_addr),
+            Some(receiver_addr),
+        ),
+    );
+
+    let throttle = relay.throttle.room();
+    let quota = relay.quotas.session([sender_addr.ip(), receiver_addr.ip()]);
+
+    let started_at = Instant::now();
+    let notify = |socket: &mut IrisTcpStream, notice: Option<IrisMessage>| match notice {
+        Some(notice) => socket.write_iris_message(notice),
+        None => Ok(()),
+    };
+    let result = notify(&mut sender_socket, sender_pairing.notice(receiver_pairing))
+        .and_then(|_| {
+            notify(
+                &mut receiver_socket,
+                receiver_pairing.notice(sender_pairing),
+            )
+        })
+        .and_then(|_| {
+            pipe::join(
+                sender_socket,
+                receiver_socket,
+                &throttle,
+                &quota,
+                &|direction, bytes| {
+                    // Counted as they go rather than taken from the pipe, so that they can be
+                    // looked up live and are known even if the pipe fails
+                    session.record_forwarded(direction, bytes);
+                    relay.metrics.record_forwarded_bytes(direction, bytes);
+                },
+                &|limit| {
+                    // Remembered before the connections are closed, so that the clients find it
+                    // as soon as they notice
+                    relay.ended_sessions.record(
+                        namespace,
+                        room_identifier,
+                        limit,
+                        [sender_addr.ip(), receiver_addr.ip()],
+                    );
+                },
+            )
+            .map_err(|_| IrisError::UserConnectionReadError)
+        });
+    relay.metrics.record_session_duration(started_at.elapsed());
+    if let Some(limit) = quota.exceeded() {
+        tracing::info!(
+            "ended room #{room_identifier} as it went over its {} limit",
+            limit.label()
+        );
+        relay.metrics.record_session_limited(limit);
+    }
+
+    let termination = match (quota.exceeded(), session.termination(), &result) {
+        (Some(limit), _, _) => limit.termination(),
+        (None, Some(termination), _) => termination,
+        (None, None, Ok(_)) => Termination::Normal,
+        (None, None, Err(_)) => Termination::Error,
+    };
+    audit_session(session.forwarded_bytes(), termination);
+
+    match result {
+        Ok(statistics) => tracing::debug!(
+            "done relaying room #{room_identifier}: {} bytes to the receiver, {} bytes to the sender",
+            statistics.sender_to_receiver,
+            statistics.receiver_to_sender
+        ),
+        Err(e) => tracing::debug!("stopped relaying room #{room_identifier}: {e}"),
     }
 }
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+
+use crate::ip_network::IpNetwork;
+
+/// Why a connection was refused by the [`AddressFilter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The address is on none of the allowed networks.
+    NotAllowed,
+    /// The address is on one of the denied networks.
+    Denied,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 2] = [Rejection::NotAllowed, Rejection::Denied];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Rejection::NotAllowed => "not_allowed",
+            Rejection::Denied => "denied",
+        }
+    }
+}
+
+/// Decides which addresses may connect to the relay at all.
+///
+/// Denied networks take precedence over allowed ones, and everything not denied is allowed as
+/// long as no network is allowed explicitly. Unix domain socket clients have no address and are
+/// always let in.
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilter {
+    allowed_networks: Vec<IpNetwork>,
+    denied_networks: Vec<IpNetwork>,
+}
+
+impl AddressFilter {
+    pub fn new(allowed_networks: Vec<IpNetwork>, denied_networks: Vec<IpNetwork>) -> Self {
+        Self {
+            allowed_networks,
+            denied_networks,
+        }
+    }
+
+    pub fn check(&self, ip: Option<IpAddr>) -> Result<(), Rejection> {
+        let Some(ip) = ip else {
+            return Ok(());
+        };
+        if self
+            .denied_networks
+            .iter()
+            .any(|network| network.contains(ip))
+        {
+            return Err(Rejection::Denied);
+        }
+        if !self.allowed_networks.is_empty()
+            && !self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(ip))
+        {
+            return Err(Rejection::NotAllowed);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::namespace::Namespace;
+use crate::room_mapping::{Room, RoomIdentifier, RoomMode};
+use crate::IrisMessage;
+
+use super::audit::Termination;
+use super::http::{Request, Response};
+use super::listener::PeerAddr;
+use super::sessions::SessionIdentifier;
+use super::{close_room, set_draining, Relay};
+
+/// A waiting room as seen from the admin API.
+#[derive(Debug, Serialize)]
+struct RoomInfo {
+    room_identifier: RoomIdentifier,
+    namespace: Option<Namespace>,
+    sender_address: PeerAddr,
+    age_secs: u64,
+    /// Receivers that may still join a reusable room, `None` for the other rooms.
+    uses_left: Option<u32>,
+}
+
+impl RoomInfo {
+    fn new(room_identifier: RoomIdentifier, room: &Room) -> Self {
+        Self {
+            room_identifier,
+            namespace: room.namespace,
+            sender_address: room.sender_addr,
+            age_secs: room.age().as_secs(),
+            uses_left: match &room.mode {
+                RoomMode::Reusable(reusable) => Some(reusable.uses_left),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DrainInfo {
+    draining: bool,
+}
+
+/// Answers a request to the admin API in JSON, see [`super::RelayConfig::admin_address`] for
+/// the routes. Rooms are listed oldest first and sessions in the order they were paired.
+pub fn handle(request: &Request, relay: &Relay) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["rooms"]) => Response::json(200, &list_rooms(relay)),
+        ("DELETE", ["rooms", room_identifier]) => match room_identifier.parse() {
+            Ok(room_identifier) => match kill_room(relay, room_identifier) {
+                Some(room) => Response::json(200, &room),
+                None => Response::not_found(),
+            },
+            Err(_) => Response::not_found(),
+        },
+        ("GET", ["sessions"]) => Response::json(200, &relay.sessions.list()),
+        ("DELETE", ["sessions", session_identifier]) => {
+            match session_identifier.parse::<SessionIdentifier>() {
+                Ok(session_identifier) => match relay.sessions.terminate(session_identifier) {
+                    Some(session) => Response::json(200, &session),
+                    None => Response::not_found(),
+                },
+                Err(_) => Response::not_found(),
+            }
+        }
+        ("GET", ["drain"]) => drain_info(relay),
+        ("POST", ["drain"]) => {
+            set_draining(relay, true);
+            drain_info(relay)
+        }
+        ("DELETE", ["drain"]) => {
+            set_draining(relay, false);
+            drain_info(relay)
+        }
+        (_, ["rooms"] | ["rooms", _] | ["sessions"] | ["sessions", _] | ["drain"]) => {
+            Response::method_not_allowed()
+        }
+        _ => Response::not_found(),
+    }
+}
+
+fn list_rooms(relay: &Relay) -> Vec<RoomInfo> {
+    let mut rooms: Vec<RoomInfo> = relay
+        .room_mapping
+        .lock()
+        .unwrap()
+        .rooms()
+        .map(|(room_identifier, room)| RoomInfo::new(room_identifier, room))
+        .collect();
+    rooms.sort_by_key(|room| std::cmp::Reverse(room.age_secs));
+    rooms
+}
+
+/// Closes a waiting room and returns it as it was, or `None` if there is no such room.
+fn kill_room(relay: &Relay, room_identifier: RoomIdentifier) -> Option<RoomInfo> {
+    let room = relay
+        .room_mapping
+        .lock()
+        .unwrap()
+        .get_and_remove_room(room_identifier)?;
+    tracing::warn!("closing room #{room_identifier} on behalf of an operator");
+    let room_info = RoomInfo::new(room_identifier, &room);
+    close_room(
+        relay,
+        room_identifier,
+        room,
+        Some(IrisMessage::RoomClosed),
+        Termination::Killed,
+    );
+    Some(room_info)
+}
+
+fn drain_info(relay: &Relay) -> Response {
+    Response::json(
+        200,
+        &DrainInfo {
+            draining: relay.is_draining.load(Ordering::Relaxed),
+        },
+    )
+}
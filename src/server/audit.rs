@@ -0,0 +1,211 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Serialize, Serializer};
+use usize_cast::FromUsize;
+
+use crate::room_mapping::RoomIdentifier;
+
+use super::listener::PeerAddr;
+
+/// Where the relay keeps its audit log and when it rotates it.
+#[derive(Debug, Clone)]
+pub struct AuditLogConfig {
+    /// File the records are appended to, created if missing.
+    pub path: PathBuf,
+    /// Size in bytes after which the file is rotated, moving it to `<path>.1`, the previous
+    /// `<path>.1` to `<path>.2` and so on.
+    pub max_file_size: u64,
+    /// Number of rotated files kept next to the current one, the oldest being deleted. With 0,
+    /// the file is started over once it reaches its maximum size.
+    pub max_rotated_files: usize,
+}
+
+impl AuditLogConfig {
+    /// Keeps the audit log at `path`, rotating it every 100 MiB and keeping 10 rotated files.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_file_size: 100 * 1024 * 1024,
+            max_rotated_files: 10,
+        }
+    }
+}
+
+/// A point in time, written in RFC 3339 format in UTC with millisecond precision.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp(SystemTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_rfc3339_millis(self.0))
+    }
+}
+
+impl Serialize for PeerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// How a room came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Termination {
+    /// Both clients were paired and hung up on their own.
+    Normal,
+    /// No receiver joined the room before it expired.
+    Timeout,
+    /// A receiver asked for a room that does not exist.
+    BadRoom,
+    /// The sender hung up while waiting for its receiver.
+    Abandoned,
+    /// The relay closed the room, or cut off the transfer, as it was shutting down.
+    Drained,
+    /// An operator closed the room, or cut off the transfer, through the admin API.
+    Killed,
+    /// The transfer went over the size limit of a single session.
+    SessionSizeExceeded,
+    /// The transfer went over the duration limit of a single session.
+    SessionDurationExceeded,
+    /// Nothing went through the transfer for too long, or a client stopped reading.
+    SessionIdleTimeout,
+    /// One of the clients used up its daily quota.
+    DailyQuotaExceeded,
+    /// Both clients were turned away as the relay was relaying as many transfers as it may.
+    RelayFull,
+    /// Relaying failed.
+    Error,
+}
+
+/// A line of the audit log, describing a room from the relay's point of view without anything
+/// about what went through it. The sender is whoever created the room, even in rooms created
+/// regardless of roles where it may end up receiving.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub room_identifier: RoomIdentifier,
+    pub sender_address: Option<PeerAddr>,
+    pub receiver_address: Option<PeerAddr>,
+    pub paired_at: Option<Timestamp>,
+    pub ended_at: Timestamp,
+    pub sender_to_receiver_bytes: u64,
+    pub receiver_to_sender_bytes: u64,
+    pub termination: Termination,
+}
+
+impl AuditRecord {
+    /// A room that ended, right now, without its clients ever being paired.
+    pub fn unpaired(
+        room_identifier: RoomIdentifier,
+        sender_address: Option<PeerAddr>,
+        receiver_address: Option<PeerAddr>,
+        termination: Termination,
+    ) -> Self {
+        Self {
+            room_identifier,
+            sender_address,
+            receiver_address,
+            paired_at: None,
+            ended_at: Timestamp::now(),
+            sender_to_receiver_bytes: 0,
+            receiver_to_sender_bytes: 0,
+            termination,
+        }
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// Appends [`AuditRecord`]s to a file as JSON Lines, one object per line, rotating the file as
+/// it grows.
+///
+/// The audit log is kept apart from the `tracing` output so that it can be retained and
+/// shipped on its own terms. Failing to write to it does not stop the relay, it is logged
+/// instead.
+pub struct AuditLog {
+    config: AuditLogConfig,
+    file: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditLogConfig) -> Result<Self, std::io::Error> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file: Mutex::new(AuditFile { file, size }),
+        })
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("failed to serialize an audit record: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.file.write_all(&line) {
+            tracing::error!("failed to write to {}: {e}", self.config.path.display());
+            return;
+        }
+        file.size += u64::from_usize(line.len());
+
+        if file.size >= self.config.max_file_size {
+            if let Err(e) = self.rotate(&mut file) {
+                tracing::error!("failed to rotate {}: {e}", self.config.path.display());
+            }
+        }
+    }
+
+    fn rotate(&self, file: &mut AuditFile) -> Result<(), std::io::Error> {
+        let path = &self.config.path;
+        if self.config.max_rotated_files == 0 {
+            file.file.set_len(0)?;
+            file.size = 0;
+            return Ok(());
+        }
+
+        let rotated_path = |index: usize| {
+            let mut rotated_path = path.clone().into_os_string();
+            rotated_path.push(format!(".{index}"));
+            PathBuf::from(rotated_path)
+        };
+        let oldest_path = rotated_path(self.config.max_rotated_files);
+        if oldest_path.exists() {
+            fs::remove_file(oldest_path)?;
+        }
+        for index in (1..self.config.max_rotated_files).rev() {
+            let from = rotated_path(index);
+            if from.exists() {
+                fs::rename(from, rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(path, rotated_path(1))?;
+
+        *file = AuditFile {
+            file: open_append(path)?,
+            size: 0,
+        };
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::namespace::Namespace;
+use crate::room_mapping::{generate_sender_token, RoomIdentifier, SenderToken};
+
+use super::listener::PeerAddr;
+
+/// How long a broadcasting sender has to connect once more for each of its receivers after
+/// the broadcast started, before the receivers it did not come for are let go.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The receivers of a broadcast that its sender did not come for yet.
+pub struct PendingBroadcast {
+    pub room_identifier: RoomIdentifier,
+    /// Where the sender connected from.
+    pub sender_addr: PeerAddr,
+    pub namespace: Option<Namespace>,
+    pub receivers: Vec<(IrisTcpStream, PeerAddr)>,
+    expires_at: Instant,
+}
+
+/// Receivers of broadcasts that started, waiting for their sender to connect for them.
+///
+/// Every receiver of a broadcast is relayed on its own connection from the sender, like any
+/// other paired transfer. The sender's first connection is paired with the first receiver
+/// right away, and the sender connects once more for each of the others, proving with the
+/// token it was handed that it is the sender of the room.
+///
+/// Broadcasts are kept by their token rather than by their room, whose identifier is handed out
+/// again as soon as the broadcast starts and may well start another broadcast in the meantime.
+#[derive(Default)]
+pub struct PendingBroadcasts {
+    broadcasts: Mutex<HashMap<SenderToken, PendingBroadcast>>,
+}
+
+impl PendingBroadcasts {
+    /// Keeps `receivers` around for the sender at `sender_addr` and returns the token the
+    /// sender has to present to claim them.
+    pub fn insert(
+        &self,
+        room_identifier: RoomIdentifier,
+        sender_addr: PeerAddr,
+        namespace: Option<Namespace>,
+        receivers: Vec<(IrisTcpStream, PeerAddr)>,
+    ) -> SenderToken {
+        let token = generate_sender_token();
+        if !receivers.is_empty() {
+            self.broadcasts.lock().unwrap().insert(
+                token,
+                PendingBroadcast {
+                    room_identifier,
+                    sender_addr,
+                    namespace,
+                    receivers,
+                    expires_at: Instant::now() + CLAIM_TIMEOUT,
+                },
+            );
+        }
+        token
+    }
+
+    /// Hands out the next receiver of the broadcast in `room_identifier`, or `None` if there is
+    /// no such broadcast in `namespace`, `token` is not its token, or every receiver was
+    /// claimed already.
+    pub fn claim(
+        &self,
+        room_identifier: RoomIdentifier,
+        namespace: Option<Namespace>,
+        token: &SenderToken,
+    ) -> Option<(IrisTcpStream, PeerAddr)> {
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        let broadcast = broadcasts.get_mut(token)?;
+        if broadcast.room_identifier != room_identifier || broadcast.namespace != namespace {
+            return None;
+        }
+        let receiver = broadcast.receivers.pop();
+        if broadcast.receivers.is_empty() {
+            broadcasts.remove(token);
+        }
+        receiver
+    }
+
+    /// Removes the broadcasts whose sender did not claim every receiver in time and hands them
+    /// back with the receivers left over.
+    pub fn remove_expired(&self) -> Vec<PendingBroadcast> {
+        let now = Instant::now();
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        let expired_tokens: Vec<SenderToken> = broadcasts
+            .iter()
+            .filter(|(_, broadcast)| broadcast.expires_at <= now)
+            .map(|(token, _)| *token)
+            .collect();
+
+        expired_tokens
+            .into_iter()
+            .filter_map(|token| broadcasts.remove(&token))
+            .collect()
+    }
+
+    /// Removes every broadcast and hands them back with the receivers left over.
+    pub fn remove_all(&self) -> Vec<PendingBroadcast> {
+        self.broadcasts
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, broadcast)| broadcast)
+            .collect()
+    }
+}
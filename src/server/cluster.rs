@@ -0,0 +1,196 @@
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use threadpool::ThreadPool;
+
+use crate::errors::IrisError;
+use crate::iris_stream::IrisStreamEssentials;
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::room_mapping::{RoomIdentifier, Shard};
+use crate::socket::Socket;
+
+use super::listener::PeerAddr;
+use super::proxy_protocol;
+use super::quota::{QuotaConfig, Quotas};
+
+/// The relays a relay shares its rooms with, e.g. behind DNS round-robin.
+///
+/// Every node hands out its own share of the room identifiers, see [`Shard`], so that any node
+/// can tell which node a room is on from its identifier. Clients joining a room on another node
+/// are forwarded to that node, which sees them as if they had connected to it directly thanks to
+/// a PROXY protocol header. Clients whose address the header cannot carry to the node, such as
+/// clients on a Unix domain socket, are not forwarded. A node forwards as many clients at once as its
+/// `max_concurrent_relays`, and turns the others away as if it were full.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Network address of every node of the cluster, e.g. `"10.0.0.1:7777"`, in the same order
+    /// on all of them. Nodes reach each other on these addresses, and accept PROXY protocol
+    /// headers from them. When the relay restricts access, nodes authenticate to each other with
+    /// the first of their access tokens, which every node has to accept.
+    pub nodes: Vec<String>,
+    /// Which of [`ClusterConfig::nodes`] this relay is.
+    pub node_index: usize,
+}
+
+impl ClusterConfig {
+    pub fn new(nodes: Vec<String>, node_index: usize) -> Self {
+        Self { nodes, node_index }
+    }
+
+    pub(super) fn shard(&self) -> Shard {
+        Shard {
+            index: self.node_index as u64,
+            count: self.nodes.len() as u64,
+        }
+    }
+}
+
+/// The other nodes of the cluster, as seen by one of them.
+pub struct Cluster {
+    shard: Shard,
+    nodes: Vec<String>,
+    /// Addresses of every node, indexed like `nodes`.
+    node_ips: Vec<Vec<IpAddr>>,
+    /// Forwarded connections are only limited by the node owning their room, this merely
+    /// follows what goes through them.
+    quotas: Quotas,
+    forwarded_connections: Arc<AtomicUsize>,
+    max_forwarded_connections: usize,
+    /// Forwarded connections get workers of their own, so that they cannot hold up the
+    /// sessions paired on this node.
+    forward_pool: ThreadPool,
+}
+
+impl Cluster {
+    /// Resolves the addresses of the nodes, to tell their connections apart. At most
+    /// `max_forwarded_connections` connections are forwarded to them at once.
+    pub fn new(
+        config: &ClusterConfig,
+        max_forwarded_connections: usize,
+    ) -> Result<Self, IrisError> {
+        let mut node_ips = Vec::new();
+        for node in &config.nodes {
+            let addrs = node.to_socket_addrs().map_err(|_| {
+                IrisError::InvalidRelayConfig(format!("unable to resolve cluster node {node}"))
+            })?;
+            node_ips.push(addrs.map(|addr| addr.ip()).collect());
+        }
+
+        Ok(Self {
+            shard: config.shard(),
+            nodes: config.nodes.clone(),
+            node_ips,
+            quotas: Quotas::new(QuotaConfig {
+                max_session_bytes: None,
+                max_session_duration: None,
+                session_idle_timeout: Duration::MAX,
+                daily_quota_bytes: None,
+            }),
+            forwarded_connections: Arc::new(AtomicUsize::new(0)),
+            max_forwarded_connections,
+            forward_pool: ThreadPool::new(max_forwarded_connections.max(1)),
+        })
+    }
+
+    /// The address of the node `room_identifier` is on, or `None` if it is on this node.
+    pub fn owner_of(&self, room_identifier: RoomIdentifier) -> Option<&str> {
+        let index = self.shard.index_of(room_identifier);
+        (index != self.shard.index).then(|| self.nodes[index as usize].as_str())
+    }
+
+    /// Whether a client at `client_addr` can be forwarded to the node `room_identifier` is on.
+    /// That node holds the client to its limits and access rules by the address the PROXY
+    /// protocol header gives it, so a client the header cannot describe is kept on this node.
+    pub fn can_forward(&self, client_addr: PeerAddr, room_identifier: RoomIdentifier) -> bool {
+        let index = self.shard.index_of(room_identifier) as usize;
+        self.node_ips[index]
+            .iter()
+            .any(|node_ip| proxy_protocol::source_for(client_addr, *node_ip).is_some())
+    }
+
+    pub fn is_node(&self, ip: IpAddr) -> bool {
+        self.node_ips.iter().flatten().any(|node_ip| *node_ip == ip)
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    /// Number of connections being forwarded to other nodes.
+    pub fn forwarded_connections(&self) -> usize {
+        self.forwarded_connections.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection as being forwarded until the returned guard is dropped, or returns
+    /// `None` if as many are being forwarded as the node allows.
+    pub fn start_forwarding(&self) -> Option<ForwardingGuard> {
+        self.forwarded_connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |forwarded| {
+                (forwarded < self.max_forwarded_connections).then_some(forwarded + 1)
+            })
+            .ok()?;
+        Some(ForwardingGuard {
+            forwarded_connections: Arc::clone(&self.forwarded_connections),
+        })
+    }
+
+    /// Runs a connection let in by [`Cluster::start_forwarding`] on a forwarding worker, for
+    /// which there is one per connection allowed at once.
+    pub fn execute(&self, forwarding: ForwardingGuard, job: impl FnOnce() + Send + 'static) {
+        self.forward_pool.execute(move || {
+            job();
+            drop(forwarding);
+        });
+    }
+}
+
+pub struct ForwardingGuard {
+    forwarded_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ForwardingGuard {
+    fn drop(&mut self) {
+        self.forwarded_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connects to the node at `node` on behalf of the client at `client_addr`, giving up once
+/// `deadline` has passed, and tells the node where the client is.
+pub fn connect(
+    node: &str,
+    client_addr: PeerAddr,
+    deadline: Instant,
+) -> Result<IrisTcpStream, IrisError> {
+    let addrs: Vec<SocketAddr> = node
+        .to_socket_addrs()
+        .map_err(|_| IrisError::StreamInitializationError)?
+        .collect();
+    // Addresses of the same family as the client's are preferred, so that the header carries
+    // the client's address as is
+    let (node_addr, header) = addrs
+        .iter()
+        .filter(|addr| {
+            client_addr
+                .ip()
+                .is_some_and(|ip| ip.is_ipv4() == addr.is_ipv4())
+        })
+        .chain(&addrs)
+        .find_map(|addr| Some((*addr, proxy_protocol::v1_header(client_addr, *addr)?)))
+        .ok_or(IrisError::StreamInitializationError)?;
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(IrisError::StreamInitializationError);
+    }
+    let stream = TcpStream::connect_timeout(&node_addr, remaining)
+        .map_err(|_| IrisError::StreamInitializationError)?;
+    stream
+        .set_nodelay(true)
+        .map_err(|_| IrisError::StreamInitializationError)?;
+
+    let mut connection = IrisTcpStream::new(Socket::Tcp(stream))?;
+    connection.write_bytes(header.as_bytes())?;
+    Ok(connection)
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::namespace::Namespace;
+use crate::room_mapping::RoomIdentifier;
+
+use super::quota::SessionLimit;
+
+/// How long the relay remembers why it cut off a session, which is plenty for its clients to
+/// notice and ask.
+const REMEMBERED_FOR: Duration = Duration::from_secs(60);
+
+struct EndedSession {
+    limit: SessionLimit,
+    ips: [Option<IpAddr>; 2],
+    ended_at: Instant,
+}
+
+/// The sessions the relay recently cut off for going over one of their limits.
+///
+/// The pipe between two clients is opaque, so the relay has no way of telling them why it is
+/// closing their connections. Instead, a client whose connection dropped out from under it
+/// reconnects and asks, and only the clients of the session are told.
+#[derive(Default)]
+pub struct EndedSessions {
+    sessions: Mutex<HashMap<(Option<Namespace>, RoomIdentifier), EndedSession>>,
+}
+
+impl EndedSessions {
+    pub fn record(
+        &self,
+        namespace: Option<Namespace>,
+        room_identifier: RoomIdentifier,
+        limit: SessionLimit,
+        ips: [Option<IpAddr>; 2],
+    ) {
+        self.sessions.lock().unwrap().insert(
+            (namespace, room_identifier),
+            EndedSession {
+                limit,
+                ips,
+                ended_at: Instant::now(),
+            },
+        );
+    }
+
+    /// The limit that the session of the room went over, if it was cut off recently and `ip`
+    /// is where one of its clients connected from.
+    pub fn lookup(
+        &self,
+        namespace: Option<Namespace>,
+        room_identifier: RoomIdentifier,
+        ip: Option<IpAddr>,
+    ) -> Option<SessionLimit> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&(namespace, room_identifier))
+            .filter(|session| session.ended_at.elapsed() < REMEMBERED_FOR)
+            .filter(|session| session.ips.contains(&ip))
+            .map(|session| session.limit)
+    }
+
+    pub fn prune(&self) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.ended_at.elapsed() < REMEMBERED_FOR);
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::socket::Socket;
+
+use super::listener::Listener;
+
+/// How long a client of one of the relay's HTTP endpoints has to send its whole request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes read for the request line and headers of a request, or the status line of a
+/// response.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// How long the accept loop sleeps when there is no connection waiting to be accepted.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The bare minimum of an HTTP/1.1 request needed by the relay's endpoints.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    /// Responds with `body` serialized as JSON.
+    pub fn json(status: u16, body: &impl Serialize) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(body) => Self::new(status, "application/json", body),
+            Err(_) => Self::new(500, "text/plain", "internal server error\n"),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404, "text/plain", "not found\n")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::new(405, "text/plain", "method not allowed\n")
+    }
+}
+
+/// Answers requests on `listener` one at a time with `handler` until `is_shutting_down` is set.
+///
+/// Meant for low traffic operator endpoints such as metrics, so there is no need to handle
+/// clients concurrently.
+pub fn serve(
+    listener: &Listener,
+    is_shutting_down: &AtomicBool,
+    handler: impl Fn(&Request) -> Response,
+) -> Result<(), std::io::Error> {
+    listener.set_nonblocking(true)?;
+
+    while !is_shutting_down.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                if let Err(e) = handle_connection(stream, &handler) {
+                    tracing::debug!("failed to answer HTTP request from #{addr}: {e}");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => tracing::error!("failed to accept an HTTP connection: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(
+    stream: Socket,
+    handler: &impl Fn(&Request) -> Response,
+) -> Result<(), std::io::Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let reader = DeadlineReader {
+        socket: &stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    let response = match read_request(&mut BufReader::new(reader.take(MAX_HEAD_SIZE))) {
+        Ok(request) => handler(&request),
+        Err(_) => Response::new(400, "text/plain", "bad request\n"),
+    };
+    write_response(&stream, &response)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request, std::io::Error> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(ErrorKind::InvalidData.into());
+    };
+
+    // None of the endpoints care about the headers, but they still need to be consumed
+    while !read_line(reader)?.trim_end().is_empty() {}
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// Reads a whole line, failing if the reader runs out before its end.
+fn read_line(reader: &mut impl BufRead) -> Result<String, std::io::Error> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line)
+}
+
+/// Reads from a socket until `deadline`, however slowly the other end sends.
+struct DeadlineReader<'a> {
+    socket: &'a Socket,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        let mut socket = self.socket;
+        socket.read(buf)
+    }
+}
+
+fn write_response(mut stream: &Socket, response: &Response) -> Result<(), std::io::Error> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+/// Sends `body` as JSON to `path` on the HTTP server at `authority`, e.g. `"10.0.0.1:8080"`,
+/// and returns the status of the response. The whole exchange has to fit in `timeout`.
+pub fn post_json(
+    authority: &str,
+    path: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<u16, std::io::Error> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            Err(std::io::Error::from(ErrorKind::TimedOut))
+        } else {
+            Ok(remaining)
+        }
+    };
+
+    let mut last_error = std::io::Error::from(ErrorKind::AddrNotAvailable);
+    let mut stream = None;
+    for addr in authority.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, remaining()?) {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let stream = Socket::Tcp(stream.ok_or(last_error)?);
+
+    stream.set_write_timeout(Some(remaining()?))?;
+    let mut writer = &stream;
+    write!(
+        writer,
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()?;
+
+    // Only the status matters, the rest of the response is left unread
+    let reader = DeadlineReader {
+        socket: &stream,
+        deadline,
+    };
+    let status_line = read_line(&mut BufReader::new(reader.take(MAX_HEAD_SIZE)))?;
+    let mut parts = status_line.split_whitespace();
+    match (parts.next(), parts.next().map(str::parse)) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/") => Ok(status),
+        _ => Err(ErrorKind::InvalidData.into()),
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use socket2::{Domain, Type};
+
+use crate::socket::Socket;
+
+/// Prefix of listen addresses that refer to a Unix domain socket rather than a network address.
+const UNIX_ADDRESS_PREFIX: &str = "unix:";
+
+/// How many connections the operating system queues up before the relay accepts them.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// An address the relay is listening on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            ListenerAddr::Unix(path) => write!(f, "{UNIX_ADDRESS_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Where a client connected from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Clients connecting over a Unix domain socket are on the same machine and cannot be told
+    /// apart.
+    #[cfg(unix)]
+    Unix,
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            #[cfg(unix)]
+            PeerAddr::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            PeerAddr::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    /// Binds `address`, which is either a network address such as `0.0.0.0:8080` or
+    /// `[::]:8080`, or the path of a Unix domain socket prefixed with `unix:`.
+    ///
+    /// Whether IPv6 listeners accept IPv4 connections as well is up to the operating system
+    /// unless `ipv6_only` says otherwise.
+    pub fn bind(address: &str, ipv6_only: Option<bool>) -> Result<Self, std::io::Error> {
+        match address.strip_prefix(UNIX_ADDRESS_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => bind_unix(PathBuf::from(path)),
+            #[cfg(not(unix))]
+            Some(_) => Err(ErrorKind::Unsupported.into()),
+            None => bind_tcp(address, ipv6_only),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<ListenerAddr, std::io::Error> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenerAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(ListenerAddr::Unix(path.clone())),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> Result<(Socket, PeerAddr), std::io::Error> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, addr)| (Socket::Tcp(stream), PeerAddr::Tcp(addr))),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener
+                .accept()
+                .map(|(stream, _)| (Socket::Unix(stream), PeerAddr::Unix)),
+        }
+    }
+}
+
+/// Whether `address` can only be reached from this machine, being either a Unix domain socket
+/// or a network address that only resolves to loopback addresses.
+pub fn is_local_address(address: &str) -> bool {
+    if address.starts_with(UNIX_ADDRESS_PREFIX) {
+        return true;
+    }
+    match address.to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|addr| addr.ip().is_loopback())
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_tcp(address: &str, ipv6_only: Option<bool>) -> Result<Listener, std::io::Error> {
+    let mut last_error = std::io::Error::from(ErrorKind::AddrNotAvailable);
+    for addr in address.to_socket_addrs()? {
+        match bind_tcp_addr(addr, ipv6_only) {
+            Ok(listener) => return Ok(Listener::Tcp(listener)),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn bind_tcp_addr(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<TcpListener, std::io::Error> {
+    let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let Some(ipv6_only) = ipv6_only.filter(|_| addr.is_ipv6()) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Same as the standard library, so that a restarted relay does not have to wait for the
+    // connections of its previous run to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Binds a Unix domain socket at `path`, taking over the socket file left behind by a previous
+/// run but not one that another process is still listening on.
+#[cfg(unix)]
+fn bind_unix(path: PathBuf) -> Result<Listener, std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() || UnixStream::connect(&path).is_ok() {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    Ok(Listener::Unix { listener, path })
+}
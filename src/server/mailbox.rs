@@ -0,0 +1,564 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use hmac::Mac;
+use rand::Rng;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use thiserror::Error;
+use threadpool::ThreadPool;
+use usize_cast::{FromUsize, IntoUsize};
+
+use crate::mailbox::{key_confirmation_mac, PAKE_PASSWORD_SIZE, SALT_SIZE};
+use crate::namespace::Namespace;
+use crate::room_mapping::{RoomIdentifier, Shard};
+use crate::socket::Socket;
+use crate::IrisMessage;
+
+use super::quota::{SessionLimit, SessionQuota};
+use super::throttle::RoomThrottle;
+
+/// Size of the buffer uploads and downloads are copied through.
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Size of the largest message read before the files of a mailbox, from the mailbox or from
+/// its receiver, all of which are far smaller.
+const MAX_HEADER_MESSAGE_SIZE: u32 = 1024;
+
+/// Where the relay keeps the files that senders leave for receivers that are not online, and
+/// for how long.
+///
+/// A mailbox holds what the sender sent as it went through the relay, which is encrypted end
+/// to end, so the relay never sees what is in it.
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// Directory the mailboxes are kept in, created if missing. Mailboxes found there when the
+    /// relay starts are served until they expire.
+    pub directory: PathBuf,
+    /// How long a mailbox is kept after it was uploaded. It may be downloaded any number of
+    /// times until then.
+    pub ttl: Duration,
+    /// Size in bytes of the largest mailbox, the upload being cut off past it.
+    pub max_size: u64,
+    /// Number of mailboxes, stored or being uploaded, after which new uploads are turned away.
+    pub max_mailboxes: usize,
+    /// Size in bytes of all the mailboxes together, uploads being cut off past it.
+    pub max_total_bytes: u64,
+    /// Number of uploads and downloads underway at once, after which new ones are turned away.
+    /// They run on workers of their own, so they never hold up the transfers between clients.
+    pub max_transfers: usize,
+}
+
+impl MailboxConfig {
+    /// Keeps mailboxes in `directory` for a day, each of them up to 1 GiB and up to 1000 or
+    /// 16 GiB of them, with up to 16 uploads and downloads at once.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_size: 1024 * 1024 * 1024,
+            max_mailboxes: 1000,
+            max_total_bytes: 16 * 1024 * 1024 * 1024,
+            max_transfers: 16,
+        }
+    }
+}
+
+/// A mailbox, along with the namespace it was uploaded in and the bytes it takes up, counted as
+/// they are uploaded.
+struct Mailbox {
+    namespace: Option<Namespace>,
+    size: u64,
+    /// When the mailbox expires, once it is completely uploaded.
+    expires_at: Option<SystemTime>,
+}
+
+/// The mailboxes of a relay, each of them a file named after its namespace, if any, and its
+/// identifier: `[<namespace>.]<id>.partial` while it is being uploaded and
+/// `[<namespace>.]<id>.mailbox` once it is complete. A mailbox is only ever downloaded in the
+/// namespace it was uploaded in.
+///
+/// Identifiers are taken from the same shard as the rooms, so that the nodes of a cluster
+/// forward downloads to the node holding the mailbox, but always with the most digits allowed
+/// as a mailbox lives far longer than a room.
+pub struct Mailboxes {
+    config: MailboxConfig,
+    shard: Shard,
+    digits: u32,
+    mailboxes: Mutex<HashMap<RoomIdentifier, Mailbox>>,
+    /// Sum of the sizes of the mailboxes, only changed with them locked.
+    total_bytes: AtomicU64,
+    /// Number of uploads and downloads underway in each namespace.
+    transfers: Arc<Mutex<HashMap<Option<Namespace>, usize>>>,
+    transfer_pool: ThreadPool,
+}
+
+impl Mailboxes {
+    /// Picks up the mailboxes stored in the configured directory, throwing away those that
+    /// expired and the uploads that never completed.
+    pub fn open(config: MailboxConfig, shard: Shard, digits: u32) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        let now = SystemTime::now();
+        let mut mailboxes = HashMap::new();
+        let mut total_bytes = 0;
+        for entry in fs::read_dir(&config.directory)? {
+            let path = entry?.path();
+            let Some((namespace, room_identifier)) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(parse_file_stem)
+            else {
+                continue;
+            };
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("mailbox") => {
+                    let metadata = fs::metadata(&path)?;
+                    let expires_at = metadata.modified()? + config.ttl;
+                    if expires_at <= now {
+                        fs::remove_file(&path)?;
+                    } else {
+                        total_bytes += metadata.len();
+                        mailboxes.insert(
+                            room_identifier,
+                            Mailbox {
+                                namespace,
+                                size: metadata.len(),
+                                expires_at: Some(expires_at),
+                            },
+                        );
+                    }
+                }
+                Some("partial") => fs::remove_file(&path)?,
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            transfer_pool: ThreadPool::new(config.max_transfers.max(1)),
+            config,
+            shard,
+            digits,
+            mailboxes: Mutex::new(mailboxes),
+            total_bytes: AtomicU64::new(total_bytes),
+            transfers: Arc::default(),
+        })
+    }
+
+    /// Number of mailboxes, stored or being uploaded.
+    pub fn len(&self) -> usize {
+        self.mailboxes.lock().unwrap().len()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.config.ttl
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.config.max_size
+    }
+
+    /// Sets aside an identifier for a new mailbox of `namespace` and creates the file it is
+    /// uploaded to, or returns `None` if the relay keeps as many mailboxes, or as many bytes of
+    /// them, as it may.
+    pub fn create(
+        &self,
+        namespace: Option<Namespace>,
+    ) -> Option<Result<(RoomIdentifier, File), std::io::Error>> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        if mailboxes.len() >= self.config.max_mailboxes
+            || self.total_bytes.load(Ordering::Relaxed) >= self.config.max_total_bytes
+        {
+            return None;
+        }
+        let (first_room_identifier, room_identifiers) =
+            self.shard.room_identifier_range(self.digits);
+        if u64::from_usize(mailboxes.len()) >= room_identifiers {
+            return None;
+        }
+
+        // Probe from a random identifier, like rooms do, to be sure to find a free one
+        let mut position = rand::thread_rng().gen_range(0..room_identifiers);
+        let room_identifier = loop {
+            let room_identifier = first_room_identifier + position * self.shard.count;
+            if let Entry::Vacant(entry) = mailboxes.entry(room_identifier) {
+                entry.insert(Mailbox {
+                    namespace,
+                    size: 0,
+                    expires_at: None,
+                });
+                break room_identifier;
+            }
+            position = (position + 1) % room_identifiers;
+        };
+        drop(mailboxes);
+
+        Some(
+            match File::create(self.path(namespace, room_identifier, "partial")) {
+                Ok(file) => Ok((room_identifier, file)),
+                Err(e) => {
+                    self.mailboxes.lock().unwrap().remove(&room_identifier);
+                    Err(e)
+                }
+            },
+        )
+    }
+
+    /// Counts `bytes` more towards a mailbox being uploaded, or returns `false` if the mailboxes
+    /// would then take up more than they may.
+    pub fn reserve(&self, room_identifier: RoomIdentifier, bytes: u64) -> bool {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed) + bytes;
+        if total_bytes > self.config.max_total_bytes {
+            return false;
+        }
+        let Some(mailbox) = mailboxes.get_mut(&room_identifier) else {
+            return false;
+        };
+        mailbox.size += bytes;
+        self.total_bytes.store(total_bytes, Ordering::Relaxed);
+        true
+    }
+
+    /// Makes a completely uploaded mailbox available for download and returns when it expires.
+    pub fn commit(&self, room_identifier: RoomIdentifier) -> Result<SystemTime, std::io::Error> {
+        let namespace = self.namespace_of(room_identifier);
+        if let Err(e) = fs::rename(
+            self.path(namespace, room_identifier, "partial"),
+            self.path(namespace, room_identifier, "mailbox"),
+        ) {
+            self.abandon(room_identifier);
+            return Err(e);
+        }
+        let expires_at = SystemTime::now() + self.config.ttl;
+        if let Some(mailbox) = self.mailboxes.lock().unwrap().get_mut(&room_identifier) {
+            mailbox.expires_at = Some(expires_at);
+        }
+        Ok(expires_at)
+    }
+
+    /// Throws away a mailbox whose upload did not complete.
+    pub fn abandon(&self, room_identifier: RoomIdentifier) {
+        let Some(mailbox) = self.mailboxes.lock().unwrap().remove(&room_identifier) else {
+            return;
+        };
+        self.total_bytes.fetch_sub(mailbox.size, Ordering::Relaxed);
+        let _ = fs::remove_file(self.path(mailbox.namespace, room_identifier, "partial"));
+    }
+
+    /// Opens a stored mailbox of `namespace` that has not expired yet.
+    pub fn open_stored(
+        &self,
+        namespace: Option<Namespace>,
+        room_identifier: RoomIdentifier,
+    ) -> Option<File> {
+        let mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.get(&room_identifier)?;
+        let is_stored = mailbox
+            .expires_at
+            .is_some_and(|expires_at| expires_at > SystemTime::now());
+        if !is_stored || mailbox.namespace != namespace {
+            return None;
+        }
+        drop(mailboxes);
+        File::open(self.path(namespace, room_identifier, "mailbox")).ok()
+    }
+
+    /// Deletes the mailboxes that expired and returns their identifiers. Downloads that are
+    /// already underway keep going on platforms that let open files be deleted.
+    pub fn remove_expired(&self) -> Vec<RoomIdentifier> {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .retain(|room_identifier, mailbox| {
+                let is_expired = mailbox
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= now);
+                if is_expired {
+                    expired.push((mailbox.namespace, *room_identifier));
+                    self.total_bytes.fetch_sub(mailbox.size, Ordering::Relaxed);
+                }
+                !is_expired
+            });
+        for (namespace, room_identifier) in &expired {
+            if let Err(e) = fs::remove_file(self.path(*namespace, *room_identifier, "mailbox")) {
+                tracing::error!("failed to delete expired mailbox #{room_identifier}: {e}");
+            }
+        }
+        expired
+            .into_iter()
+            .map(|(_, room_identifier)| room_identifier)
+            .collect()
+    }
+
+    /// Number of uploads and downloads underway.
+    pub fn transfers(&self) -> usize {
+        self.transfers.lock().unwrap().values().sum()
+    }
+
+    /// Number of uploads and downloads of `namespace` underway.
+    pub fn transfers_in(&self, namespace: Namespace) -> usize {
+        self.transfers
+            .lock()
+            .unwrap()
+            .get(&Some(namespace))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Lets in an upload or a download of `namespace`, counted as underway until the returned
+    /// guard is dropped, or returns `None` if as many are underway as the relay allows.
+    pub fn start_transfer(&self, namespace: Option<Namespace>) -> Option<TransferGuard> {
+        let mut transfers = self.transfers.lock().unwrap();
+        if transfers.values().sum::<usize>() >= self.config.max_transfers {
+            return None;
+        }
+        *transfers.entry(namespace).or_default() += 1;
+        Some(TransferGuard {
+            transfers: Arc::clone(&self.transfers),
+            namespace,
+        })
+    }
+
+    /// Runs a transfer let in by [`Mailboxes::start_transfer`] on a transfer worker, for which
+    /// there is one per transfer allowed at once.
+    pub fn execute(&self, transfer: TransferGuard, job: impl FnOnce() + Send + 'static) {
+        self.transfer_pool.execute(move || {
+            job();
+            drop(transfer);
+        });
+    }
+
+    fn namespace_of(&self, room_identifier: RoomIdentifier) -> Option<Namespace> {
+        self.mailboxes
+            .lock()
+            .unwrap()
+            .get(&room_identifier)
+            .and_then(|mailbox| mailbox.namespace)
+    }
+
+    fn path(
+        &self,
+        namespace: Option<Namespace>,
+        room_identifier: RoomIdentifier,
+        extension: &str,
+    ) -> PathBuf {
+        self.config.directory.join(match namespace {
+            Some(namespace) => format!("{namespace}.{room_identifier}.{extension}"),
+            None => format!("{room_identifier}.{extension}"),
+        })
+    }
+}
+
+/// Reads the namespace and the identifier of a mailbox back from the name of its file, see
+/// [`Mailboxes`].
+fn parse_file_stem(stem: &str) -> Option<(Option<Namespace>, RoomIdentifier)> {
+    match stem.rsplit_once('.') {
+        Some((namespace, room_identifier)) => {
+            Some((Some(namespace.parse().ok()?), room_identifier.parse().ok()?))
+        }
+        None => Some((None, stem.parse().ok()?)),
+    }
+}
+
+pub struct TransferGuard {
+    transfers: Arc<Mutex<HashMap<Option<Namespace>, usize>>>,
+    namespace: Option<Namespace>,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        let mut transfers = self.transfers.lock().unwrap();
+        if let Entry::Occupied(mut entry) = transfers.entry(self.namespace) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Why an upload or a download stopped before the empty message that ends it.
+#[derive(Debug, Error)]
+pub enum TransferFailure {
+    #[error("went over its {} limit", .0.label())]
+    Limit(SessionLimit),
+    /// The mailboxes took up as many bytes as they may.
+    #[error("the mailboxes are full")]
+    Full,
+    /// The receiver did not prove it knows the passphrase of the mailbox.
+    #[error("the receiver failed to confirm the key")]
+    KeyNotConfirmed,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl TransferFailure {
+    /// Tells a read or write that gave up because of the socket's timeout, which is reported
+    /// differently depending on the platform, from the other I/O errors, as the client having
+    /// let the transfer sit idle for too long.
+    fn from_io(e: std::io::Error) -> Self {
+        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+            TransferFailure::Limit(SessionLimit::Idle)
+        } else {
+            TransferFailure::Io(e)
+        }
+    }
+}
+
+/// Stores the size prefixed messages the sender writes into `file`, without looking into them,
+/// up to and including the empty message that ends the upload. Returns the number of bytes
+/// stored.
+///
+/// Nothing beyond the largest size of a mailbox is stored, which counts as going over the size
+/// limit of the session.
+pub fn receive_upload(
+    reader: &mut BufReader<Socket>,
+    file: &mut File,
+    (mailboxes, room_identifier): (&Mailboxes, RoomIdentifier),
+    throttle: &RoomThrottle,
+    quota: &SessionQuota,
+) -> Result<u64, TransferFailure> {
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut stored_bytes = 0;
+    loop {
+        let mut size_bytes = [0; 4];
+        reader
+            .read_exact(&mut size_bytes)
+            .map_err(TransferFailure::from_io)?;
+        let size = u64::from(u32::from_be_bytes(size_bytes));
+        stored_bytes += size_bytes.len() as u64 + size;
+        if stored_bytes > mailboxes.max_size() {
+            return Err(TransferFailure::Limit(SessionLimit::Size));
+        }
+        if !mailboxes.reserve(room_identifier, size_bytes.len() as u64 + size) {
+            return Err(TransferFailure::Full);
+        }
+        file.write_all(&size_bytes)?;
+
+        let mut remaining = size;
+        while remaining > 0 {
+            let chunk_size = throttle
+                .max_chunk_size()
+                .min(buffer.len())
+                .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let bytes_read = reader
+                .read(&mut buffer[..chunk_size])
+                .map_err(TransferFailure::from_io)?;
+            if bytes_read == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            throttle.acquire(bytes_read);
+            file.write_all(&buffer[..bytes_read])?;
+            remaining -= u64::from_usize(bytes_read);
+            quota.record_forwarded(u64::from_usize(bytes_read));
+            if let Some(limit) = quota.check() {
+                return Err(TransferFailure::Limit(limit));
+            }
+        }
+
+        if size == 0 {
+            file.sync_all()?;
+            return Ok(stored_bytes);
+        }
+    }
+}
+
+/// Runs the PAKE with the receiver of a mailbox, against the password in the key confirmation
+/// blob the sender left in it, and hands out the key to the files only once the receiver sent
+/// back proof that both ended up with the same key, which takes the passphrase. Leaves `file`
+/// at the start of the files.
+pub fn confirm_key(
+    file: &mut File,
+    reader: &mut BufReader<Socket>,
+    writer: &Socket,
+    identity: &str,
+) -> Result<(), TransferFailure> {
+    // The cipher type goes out as it was uploaded
+    write_message(writer, &read_message(file, MAX_HEADER_MESSAGE_SIZE)?)?;
+    let key_confirmation = read_message(file, MAX_HEADER_MESSAGE_SIZE)?;
+    let Some((salt, rest)) = key_confirmation.split_first_chunk::<SALT_SIZE>() else {
+        return Err(std::io::Error::from(ErrorKind::InvalidData).into());
+    };
+    let Some((password, wrapped_key)) = rest.split_first_chunk::<PAKE_PASSWORD_SIZE>() else {
+        return Err(std::io::Error::from(ErrorKind::InvalidData).into());
+    };
+    write_message(writer, salt)?;
+
+    let (spake, outbound_message) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(password),
+        &Identity::new(identity.as_bytes()),
+    );
+    let receiver_message = read_message(reader, MAX_HEADER_MESSAGE_SIZE)?;
+    write_message(writer, &outbound_message)?;
+    let key = spake
+        .finish(&receiver_message)
+        .map_err(|_| TransferFailure::KeyNotConfirmed)?;
+    let confirmation = read_message(reader, MAX_HEADER_MESSAGE_SIZE)?;
+    key_confirmation_mac(&key)
+        .verify_slice(&confirmation)
+        .map_err(|_| TransferFailure::KeyNotConfirmed)?;
+
+    let message =
+        serde_json::to_vec(&IrisMessage::MailboxKeyConfirmed).map_err(std::io::Error::other)?;
+    write_message(writer, &message)?;
+    write_message(writer, wrapped_key)?;
+    Ok(())
+}
+
+/// Reads a size prefixed message, refusing one larger than `max_size`.
+fn read_message(reader: &mut impl Read, max_size: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut size_bytes = [0; 4];
+    reader.read_exact(&mut size_bytes)?;
+    let size = u32::from_be_bytes(size_bytes);
+    if size > max_size {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let mut message = vec![0; size.into_usize()];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn write_message(mut writer: &Socket, message: &[u8]) -> Result<(), std::io::Error> {
+    let size = u32::try_from(message.len()).map_err(|_| ErrorKind::InvalidInput)?;
+    writer.write_all(&[&size.to_be_bytes(), message].concat())
+}
+
+/// Sends the files of a stored mailbox to a receiver as they are, ending with the empty message
+/// that ended its upload. Returns the number of bytes sent.
+pub fn send_download(
+    file: &mut File,
+    mut writer: &Socket,
+    throttle: &RoomThrottle,
+    quota: &SessionQuota,
+) -> Result<u64, TransferFailure> {
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut sent_bytes = 0;
+    loop {
+        let chunk_size = throttle.max_chunk_size().min(buffer.len());
+        let bytes_read = file.read(&mut buffer[..chunk_size])?;
+        if bytes_read == 0 {
+            return Ok(sent_bytes);
+        }
+        throttle.acquire(bytes_read);
+        if let Err(e) = writer.write_all(&buffer[..bytes_read]) {
+            let failure = TransferFailure::from_io(e);
+            if matches!(failure, TransferFailure::Limit(SessionLimit::Idle)) {
+                quota.record_stalled();
+            }
+            return Err(failure);
+        }
+        sent_bytes += u64::from_usize(bytes_read);
+        quota.record_forwarded(u64::from_usize(bytes_read));
+        if let Some(limit) = quota.check() {
+            return Err(TransferFailure::Limit(limit));
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::address_filter::Rejection;
+use super::pipe::Direction;
+use super::quota::SessionLimit;
+
+/// Upper bounds, in seconds, of the buckets of the session duration histogram.
+const SESSION_DURATION_BUCKETS: [f64; 9] = [
+    1.0,
+    5.0,
+    15.0,
+    60.0,
+    300.0,
+    900.0,
+    3600.0,
+    4.0 * 3600.0,
+    24.0 * 3600.0,
+];
+
+/// Why a connection did not make it past the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The client did not send a complete, well formed message before the deadline.
+    ReadFailed,
+    UnexpectedMessage,
+    AccessDenied,
+    BadRoomIdentifier,
+    RelayFull,
+    RelayDraining,
+    RateLimited,
+    UntrustedProxy,
+    InvalidProxyHeader,
+    QuotaExceeded,
+}
+
+impl HandshakeFailure {
+    const ALL: [HandshakeFailure; 10] = [
+        HandshakeFailure::ReadFailed,
+        HandshakeFailure::UnexpectedMessage,
+        HandshakeFailure::AccessDenied,
+        HandshakeFailure::BadRoomIdentifier,
+        HandshakeFailure::RelayFull,
+        HandshakeFailure::RelayDraining,
+        HandshakeFailure::RateLimited,
+        HandshakeFailure::UntrustedProxy,
+        HandshakeFailure::InvalidProxyHeader,
+        HandshakeFailure::QuotaExceeded,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeFailure::ReadFailed => "read_failed",
+            HandshakeFailure::UnexpectedMessage => "unexpected_message",
+            HandshakeFailure::AccessDenied => "access_denied",
+            HandshakeFailure::BadRoomIdentifier => "bad_room_identifier",
+            HandshakeFailure::RelayFull => "relay_full",
+            HandshakeFailure::RelayDraining => "relay_draining",
+            HandshakeFailure::RateLimited => "rate_limited",
+            HandshakeFailure::UntrustedProxy => "untrusted_proxy",
+            HandshakeFailure::InvalidProxyHeader => "invalid_proxy_header",
+            HandshakeFailure::QuotaExceeded => "quota_exceeded",
+        }
+    }
+}
+
+/// Values sampled from the relay's state when rendering the metrics.
+pub struct Snapshot {
+    pub waiting_rooms: usize,
+    pub active_sessions: usize,
+    pub banned_sources: usize,
+    pub room_bandwidth_limit: Option<u64>,
+    pub global_bandwidth_limit: Option<u64>,
+    pub throttled_time: Duration,
+}
+
+/// Counters kept by the relay, rendered in the Prometheus text format by [`Metrics::render`].
+#[derive(Default)]
+pub struct Metrics {
+    forwarded_bytes: [AtomicU64; 2],
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    bans: AtomicU64,
+    rejected_connections: [AtomicU64; Rejection::ALL.len()],
+    limited_sessions: [AtomicU64; SessionLimit::ALL.len()],
+    session_duration_buckets: [AtomicU64; SESSION_DURATION_BUCKETS.len()],
+    session_duration_count: AtomicU64,
+    session_duration_sum_millis: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_forwarded_bytes(&self, direction: Direction, bytes: u64) {
+        self.forwarded_bytes[direction as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_handshake_failure(&self, failure: HandshakeFailure) {
+        self.handshake_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ban(&self) {
+        self.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_connection(&self, rejection: Rejection) {
+        self.rejected_connections[rejection as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_session_limited(&self, limit: SessionLimit) {
+        self.limited_sessions[limit as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_session_duration(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, upper_bound) in self
+            .session_duration_buckets
+            .iter()
+            .zip(SESSION_DURATION_BUCKETS)
+        {
+            if seconds <= upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.session_duration_count.fetch_add(1, Ordering::Relaxed);
+        self.session_duration_sum_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders every metric, along with the ones that are sampled from the relay's state.
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut output = String::new();
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_waiting_rooms",
+            "gauge",
+            "Rooms whose sender is waiting for a receiver.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_waiting_rooms {}",
+            snapshot.waiting_rooms
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_active_sessions",
+            "gauge",
+            "Paired transfers currently being relayed.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_active_sessions {}",
+            snapshot.active_sessions
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_forwarded_bytes_total",
+            "counter",
+            "Bytes forwarded between paired clients.",
+        );
+        for direction in [Direction::SenderToReceiver, Direction::ReceiverToSender] {
+            let _ = writeln!(
+                output,
+                "iris_relay_forwarded_bytes_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                self.forwarded_bytes[direction as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_handshake_failures_total",
+            "counter",
+            "Connections turned away before being paired, by reason.",
+        );
+        for failure in HandshakeFailure::ALL {
+            let _ = writeln!(
+                output,
+                "iris_relay_handshake_failures_total{{reason=\"{}\"}} {}",
+                failure.label(),
+                self.handshake_failures[failure as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_banned_sources",
+            "gauge",
+            "Source addresses currently banned for failing to join rooms too often.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_banned_sources {}",
+            snapshot.banned_sources
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_bans_total",
+            "counter",
+            "Times a source address was banned for failing to join rooms too often.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_bans_total {}",
+            self.bans.load(Ordering::Relaxed)
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_rejected_connections_total",
+            "counter",
+            "Connections turned away by the allow and deny lists, by reason.",
+        );
+        for rejection in Rejection::ALL {
+            let _ = writeln!(
+                output,
+                "iris_relay_rejected_connections_total{{reason=\"{}\"}} {}",
+                rejection.label(),
+                self.rejected_connections[rejection as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_room_bandwidth_limit_bytes",
+            "gauge",
+            "Bytes per second a single room may forward, 0 when unlimited.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_room_bandwidth_limit_bytes {}",
+            snapshot.room_bandwidth_limit.unwrap_or(0)
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_global_bandwidth_limit_bytes",
+            "gauge",
+            "Bytes per second shared by every room, 0 when unlimited.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_global_bandwidth_limit_bytes {}",
+            snapshot.global_bandwidth_limit.unwrap_or(0)
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_throttled_seconds_total",
+            "counter",
+            "Time rooms spent waiting on the bandwidth limits.",
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_throttled_seconds_total {}",
+            snapshot.throttled_time.as_secs_f64()
+        );
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_limited_sessions_total",
+            "counter",
+            "Paired transfers ended for going over a limit, by limit.",
+        );
+        for limit in SessionLimit::ALL {
+            let _ = writeln!(
+                output,
+                "iris_relay_limited_sessions_total{{limit=\"{}\"}} {}",
+                limit.label(),
+                self.limited_sessions[limit as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        write_metric_header(
+            &mut output,
+            "iris_relay_session_duration_seconds",
+            "histogram",
+            "How long paired transfers were relayed for.",
+        );
+        for (bucket, upper_bound) in self
+            .session_duration_buckets
+            .iter()
+            .zip(SESSION_DURATION_BUCKETS)
+        {
+            let _ = writeln!(
+                output,
+                "iris_relay_session_duration_seconds_bucket{{le=\"{upper_bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let session_duration_count = self.session_duration_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            output,
+            "iris_relay_session_duration_seconds_bucket{{le=\"+Inf\"}} {session_duration_count}"
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_session_duration_seconds_sum {}",
+            self.session_duration_sum_millis.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        let _ = writeln!(
+            output,
+            "iris_relay_session_duration_seconds_count {session_duration_count}"
+        );
+
+        output
+    }
+}
+
+fn write_metric_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {metric_type}");
+}
@@ -0,0 +1,165 @@
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::thread;
+use std::time::Duration;
+
+use usize_cast::FromUsize;
+
+use crate::iris_tcp_stream::IrisTcpStream;
+use crate::socket::Socket;
+
+use super::quota::{SessionLimit, SessionQuota};
+use super::throttle::RoomThrottle;
+
+/// Size of the buffer used for each direction of a relayed session. It is allocated once per
+/// direction and reused for the whole session.
+const PIPE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// How often a direction that has nothing to forward checks whether the session went over one
+/// of its limits.
+const LIMIT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SenderToReceiver,
+    ReceiverToSender,
+}
+
+impl Direction {
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::SenderToReceiver => "sender_to_receiver",
+            Direction::ReceiverToSender => "receiver_to_sender",
+        }
+    }
+}
+
+/// Number of bytes forwarded in each direction of a session.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipeStatistics {
+    pub sender_to_receiver: u64,
+    pub receiver_to_sender: u64,
+}
+
+/// Joins the two streams into an opaque full-duplex pipe.
+///
+/// The relay does not look at what is being sent, so clients are free to change their protocol
+/// without having to upgrade the relay. Each direction is pumped on its own thread until its
+/// reading side reaches EOF, at which point the EOF is passed on to the other peer. If either
+/// direction fails, both connections are torn down so that neither thread stays blocked.
+///
+/// Both directions are paced by `throttle`, and `on_forwarded` is called every time a chunk has
+/// been passed on, from both threads. Once the session goes over one of the limits of `quota`,
+/// `on_limit` is called with that limit, possibly from both threads, right before both
+/// connections are torn down.
+pub fn join(
+    sender: IrisTcpStream,
+    receiver: IrisTcpStream,
+    throttle: &RoomThrottle,
+    quota: &SessionQuota,
+    on_forwarded: &(dyn Fn(Direction, u64) + Sync),
+    on_limit: &(dyn Fn(SessionLimit) + Sync),
+) -> Result<PipeStatistics, std::io::Error> {
+    let (mut sender_reader, sender_writer) = sender.into_split();
+    let (mut receiver_reader, receiver_writer) = receiver.into_split();
+    // Wake up every now and then to check the limits, even while the clients are quiet
+    sender_writer.set_read_timeout(Some(LIMIT_CHECK_INTERVAL))?;
+    receiver_writer.set_read_timeout(Some(LIMIT_CHECK_INTERVAL))?;
+    let tear_down = || {
+        if let Some(limit) = quota.exceeded() {
+            on_limit(limit);
+        }
+        shutdown_both(&sender_writer, &receiver_writer);
+    };
+
+    thread::scope(|s| {
+        let upstream = s.spawn(|| {
+            let result = pump(
+                &mut sender_reader,
+                &receiver_writer,
+                Direction::SenderToReceiver,
+                throttle,
+                quota,
+                on_forwarded,
+            );
+            if result.is_err() {
+                tear_down();
+            }
+            result
+        });
+
+        let downstream = pump(
+            &mut receiver_reader,
+            &sender_writer,
+            Direction::ReceiverToSender,
+            throttle,
+            quota,
+            on_forwarded,
+        );
+        if downstream.is_err() {
+            tear_down();
+        }
+
+        let upstream = upstream.join().expect("upstream pump panicked");
+        Ok(PipeStatistics {
+            sender_to_receiver: upstream?,
+            receiver_to_sender: downstream?,
+        })
+    })
+}
+
+/// Copies everything from `reader` into `writer` until EOF, then half-closes `writer`. Fails as
+/// soon as the session goes over one of its limits.
+fn pump(
+    reader: &mut BufReader<Socket>,
+    mut writer: &Socket,
+    direction: Direction,
+    throttle: &RoomThrottle,
+    quota: &SessionQuota,
+    on_forwarded: &(dyn Fn(Direction, u64) + Sync),
+) -> Result<u64, std::io::Error> {
+    let mut buffer = vec![0; PIPE_BUFFER_SIZE];
+    let mut total_bytes = 0;
+
+    loop {
+        if let Some(limit) = quota.check() {
+            return Err(std::io::Error::other(format!(
+                "the session went over its {} limit",
+                limit.label()
+            )));
+        }
+
+        let chunk_size = throttle.max_chunk_size().min(buffer.len());
+        let bytes_read = match reader.read(&mut buffer[..chunk_size]) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) if is_timeout(&e) || e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        throttle.acquire(bytes_read);
+        if let Err(e) = writer.write_all(&buffer[..bytes_read]) {
+            if is_timeout(&e) {
+                quota.record_stalled();
+            }
+            return Err(e);
+        }
+        total_bytes += u64::from_usize(bytes_read);
+        on_forwarded(direction, u64::from_usize(bytes_read));
+        quota.record_forwarded(u64::from_usize(bytes_read));
+    }
+
+    // The peer may already be gone, in which case there is nobody left to notify.
+    let _ = writer.shutdown(Shutdown::Write);
+    Ok(total_bytes)
+}
+
+/// Whether a read gave up because of the socket's read timeout, which is reported differently
+/// depending on the platform.
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn shutdown_both(sender: &Socket, receiver: &Socket) {
+    let _ = sender.shutdown(Shutdown::Both);
+    let _ = receiver.shutdown(Shutdown::Both);
+}
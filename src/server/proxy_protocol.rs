@@ -0,0 +1,158 @@
+//! Parsing of the PROXY protocol header that load balancers such as HAProxy prepend to the
+//! connections they forward, see <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>,
+//! and writing of the header nodes of a cluster prepend to the connections they forward to
+//! each other.
+
+use std::io::{BufRead, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::listener::PeerAddr;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest a version 1 header can be, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// What a PROXY protocol header says about the connection it came on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// The upstream forwarded a connection from a client at this address.
+    Proxied(SocketAddr),
+    /// The upstream opened the connection itself, e.g. for a health check, or does not know
+    /// where the client is.
+    Local,
+}
+
+/// Reads the PROXY protocol header at the start of the stream, if there is one.
+///
+/// Iris messages start with their size as a big-endian `u32`, whose first byte is 0 for any
+/// message small enough to be part of the handshake, while both versions of the header start
+/// with a printable character. That makes it possible to tell them apart from the first byte and
+/// to accept connections with and without the header alike.
+pub fn read_header(reader: &mut impl BufRead) -> Result<Option<ProxyHeader>, std::io::Error> {
+    let first_byte = match reader.fill_buf()?.first() {
+        Some(first_byte) => *first_byte,
+        None => return Ok(None),
+    };
+
+    if first_byte == V1_PREFIX[0] {
+        read_v1_header(reader).map(Some)
+    } else if first_byte == V2_SIGNATURE[0] {
+        read_v2_header(reader).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// The address of `source` as a header sent to `destination` carries it, which has to be of the
+/// same family. IPv4 clients are described to IPv6 destinations by their IPv4-mapped address.
+/// Returns `None` for clients on a Unix domain socket, and for IPv6 clients forwarded to an IPv4
+/// destination, as the header cannot say where they are.
+pub fn source_for(source: PeerAddr, destination: IpAddr) -> Option<SocketAddr> {
+    let PeerAddr::Tcp(source) = source else {
+        return None;
+    };
+    let source_ip = match (source.ip(), destination) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => IpAddr::V6(ip.to_ipv6_mapped()),
+        (IpAddr::V6(ip), IpAddr::V4(_)) => IpAddr::V4(ip.to_ipv4_mapped()?),
+        (ip, _) => ip,
+    };
+    Some(SocketAddr::new(source_ip, source.port()))
+}
+
+/// The version 1 header for a connection from `source` forwarded to `destination`, or `None` if
+/// it cannot say where the client is, see [`source_for`].
+pub fn v1_header(source: PeerAddr, destination: SocketAddr) -> Option<String> {
+    let source = source_for(source, destination.ip())?;
+    Some(format!(
+        "PROXY {} {} {} {} {}\r\n",
+        if destination.is_ipv4() {
+            "TCP4"
+        } else {
+            "TCP6"
+        },
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    ))
+}
+
+fn read_v1_header(reader: &mut impl BufRead) -> Result<ProxyHeader, std::io::Error> {
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    reader
+        .take(V1_MAX_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| line.strip_prefix(V1_PREFIX))
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(invalid_header)?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(ProxyHeader::Local),
+        [protocol @ ("TCP4" | "TCP6"), source_ip, _, source_port, _] => {
+            let source_ip: IpAddr = source_ip.parse().map_err(|_| invalid_header())?;
+            if source_ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid_header());
+            }
+            let source_port: u16 = source_port.parse().map_err(|_| invalid_header())?;
+            Ok(ProxyHeader::Proxied(SocketAddr::new(
+                source_ip,
+                source_port,
+            )))
+        }
+        _ => Err(invalid_header()),
+    }
+}
+
+fn read_v2_header(reader: &mut impl BufRead) -> Result<ProxyHeader, std::io::Error> {
+    let mut fixed_part = [0; 16];
+    reader.read_exact(&mut fixed_part)?;
+    if fixed_part[..12] != V2_SIGNATURE || fixed_part[12] >> 4 != 2 {
+        return Err(invalid_header());
+    }
+    let command = fixed_part[12] & 0x0F;
+    let family = fixed_part[13];
+    let length = u16::from_be_bytes([fixed_part[14], fixed_part[15]]);
+
+    // Read the whole variable part, including any TLVs we do not care about, so that the next
+    // read starts at the first message
+    let mut addresses = vec![0; usize::from(length)];
+    reader.read_exact(&mut addresses)?;
+
+    match (command, family) {
+        (V2_COMMAND_LOCAL, _) => Ok(ProxyHeader::Local),
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP4) if addresses.len() >= 12 => {
+            let source_ip: [u8; 4] = addresses[0..4].try_into().unwrap();
+            let source_port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(ProxyHeader::Proxied(SocketAddr::new(
+                Ipv4Addr::from(source_ip).into(),
+                source_port,
+            )))
+        }
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP6) if addresses.len() >= 36 => {
+            let source_ip: [u8; 16] = addresses[0..16].try_into().unwrap();
+            let source_port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(ProxyHeader::Proxied(SocketAddr::new(
+                Ipv6Addr::from(source_ip).into(),
+                source_port,
+            )))
+        }
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP4 | V2_FAMILY_TCP6) => Err(invalid_header()),
+        // Other families, such as UDP or Unix domain sockets, do not describe a client
+        // reachable over TCP
+        (V2_COMMAND_PROXY, _) => Ok(ProxyHeader::Local),
+        _ => Err(invalid_header()),
+    }
+}
+
+fn invalid_header() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "invalid PROXY protocol header")
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::IrisMessage;
+
+use super::audit::Termination;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A limit that made the relay end a session early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionLimit {
+    /// The session forwarded more bytes than a single session may.
+    Size,
+    /// The session lasted longer than a single session may.
+    Duration,
+    /// The session went without forwarding anything for too long, or one of its clients
+    /// stopped reading what was forwarded to it.
+    Idle,
+    /// One of the clients used up its quota for the day.
+    DailyQuota,
+}
+
+impl SessionLimit {
+    pub const ALL: [SessionLimit; 4] = [
+        SessionLimit::Size,
+        SessionLimit::Duration,
+        SessionLimit::Idle,
+        SessionLimit::DailyQuota,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SessionLimit::Size => "size",
+            SessionLimit::Duration => "duration",
+            SessionLimit::Idle => "idle",
+            SessionLimit::DailyQuota => "daily_quota",
+        }
+    }
+
+    pub fn termination(self) -> Termination {
+        match self {
+            SessionLimit::Size => Termination::SessionSizeExceeded,
+            SessionLimit::Duration => Termination::SessionDurationExceeded,
+            SessionLimit::Idle => Termination::SessionIdleTimeout,
+            SessionLimit::DailyQuota => Termination::DailyQuotaExceeded,
+        }
+    }
+
+    /// The message telling the clients why their session was ended.
+    pub fn message(self) -> IrisMessage {
+        match self {
+            SessionLimit::Size => IrisMessage::SessionSizeExceeded,
+            SessionLimit::Duration => IrisMessage::SessionDurationExceeded,
+            SessionLimit::Idle => IrisMessage::SessionIdleTimeout,
+            SessionLimit::DailyQuota => IrisMessage::DailyQuotaExceeded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaConfig {
+    pub max_session_bytes: Option<u64>,
+    pub max_session_duration: Option<Duration>,
+    pub session_idle_timeout: Duration,
+    pub daily_quota_bytes: Option<u64>,
+}
+
+struct DailyUsage {
+    day: u64,
+    bytes: u64,
+}
+
+/// Keeps track of how much every source address had relayed today, along with the limits on
+/// single sessions.
+///
+/// Every byte of a session counts towards the quota of both of its clients, no matter which
+/// direction it went. Days start at midnight UTC. Unix domain socket clients have no source
+/// address and are never held to a daily quota.
+pub struct Quotas {
+    config: QuotaConfig,
+    usage: Mutex<HashMap<IpAddr, DailyUsage>>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `ip` already used up its quota for today.
+    pub fn is_exhausted(&self, ip: Option<IpAddr>) -> bool {
+        let (Some(ip), Some(daily_quota_bytes)) = (ip, self.config.daily_quota_bytes) else {
+            return false;
+        };
+        let today = today();
+        self.usage
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|usage| usage.day == today && usage.bytes >= daily_quota_bytes)
+    }
+
+    /// Starts keeping track of a newly paired session between clients at `sources`.
+    pub fn session(&self, sources: [Option<IpAddr>; 2]) -> SessionQuota<'_> {
+        let mut ips: Vec<IpAddr> = sources.into_iter().flatten().collect();
+        ips.dedup();
+        let session_quota = SessionQuota {
+            quotas: self,
+            ips,
+            started_at: Instant::now(),
+            last_forwarded_at: Mutex::new(Instant::now()),
+            forwarded_bytes: AtomicU64::new(0),
+            exceeded: Mutex::new(None),
+        };
+        // Whatever was relayed while the sender was waiting may have used up the quota already
+        if session_quota
+            .ips
+            .iter()
+            .any(|ip| self.is_exhausted(Some(*ip)))
+        {
+            session_quota.exceed(SessionLimit::DailyQuota);
+        }
+        session_quota
+    }
+
+    /// Forgets the usage of previous days.
+    pub fn prune(&self) {
+        let today = today();
+        self.usage
+            .lock()
+            .unwrap()
+            .retain(|_, usage| usage.day == today);
+    }
+
+    /// Adds `bytes` to the usage of `ip` and returns whether that used up its quota.
+    fn record(&self, ip: IpAddr, bytes: u64) -> bool {
+        let Some(daily_quota_bytes) = self.config.daily_quota_bytes else {
+            return false;
+        };
+        let today = today();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(ip).or_insert(DailyUsage {
+            day: today,
+            bytes: 0,
+        });
+        if usage.day != today {
+            usage.day = today;
+            usage.bytes = 0;
+        }
+        usage.bytes = usage.bytes.saturating_add(bytes);
+        usage.bytes >= daily_quota_bytes
+    }
+}
+
+/// Checks a single session against the limits as it is being relayed.
+pub struct SessionQuota<'a> {
+    quotas: &'a Quotas,
+    ips: Vec<IpAddr>,
+    started_at: Instant,
+    last_forwarded_at: Mutex<Instant>,
+    forwarded_bytes: AtomicU64,
+    exceeded: Mutex<Option<SessionLimit>>,
+}
+
+impl SessionQuota<'_> {
+    /// Accounts for `bytes` more having been forwarded, in either direction.
+    pub fn record_forwarded(&self, bytes: u64) {
+        *self.last_forwarded_at.lock().unwrap() = Instant::now();
+        let forwarded_bytes = self.forwarded_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if self
+            .quotas
+            .config
+            .max_session_bytes
+            .is_some_and(|max_session_bytes| forwarded_bytes > max_session_bytes)
+        {
+            self.exceed(SessionLimit::Size);
+        }
+
+        let mut is_exhausted = false;
+        for ip in &self.ips {
+            is_exhausted |= self.quotas.record(*ip, bytes);
+        }
+        if is_exhausted {
+            self.exceed(SessionLimit::DailyQuota);
+        }
+    }
+
+    /// Accounts for a client having not read what was forwarded to it for as long as the
+    /// session may sit idle.
+    pub fn record_stalled(&self) {
+        self.exceed(SessionLimit::Idle);
+    }
+
+    /// Checks the limits that are not about what was forwarded and returns the first limit the
+    /// session went over, or `None` while the session is within its limits.
+    pub fn check(&self) -> Option<SessionLimit> {
+        if self
+            .quotas
+            .config
+            .max_session_duration
+            .is_some_and(|max_session_duration| self.started_at.elapsed() >= max_session_duration)
+        {
+            self.exceed(SessionLimit::Duration);
+        }
+        if self.last_forwarded_at.lock().unwrap().elapsed()
+            >= self.quotas.config.session_idle_timeout
+        {
+            self.exceed(SessionLimit::Idle);
+        }
+        *self.exceeded.lock().unwrap()
+    }
+
+    /// The limit the session went over, as of the last check.
+    pub fn exceeded(&self) -> Option<SessionLimit> {
+        *self.exceeded.lock().unwrap()
+    }
+
+    fn exceed(&self, limit: SessionLimit) {
+        self.exceeded.lock().unwrap().get_or_insert(limit);
+    }
+}
+
+/// The number of days since the Unix epoch, in UTC.
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECS_PER_DAY
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What happened to a source that failed to join a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailedJoinOutcome {
+    /// The source has to wait this long before trying again.
+    Backoff(Duration),
+    /// The source failed too many times and is banned for this long.
+    Banned(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub max_failed_joins: u32,
+    pub failed_join_backoff: Duration,
+    pub ban_duration: Duration,
+    pub max_room_creations_per_minute: u32,
+    pub trust_local_clients: bool,
+}
+
+/// Who is being held to account, see [`RateLimiter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SourceKey {
+    Network(IpAddr),
+    /// Every client on a Unix domain socket, as they cannot be told apart.
+    Local,
+}
+
+struct Source {
+    failed_joins: u32,
+    last_failed_join: Instant,
+    /// When a failed join was last forgiven, or when the source started failing again.
+    failures_decayed_at: Instant,
+    banned_until: Option<Instant>,
+    room_creation_tokens: f64,
+    last_room_creation: Instant,
+}
+
+impl Source {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            failed_joins: 0,
+            last_failed_join: now,
+            failures_decayed_at: now,
+            banned_until: None,
+            room_creation_tokens: f64::from(config.max_room_creations_per_minute),
+            last_room_creation: now,
+        }
+    }
+
+    fn ban_remaining(&self, now: Instant) -> Option<Duration> {
+        self.banned_until
+            .filter(|banned_until| *banned_until > now)
+            .map(|banned_until| banned_until - now)
+    }
+
+    /// Forgives one failure for every `decay_interval` that went by since the last one was.
+    fn decay_failures(&mut self, decay_interval: Duration, now: Instant) {
+        if self.failed_joins == 0 {
+            self.failures_decayed_at = now;
+            return;
+        }
+        if decay_interval.is_zero() {
+            self.failed_joins = 0;
+            return;
+        }
+        let intervals =
+            now.duration_since(self.failures_decayed_at).as_nanos() / decay_interval.as_nanos();
+        let forgiven = u32::try_from(intervals)
+            .unwrap_or(u32::MAX)
+            .min(self.failed_joins);
+        self.failed_joins -= forgiven;
+        self.failures_decayed_at += decay_interval * forgiven;
+    }
+
+    fn refill_room_creation_tokens(&mut self, config: &RateLimitConfig, now: Instant) {
+        let capacity = f64::from(config.max_room_creations_per_minute);
+        let elapsed_minutes = now.duration_since(self.last_room_creation).as_secs_f64() / 60.0;
+        self.room_creation_tokens =
+            (self.room_creation_tokens + elapsed_minutes * capacity).min(capacity);
+        self.last_room_creation = now;
+    }
+
+    /// Whether the source is indistinguishable from one the relay has never seen.
+    fn is_idle(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+        self.decay_failures(failure_decay_interval(config), now);
+        self.refill_room_creation_tokens(config, now);
+        self.failed_joins == 0
+            && self.ban_remaining(now).is_none()
+            && self.room_creation_tokens >= f64::from(config.max_room_creations_per_minute)
+    }
+}
+
+/// Keeps track of what every source has been up to, so that nobody can enumerate the waiting
+/// rooms by guessing room identifiers or fill up the relay by creating rooms.
+///
+/// Every failed join makes the source wait before it may try to join again, twice as long as
+/// after the previous failure, and enough failures get the source banned altogether. Failures
+/// wear off one at a time, so that a source may fail `max_failed_joins` times per ban duration
+/// without being banned. Joining a room does not make up for them, as a client could join a
+/// room of its own between guesses. Room creations are limited with a token bucket.
+///
+/// A source is a single IPv4 address, or a whole /64 for IPv6 as that is what a single
+/// subscriber usually gets. Clients without an IP address, i.e. those connected over a Unix
+/// domain socket, share a single source unless local clients are trusted, in which case they
+/// are never limited.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sources: Mutex<HashMap<SourceKey, Source>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns how long the source is still banned for, if it is.
+    pub fn check_banned(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let Some(key) = self.source_key(ip) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        match self.sources.lock().unwrap().get(&key) {
+            Some(source) => source.ban_remaining(now).map_or(Ok(()), Err),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns how long the source has to wait before it may try to join a room again, if it
+    /// is still backing off from its previous failures.
+    pub fn check_join(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let Some(key) = self
+            .source_key(ip)
+            .filter(|_| self.config.max_failed_joins > 0)
+        else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        let Some(source) = sources.get_mut(&key) else {
+            return Ok(());
+        };
+        source.decay_failures(failure_decay_interval(&self.config), now);
+        if source.failed_joins == 0 {
+            return Ok(());
+        }
+
+        let retry_at = source.last_failed_join + self.backoff(source.failed_joins);
+        if retry_at > now {
+            Err(retry_at - now)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn record_failed_join(&self, ip: Option<IpAddr>) -> FailedJoinOutcome {
+        let Some(key) = self
+            .source_key(ip)
+            .filter(|_| self.config.max_failed_joins > 0)
+        else {
+            return FailedJoinOutcome::Backoff(Duration::ZERO);
+        };
+
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        let source = sources
+            .entry(key)
+            .or_insert_with(|| Source::new(&self.config, now));
+        source.decay_failures(failure_decay_interval(&self.config), now);
+        source.failed_joins += 1;
+        source.last_failed_join = now;
+
+        if source.failed_joins >= self.config.max_failed_joins {
+            source.failed_joins = 0;
+            source.banned_until = Some(now + self.config.ban_duration);
+            FailedJoinOutcome::Banned(self.config.ban_duration)
+        } else {
+            FailedJoinOutcome::Backoff(self.backoff(source.failed_joins))
+        }
+    }
+
+    /// Takes a room creation from the source's budget, or returns how long it has to wait for
+    /// the next one if it is used up.
+    pub fn check_room_creation(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let Some(key) = self
+            .source_key(ip)
+            .filter(|_| self.config.max_room_creations_per_minute > 0)
+        else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        let source = sources
+            .entry(key)
+            .or_insert_with(|| Source::new(&self.config, now));
+        source.refill_room_creation_tokens(&self.config, now);
+        if source.room_creation_tokens >= 1.0 {
+            source.room_creation_tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing_tokens = 1.0 - source.room_creation_tokens;
+            Err(Duration::from_secs_f64(
+                missing_tokens * 60.0 / f64::from(self.config.max_room_creations_per_minute),
+            ))
+        }
+    }
+
+    pub fn banned_sources(&self) -> usize {
+        let now = Instant::now();
+        self.sources
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|source| source.ban_remaining(now).is_some())
+            .count()
+    }
+
+    /// Forgets the sources that have nothing held against them anymore, so that the relay does
+    /// not remember every address it has ever seen.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.sources
+            .lock()
+            .unwrap()
+            .retain(|_, source| !source.is_idle(&self.config, now));
+    }
+
+    fn source_key(&self, ip: Option<IpAddr>) -> Option<SourceKey> {
+        match ip {
+            Some(ip) => Some(SourceKey::Network(network_of(ip))),
+            None if self.config.trust_local_clients => None,
+            None => Some(SourceKey::Local),
+        }
+    }
+
+    fn backoff(&self, failed_joins: u32) -> Duration {
+        // Cap the exponent, the ban duration caps the backoff long before this anyway
+        let factor = 1u32 << failed_joins.saturating_sub(1).min(20);
+        self.config
+            .failed_join_backoff
+            .saturating_mul(factor)
+            .min(self.config.ban_duration)
+    }
+}
+
+/// How long it takes for a single failed join to be forgiven.
+fn failure_decay_interval(config: &RateLimitConfig) -> Duration {
+    config.ban_duration / config.max_failed_joins.max(1)
+}
+
+/// The address standing in for every address of the same source, see [`RateLimiter`].
+fn network_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
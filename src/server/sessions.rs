@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::namespace::Namespace;
+use crate::room_mapping::RoomIdentifier;
+use crate::socket::Socket;
+
+use super::audit::Termination;
+use super::listener::PeerAddr;
+use super::pipe::Direction;
+
+pub type SessionIdentifier = u64;
+
+struct Session {
+    room_identifier: RoomIdentifier,
+    namespace: Option<Namespace>,
+    sender_addr: PeerAddr,
+    receiver_addr: PeerAddr,
+    started_at: Instant,
+    sender_socket: Socket,
+    receiver_socket: Socket,
+    forwarded_bytes: Arc<[AtomicU64; 2]>,
+    termination: Option<Termination>,
+}
+
+impl Session {
+    fn info(&self, session_identifier: SessionIdentifier) -> SessionInfo {
+        SessionInfo {
+            session_identifier,
+            room_identifier: self.room_identifier,
+            namespace: self.namespace,
+            sender_address: self.sender_addr,
+            receiver_address: self.receiver_addr,
+            age_secs: self.started_at.elapsed().as_secs(),
+            sender_to_receiver_bytes: self.forwarded_bytes[Direction::SenderToReceiver as usize]
+                .load(Ordering::Relaxed),
+            receiver_to_sender_bytes: self.forwarded_bytes[Direction::ReceiverToSender as usize]
+                .load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forcefully closes both connections, which makes the relay thread wind down.
+    fn terminate(&mut self, termination: Termination) {
+        tracing::warn!(
+            "terminating room #{} after {:?}",
+            self.room_identifier,
+            self.started_at.elapsed()
+        );
+        let _ = self.sender_socket.shutdown(Shutdown::Both);
+        let _ = self.receiver_socket.shutdown(Shutdown::Both);
+        self.termination.get_or_insert(termination);
+    }
+}
+
+/// A session as seen from the admin API.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub session_identifier: SessionIdentifier,
+    pub room_identifier: RoomIdentifier,
+    pub namespace: Option<Namespace>,
+    pub sender_address: PeerAddr,
+    pub receiver_address: PeerAddr,
+    pub age_secs: u64,
+    pub sender_to_receiver_bytes: u64,
+    pub receiver_to_sender_bytes: u64,
+}
+
+/// Keeps track of the paired transfers that are currently being relayed, or waiting for a
+/// relay worker, so that they can be inspected and torn down from outside of the thread
+/// relaying them.
+#[derive(Default)]
+pub struct Sessions {
+    next_session_identifier: AtomicU64,
+    sessions: Arc<Mutex<HashMap<SessionIdentifier, Session>>>,
+}
+
+impl Sessions {
+    /// Records a new session, which stays registered until the returned guard is dropped. Both
+    /// clients come with the address they connected from. Returns `None` if `capacity` sessions
+    /// are registered already.
+    pub fn register(
+        &self,
+        room_identifier: RoomIdentifier,
+        namespace: Option<Namespace>,
+        (sender_socket, sender_addr): (Socket, PeerAddr),
+        (receiver_socket, receiver_addr): (Socket, PeerAddr),
+        capacity: usize,
+    ) -> Option<SessionGuard> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= capacity {
+            return None;
+        }
+        let session_identifier = self.next_session_identifier.fetch_add(1, Ordering::Relaxed);
+        let forwarded_bytes = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+        sessions.insert(
+            session_identifier,
+            Session {
+                room_identifier,
+                namespace,
+                sender_addr,
+                receiver_addr,
+                started_at: Instant::now(),
+                sender_socket,
+                receiver_socket,
+                forwarded_bytes: Arc::clone(&forwarded_bytes),
+                termination: None,
+            },
+        );
+
+        Some(SessionGuard {
+            sessions: Arc::clone(&self.sessions),
+            session_identifier,
+            forwarded_bytes,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Number of sessions of `namespace` being relayed.
+    pub fn len_in(&self, namespace: Namespace) -> usize {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.namespace == Some(namespace))
+            .count()
+    }
+
+    /// Every session being relayed, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(session_identifier, session)| session.info(*session_identifier))
+            .collect();
+        sessions.sort_by_key(|session| session.session_identifier);
+        sessions
+    }
+
+    /// Forcefully closes both connections of a single session, on behalf of an operator.
+    /// Returns the session as it was when it was cut off, or `None` if there is no such session.
+    pub fn terminate(&self, session_identifier: SessionIdentifier) -> Option<SessionInfo> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&session_identifier)?;
+        session.terminate(Termination::Killed);
+        Some(session.info(session_identifier))
+    }
+
+    /// Forcefully closes both connections of every session still being relayed, which makes
+    /// their relay threads wind down. Returns how many sessions were cut off.
+    pub fn terminate_all(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        for session in sessions.values_mut() {
+            session.terminate(Termination::Drained);
+        }
+        sessions.len()
+    }
+}
+
+pub struct SessionGuard {
+    sessions: Arc<Mutex<HashMap<SessionIdentifier, Session>>>,
+    session_identifier: SessionIdentifier,
+    forwarded_bytes: Arc<[AtomicU64; 2]>,
+}
+
+impl SessionGuard {
+    /// Accounts for `bytes` more having been forwarded in `direction`.
+    pub fn record_forwarded(&self, direction: Direction, bytes: u64) {
+        self.forwarded_bytes[direction as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes forwarded so far, indexed by [`Direction`].
+    pub fn forwarded_bytes(&self) -> [u64; 2] {
+        [
+            self.forwarded_bytes[0].load(Ordering::Relaxed),
+            self.forwarded_bytes[1].load(Ordering::Relaxed),
+        ]
+    }
+
+    /// Why the session was cut off by [`Sessions::terminate`] or [`Sessions::terminate_all`],
+    /// if it was.
+    pub fn termination(&self) -> Option<Termination> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&self.session_identifier)
+            .and_then(|session| session.termination)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session_identifier);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use usize_cast::FromUsize;
+
+/// How far ahead of its rate a room may get after having been idle, so that short pauses in a
+/// transfer do not make it slower than its limit.
+const MAX_BURST: Duration = Duration::from_millis(50);
+
+/// How much data a throttled room forwards at once, as a fraction of a second's worth, so that
+/// it is paced smoothly and picks up changes to the limits quickly.
+const CHUNKS_PER_SECOND: u64 = 10;
+
+/// Bandwidth limits applied to the forwarding of every paired room.
+///
+/// A room is held to the smaller of the per room limit and an equal share of the global limit
+/// between every room being relayed. The limits are in bytes per second, with 0 meaning
+/// unlimited, and can be changed while rooms are being relayed.
+#[derive(Default)]
+pub struct Throttle {
+    room_limit: AtomicU64,
+    global_limit: AtomicU64,
+    active_rooms: AtomicUsize,
+    throttled_nanos: AtomicU64,
+}
+
+impl Throttle {
+    pub fn new(room_limit: Option<u64>, global_limit: Option<u64>) -> Self {
+        let throttle = Self::default();
+        throttle.set_room_limit(room_limit);
+        throttle.set_global_limit(global_limit);
+        throttle
+    }
+
+    pub fn set_room_limit(&self, room_limit: Option<u64>) {
+        self.room_limit
+            .store(room_limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn set_global_limit(&self, global_limit: Option<u64>) {
+        self.global_limit
+            .store(global_limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn room_limit(&self) -> Option<u64> {
+        non_zero(self.room_limit.load(Ordering::Relaxed))
+    }
+
+    pub fn global_limit(&self) -> Option<u64> {
+        non_zero(self.global_limit.load(Ordering::Relaxed))
+    }
+
+    /// Total time rooms have spent waiting on the limits.
+    pub fn throttled_time(&self) -> Duration {
+        Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Starts throttling a newly paired room, which counts towards the sharing of the global
+    /// limit until the returned value is dropped.
+    pub fn room(&self) -> RoomThrottle<'_> {
+        self.active_rooms.fetch_add(1, Ordering::Relaxed);
+        RoomThrottle {
+            throttle: self,
+            next_send_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// The rate the rooms are currently held to, or `None` if they are not limited.
+    fn room_rate(&self) -> Option<u64> {
+        let active_rooms = u64::from_usize(self.active_rooms.load(Ordering::Relaxed).max(1));
+        let global_share = self
+            .global_limit()
+            .map(|global_limit| (global_limit / active_rooms).max(1));
+        match (self.room_limit(), global_share) {
+            (Some(room_limit), Some(global_share)) => Some(room_limit.min(global_share)),
+            (room_limit, global_share) => room_limit.or(global_share),
+        }
+    }
+}
+
+/// Paces the forwarding of a single room, shared by both of its directions.
+pub struct RoomThrottle<'a> {
+    throttle: &'a Throttle,
+    next_send_at: Mutex<Instant>,
+}
+
+impl RoomThrottle<'_> {
+    /// The most that should be forwarded at once before calling [`RoomThrottle::acquire`].
+    pub fn max_chunk_size(&self) -> usize {
+        match self.throttle.room_rate() {
+            Some(rate) => usize::try_from(rate / CHUNKS_PER_SECOND)
+                .unwrap_or(usize::MAX)
+                .max(1),
+            None => usize::MAX,
+        }
+    }
+
+    /// Blocks until the room is allowed to forward `bytes` more bytes.
+    pub fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.throttle.room_rate() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let send_at = {
+            let mut next_send_at = self.next_send_at.lock().unwrap();
+            // Credit left over from a pause is capped so that the room cannot burst for long
+            let send_at = (*next_send_at).max(now.checked_sub(MAX_BURST).unwrap_or(now));
+            *next_send_at =
+                send_at + Duration::from_secs_f64(u64::from_usize(bytes) as f64 / rate as f64);
+            send_at
+        };
+
+        if send_at > now {
+            let wait = send_at - now;
+            self.throttle
+                .throttled_nanos
+                .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Drop for RoomThrottle<'_> {
+    fn drop(&mut self) {
+        self.throttle.active_rooms.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn non_zero(limit: u64) -> Option<u64> {
+    (limit > 0).then_some(limit)
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::room_mapping::RoomIdentifier;
+
+use super::audit::{AuditRecord, Termination, Timestamp};
+use super::http;
+use super::listener::PeerAddr;
+
+/// How often an idle delivery thread checks whether the relay is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the relay POSTs an event every time a room is created, paired and ended, and how hard
+/// it tries to deliver them.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Plain HTTP URL the events are sent to as JSON, e.g.
+    /// `"http://10.0.0.1:8080/iris/events"`.
+    pub url: String,
+    /// Number of events waiting to be delivered after which new events are dropped, so that a
+    /// slow endpoint never holds up the relay.
+    pub max_queued_events: usize,
+    /// Number of times delivering an event is attempted before it is dropped.
+    pub max_attempts: u32,
+    /// How long to wait before retrying a failed delivery, doubled after every attempt.
+    pub initial_backoff: Duration,
+    /// How long a single delivery attempt may take.
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    /// POSTs events to `url`, queueing up to 1000 of them and trying to deliver each of them 5
+    /// times, waiting 1, 2, 4 and 8 seconds in between, with a 5 second timeout.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            max_queued_events: 1000,
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Splits the URL into the authority to connect to and the path to POST to, or returns
+    /// `None` if it is not a plain HTTP URL.
+    pub(super) fn endpoint(&self) -> Option<(&str, &str)> {
+        let rest = self.url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() || authority.contains('@') {
+            return None;
+        }
+        Some((authority, path))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A sender, or a receiver creating a room regardless of roles, got a room.
+    Created,
+    /// The clients of a room were paired and the transfer started.
+    Paired,
+    /// The room ended as expected.
+    Completed,
+    /// The room ended in any other way, see its termination.
+    Failed,
+}
+
+/// What the webhook is told about a room, a subset of the audit log's records for the events
+/// before the room ended.
+#[derive(Debug, Serialize)]
+pub struct WebhookEvent {
+    pub event: EventKind,
+    pub room_identifier: RoomIdentifier,
+    pub sender_address: Option<PeerAddr>,
+    pub receiver_address: Option<PeerAddr>,
+    pub sender_to_receiver_bytes: u64,
+    pub receiver_to_sender_bytes: u64,
+    /// How the room ended, only for the events of rooms that did.
+    pub termination: Option<Termination>,
+    pub timestamp: Timestamp,
+}
+
+impl WebhookEvent {
+    /// An event about a room that has not ended yet.
+    pub fn new(
+        event: EventKind,
+        room_identifier: RoomIdentifier,
+        sender_address: Option<PeerAddr>,
+        receiver_address: Option<PeerAddr>,
+    ) -> Self {
+        Self {
+            event,
+            room_identifier,
+            sender_address,
+            receiver_address,
+            sender_to_receiver_bytes: 0,
+            receiver_to_sender_bytes: 0,
+            termination: None,
+            timestamp: Timestamp::now(),
+        }
+    }
+
+    /// The event telling how a room ended.
+    pub fn ended(record: &AuditRecord) -> Self {
+        Self {
+            event: match record.termination {
+                Termination::Normal => EventKind::Completed,
+                _ => EventKind::Failed,
+            },
+            room_identifier: record.room_identifier,
+            sender_address: record.sender_address,
+            receiver_address: record.receiver_address,
+            sender_to_receiver_bytes: record.sender_to_receiver_bytes,
+            receiver_to_sender_bytes: record.receiver_to_sender_bytes,
+            termination: Some(record.termination),
+            timestamp: record.ended_at,
+        }
+    }
+}
+
+/// Hands events over to the thread delivering them, without ever waiting for it.
+pub struct Webhook {
+    queue: SyncSender<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Creates the webhook along with the queue its events are delivered from, which is meant
+    /// to be handed to a thread of its own, see [`WebhookQueue::deliver`].
+    pub fn new(config: WebhookConfig) -> (Self, WebhookQueue) {
+        let (sender, receiver) = mpsc::sync_channel(config.max_queued_events);
+        (Self { queue: sender }, WebhookQueue { config, receiver })
+    }
+
+    /// Queues `event` for delivery, dropping it if the queue is full.
+    pub fn notify(&self, event: WebhookEvent) {
+        match self.queue.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => tracing::warn!(
+                "dropped the {:?} event of room #{} as the webhook queue is full",
+                event.event,
+                event.room_identifier
+            ),
+            // The delivery thread only stops once the relay shuts down
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+pub struct WebhookQueue {
+    config: WebhookConfig,
+    receiver: Receiver<WebhookEvent>,
+}
+
+impl WebhookQueue {
+    /// Delivers the queued events one after the other, in the order they happened, until
+    /// `is_shutting_down` is set. Failed deliveries are retried with exponential backoff, which
+    /// stops once the relay shuts down.
+    pub fn deliver(self, is_shutting_down: &AtomicBool) {
+        // Checked when the relay is configured
+        let Some((authority, path)) = self.config.endpoint() else {
+            return;
+        };
+
+        while !is_shutting_down.load(Ordering::Relaxed) {
+            let event = match self.receiver.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("failed to serialize a webhook event: {e}");
+                    continue;
+                }
+            };
+
+            let mut backoff = self.config.initial_backoff;
+            for attempt in 1..=self.config.max_attempts {
+                match http::post_json(authority, path, &body, self.config.timeout) {
+                    Ok(status) if (200..300).contains(&status) => break,
+                    Ok(status) => tracing::warn!(
+                        "webhook answered the {:?} event of room #{} with status {status}",
+                        event.event,
+                        event.room_identifier
+                    ),
+                    Err(e) => tracing::warn!(
+                        "failed to deliver the {:?} event of room #{} to the webhook: {e}",
+                        event.event,
+                        event.room_identifier
+                    ),
+                }
+                if attempt == self.config.max_attempts || !sleep(backoff, is_shutting_down) {
+                    tracing::error!(
+                        "gave up on delivering the {:?} event of room #{} after {attempt} attempts",
+                        event.event,
+                        event.room_identifier
+                    );
+                    break;
+                }
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+    }
+}
+
+/// Sleeps for `duration` unless the relay starts shutting down in the meantime, and returns
+/// whether it slept through.
+fn sleep(duration: Duration, is_shutting_down: &AtomicBool) -> bool {
+    let mut remaining = duration;
+    while !remaining.is_zero() {
+        if is_shutting_down.load(Ordering::Relaxed) {
+            return false;
+        }
+        let nap = remaining.min(SHUTDOWN_POLL_INTERVAL);
+        thread::sleep(nap);
+        remaining -= nap;
+    }
+    !is_shutting_down.load(Ordering::Relaxed)
+}
@@ -0,0 +1,146 @@
+use std::io::{ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::mem::MaybeUninit;
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// A connected stream socket, either over the network or, on Unix, over a Unix domain socket.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn try_clone(&self) -> Result<Self, std::io::Error> {
+        match self {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        match self {
+            Socket::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        match self {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        match self {
+            Socket::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Checks, without blocking, whether the other end has closed the connection. Anything it
+    /// sent in the meantime is left for the next read.
+    ///
+    /// Only meant for sockets that nobody is currently reading from, such as a sender waiting in
+    /// a room, and safe to call from any thread as it leaves the socket's settings alone.
+    pub fn is_peer_disconnected(&self) -> bool {
+        match self.peek_without_blocking() {
+            Ok(bytes_read) => bytes_read == 0,
+            Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        }
+    }
+
+    #[cfg(unix)]
+    fn peek_without_blocking(&self) -> Result<usize, std::io::Error> {
+        let socket = match self {
+            Socket::Tcp(stream) => socket2::SockRef::from(stream),
+            Socket::Unix(stream) => socket2::SockRef::from(stream),
+        };
+        socket.recv_with_flags(
+            &mut [MaybeUninit::uninit()],
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    }
+
+    // There is no flag to peek without blocking here, and nobody else is using the socket
+    // while it is being checked
+    #[cfg(not(unix))]
+    fn peek_without_blocking(&self) -> Result<usize, std::io::Error> {
+        let Socket::Tcp(stream) = self;
+        stream.set_nonblocking(true)?;
+        let result = stream.peek(&mut [0]);
+        stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &Socket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &Socket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Joins a host and a port into an address that can be resolved, wrapping IPv6 literals such
+/// as `::1` in brackets.
+pub fn join_host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
@@ -0,0 +1,202 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use iris::{
+    get_receiver_communication_channels, get_sender_communication_channels, simple_receive,
+    simple_send, CipherType, ConflictingFileMode, IrisError, IrisMessage, RelayConnectionOptions,
+    SenderProgressMessage, SenderWorkerCommunication,
+};
+
+pub const PASSPHRASE: &str = "this-is-secret";
+
+/// A file for a test to send, made in the temporary directory under a name no other test uses,
+/// as receivers write into the working directory that every test shares. The file, and the copy
+/// of it received into the working directory, are removed once it is dropped.
+pub struct Fixture {
+    path: PathBuf,
+    name: String,
+}
+
+impl Fixture {
+    pub fn new(label: &str) -> Self {
+        let name = format!("iris-fixture-{label}-{}", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        fs::write(&path, format!("{label}\n")).unwrap();
+        Self { path, name }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Checks that the file was received into the working directory as it was sent, and
+    /// removes the copy so that it can be received again.
+    pub fn assert_received(&self) {
+        assert_eq!(fs::read(&self.name).unwrap(), fs::read(&self.path).unwrap());
+        fs::remove_file(&self.name).unwrap();
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(&self.name);
+    }
+}
+
+/// A sender that has been assigned a room and is waiting for its receiver.
+pub struct WaitingSender {
+    pub room_identifier: u64,
+    thread: JoinHandle<Result<(), IrisError>>,
+    // The sender reports its progress until it is done, so the other end has to stay around
+    _worker_communication: SenderWorkerCommunication,
+}
+
+impl WaitingSender {
+    pub fn join(self) -> Result<(), IrisError> {
+        self.thread.join().unwrap()
+    }
+}
+
+/// Starts sending `files` through the relay at `relay_addr` and waits until the sender has been
+/// assigned a room.
+pub fn spawn_sender(
+    relay_addr: SocketAddr,
+    relay_connection_options: RelayConnectionOptions,
+    files: Vec<&str>,
+) -> WaitingSender {
+    let (worker_communication, progress_communication) = get_sender_communication_channels();
+    let files = files.into_iter().map(Into::into).collect();
+    let sender = thread::spawn(move || {
+        simple_send(
+            relay_addr.ip().to_string(),
+            relay_addr.port().to_string(),
+            &relay_connection_options,
+            None,
+            CipherType::XChaCha20Poly1305,
+            PASSPHRASE,
+            files,
+            &progress_communication,
+        )
+    });
+
+    loop {
+        match worker_communication.read() {
+            Ok(Some(SenderProgressMessage::AssignedRoomIdentifier { room_identifier })) => {
+                return WaitingSender {
+                    room_identifier,
+                    thread: sender,
+                    _worker_communication: worker_communication,
+                };
+            }
+            Ok(_) => thread::sleep(Duration::from_millis(10)),
+            Err(_) => panic!("sender failed before getting a room: {:?}", sender.join()),
+        }
+    }
+}
+
+/// Receives whatever is sent in `room_identifier` into the current directory.
+pub fn receive(
+    relay_addr: SocketAddr,
+    relay_connection_options: &RelayConnectionOptions,
+    room_identifier: &str,
+) -> Result<(), IrisError> {
+    let (_worker_communication, progress_communication) = get_receiver_communication_channels();
+    simple_receive(
+        relay_addr.ip().to_string(),
+        relay_addr.port().to_string(),
+        relay_connection_options,
+        Some(room_identifier),
+        PASSPHRASE,
+        ConflictingFileMode::Error,
+        &progress_communication,
+    )
+}
+
+/// Sends `files` through the relay at `relay_addr`, only useful when the sender is expected to
+/// be turned away as nobody is there to receive the files.
+pub fn send(
+    relay_addr: SocketAddr,
+    relay_connection_options: &RelayConnectionOptions,
+    files: Vec<&str>,
+) -> Result<(), IrisError> {
+    let (_worker_communication, progress_communication) = get_sender_communication_channels();
+    simple_send(
+        relay_addr.ip().to_string(),
+        relay_addr.port().to_string(),
+        relay_connection_options,
+        None,
+        CipherType::XChaCha20Poly1305,
+        PASSPHRASE,
+        files.into_iter().map(Into::into).collect(),
+        &progress_communication,
+    )
+}
+
+/// Writes a size-prefixed message the way clients talk to the relay.
+pub fn write_message(stream: &mut impl Write, message: IrisMessage) {
+    let serialized_message = serde_json::to_vec(&message).unwrap();
+    let size = u32::try_from(serialized_message.len()).unwrap();
+    stream.write_all(&size.to_be_bytes()).unwrap();
+    stream.write_all(&serialized_message).unwrap();
+}
+
+/// Reads a size-prefixed message the way clients listen to the relay.
+pub fn read_message(stream: &mut impl Read) -> IrisMessage {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size).unwrap();
+    let mut serialized_message = vec![0; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut serialized_message).unwrap();
+    serde_json::from_slice(&serialized_message).unwrap()
+}
+
+/// Pairs a raw sender and receiver through the relay at `relay_addr`, leaving them right where
+/// the clients would start their key exchange.
+pub fn pair(relay_addr: SocketAddr) -> (TcpStream, TcpStream) {
+    let (_, sender, receiver) = pair_in_room(relay_addr);
+    (sender, receiver)
+}
+
+/// Same as [`pair`], along with the room the two of them were paired in.
+pub fn pair_in_room(relay_addr: SocketAddr) -> (u64, TcpStream, TcpStream) {
+    let mut sender = TcpStream::connect(relay_addr).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+    let mut receiver = TcpStream::connect(relay_addr).unwrap();
+    write_message(
+        &mut receiver,
+        IrisMessage::ReceiverConnecting { room_identifier },
+    );
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverConnected
+    ));
+    (room_identifier, sender, receiver)
+}
+
+/// Reads whatever is left on `stream` until the relay hangs up.
+pub fn read_until_closed(stream: &mut impl Read) -> Vec<u8> {
+    let mut rest = Vec::new();
+    // Being reset counts as being hung up on
+    let _ = stream.read_to_end(&mut rest);
+    rest
+}
+
+/// Asks the relay at `relay_addr` how the session of `room_identifier` ended, the way clients
+/// do once they are cut off.
+pub fn ask_how_session_ended(relay_addr: SocketAddr, room_identifier: u64) -> IrisMessage {
+    let mut client = TcpStream::connect(relay_addr).unwrap();
+    write_message(
+        &mut client,
+        IrisMessage::AskingHowSessionEnded { room_identifier },
+    );
+    read_message(&mut client)
+}
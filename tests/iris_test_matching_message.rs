@@ -33,6 +33,7 @@ fn test_matching_message() {
             let (_worker_communication, progress_communication) = get_sender_communication_channels();
             send(
                 &mut sender_connection,
+                None,
                 2000,
                 "this-is-secret",
                 CipherType::XChaCha20Poly1305,
@@ -45,6 +46,7 @@ fn test_matching_message() {
             let (_worker_communication, progress_communication) = get_receiver_communication_channels();
             receive(
                 &mut receiver_connection,
+                None,
                 2000,
                 "this-is-secret",
                 ConflictingFileMode::Error,
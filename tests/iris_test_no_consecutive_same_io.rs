@@ -30,6 +30,7 @@ fn test_no_consecutive_same_io() {
             let files = vec!["./tests/bbb".into()];
             send(
                 &mut sender_connection,
+                None,
                 3000,
                 "this-is-secret",
                 CipherType::XChaCha20Poly1305,
@@ -42,6 +43,7 @@ fn test_no_consecutive_same_io() {
             let (_worker_communication, progress_communication) = get_receiver_communication_channels();
             receive(
                 &mut receiver_connection,
+                None,
                 3000,
                 "this-is-secret",
                 ConflictingFileMode::Error,
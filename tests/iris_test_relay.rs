@@ -0,0 +1,2250 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iris::{
+    get_receiver_communication_channels, get_sender_communication_channels, simple_broadcast,
+    simple_receive, simple_receive_from_mailbox, simple_send, simple_send_reusable,
+    simple_send_to_mailbox, AuditLogConfig, BroadcastOptions, CipherType, ClusterConfig,
+    ConflictingFileMode, IrisError, IrisMessage, ListenerAddr, MailboxConfig, NamespaceLimits,
+    ReceiverProgressMessage, RelayBuilder, RelayConnectionOptions, ReusableRoomOptions,
+    SenderProgressMessage, WebhookConfig,
+};
+
+use common::{
+    ask_how_session_ended, pair, pair_in_room, read_message, read_until_closed, receive, send,
+    spawn_sender, write_message, Fixture, PASSPHRASE,
+};
+
+/// Checks that a file makes it from the sender to the receiver through a relay bound to an
+/// ephemeral port.
+#[test]
+fn test_transfer_through_relay() {
+    let file = Fixture::new("transfer-through-relay");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let port = relay.local_addr().port().to_string();
+
+    let (sender_worker_communication, sender_progress_communication) =
+        get_sender_communication_channels();
+    let sender_port = port.clone();
+    let files = vec![file.path().into()];
+    let sender = thread::spawn(move || {
+        simple_send(
+            "127.0.0.1".to_string(),
+            sender_port,
+            &RelayConnectionOptions::default(),
+            None,
+            CipherType::XChaCha20Poly1305,
+            "this-is-secret",
+            files,
+            &sender_progress_communication,
+        )
+    });
+
+    let room_identifier = loop {
+        match sender_worker_communication.read().unwrap() {
+            Some(SenderProgressMessage::AssignedRoomIdentifier { room_identifier }) => {
+                break room_identifier
+            }
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let (_receiver_worker_communication, receiver_progress_communication) =
+        get_receiver_communication_channels();
+    simple_receive(
+        "127.0.0.1".to_string(),
+        port,
+        &RelayConnectionOptions::default(),
+        Some(&room_identifier.to_string()),
+        "this-is-secret",
+        ConflictingFileMode::Error,
+        &receiver_progress_communication,
+    )
+    .unwrap();
+    sender.join().unwrap().unwrap();
+
+    file.assert_received();
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a receiver with an unknown room identifier is turned away.
+#[test]
+fn test_bad_room_identifier() {
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+
+    let (_worker_communication, progress_communication) = get_receiver_communication_channels();
+    let result = simple_receive(
+        "127.0.0.1".to_string(),
+        relay.local_addr().port().to_string(),
+        &RelayConnectionOptions::default(),
+        Some("1"),
+        "this-is-secret",
+        ConflictingFileMode::Error,
+        &progress_communication,
+    );
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that the relay passes on whatever bytes the clients send, in both directions at
+/// once, even when they do not look like messages at all.
+#[test]
+fn test_opaque_pipe() {
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    // Starts out like a message far larger than the relay would ever take in a handshake
+    let upstream: Vec<u8> = [0xFF; 4]
+        .into_iter()
+        .chain((0..1_000_000u32).map(|i| (i.wrapping_mul(31) ^ (i >> 8)) as u8))
+        .collect();
+    let downstream: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+    let mut sender_writer = sender.try_clone().unwrap();
+    let mut receiver_writer = receiver.try_clone().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            sender_writer.write_all(&upstream).unwrap();
+            sender_writer.shutdown(std::net::Shutdown::Write).unwrap();
+        });
+        s.spawn(|| {
+            receiver_writer.write_all(&downstream).unwrap();
+            receiver_writer.shutdown(std::net::Shutdown::Write).unwrap();
+        });
+        assert_eq!(read_until_closed(&mut receiver), upstream);
+        assert_eq!(read_until_closed(&mut sender), downstream);
+    });
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a client hanging up its side of the pipe is passed on, while the other
+/// direction keeps going until the other client hangs up as well.
+#[test]
+fn test_pipe_half_close() {
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    sender.write_all(b"request").unwrap();
+    sender.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(read_until_closed(&mut receiver), b"request");
+
+    receiver.write_all(b"response").unwrap();
+    let mut response = [0; 8];
+    sender.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"response");
+    receiver.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(read_until_closed(&mut sender).is_empty());
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that clients that never say anything neither stop a real pair from getting through
+/// nor pile up beyond the queue of the handshake workers.
+#[test]
+fn test_idle_clients() {
+    let file = Fixture::new("idle-clients");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_concurrent_handshakes(2)
+        .max_queued_handshakes(4)
+        .handshake_timeout(Duration::from_secs(1))
+        .spawn()
+        .unwrap();
+
+    let idle_clients: Vec<TcpStream> = (0..6)
+        .map(|_| TcpStream::connect(relay.local_addr()).unwrap())
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+    let mut turned_away = TcpStream::connect(relay.local_addr()).unwrap();
+    assert!(matches!(
+        read_message(&mut turned_away),
+        IrisMessage::RelayFull
+    ));
+    drop(idle_clients);
+
+    // These take up both handshake workers, the real pair waits in the queue behind them
+    let _idle_clients: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(relay.local_addr()).unwrap())
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    receive(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        &sender.room_identifier.to_string(),
+    )
+    .unwrap();
+    sender.join().unwrap();
+    file.assert_received();
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a pair is turned away rather than left waiting when every relay worker is busy.
+#[test]
+fn test_relay_workers_busy() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_concurrent_relays(1)
+        .max_broadcast_receivers(1)
+        .spawn()
+        .unwrap();
+
+    let _busy_pair = pair(relay.local_addr());
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+    let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(
+        &mut receiver,
+        IrisMessage::ReceiverConnecting { room_identifier },
+    );
+    assert!(matches!(read_message(&mut sender), IrisMessage::RelayFull));
+    assert!(matches!(
+        read_message(&mut receiver),
+        IrisMessage::RelayFull
+    ));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a room nobody joins is closed once its time is up, and that its sender is told.
+#[test]
+fn test_room_expiry() {
+    let file = Fixture::new("room-expiry");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .room_ttl(Duration::from_secs(1))
+        .spawn()
+        .unwrap();
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    let room_identifier = sender.room_identifier.to_string();
+    assert!(matches!(sender.join(), Err(IrisError::RoomExpired)));
+    let result = receive(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        &room_identifier,
+    );
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a full relay turns senders away, and that the room of a sender that hung up
+/// makes way for a new one.
+#[test]
+fn test_abandoned_rooms() {
+    let file = Fixture::new("abandoned-rooms");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_waiting_rooms(1)
+        .spawn()
+        .unwrap();
+
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::AssignedRoomIdentifier { .. }
+    ));
+    let result = send(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    assert!(matches!(result, Err(IrisError::RelayFull)));
+
+    drop(sender);
+    let started_at = Instant::now();
+    let mut sender = loop {
+        let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+        write_message(&mut sender, IrisMessage::SenderConnecting);
+        match read_message(&mut sender) {
+            IrisMessage::AssignedRoomIdentifier { .. } => break sender,
+            IrisMessage::RelayFull if started_at.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            message => panic!("the abandoned room was never closed, got {message:?}"),
+        }
+    };
+
+    relay.drain(Duration::ZERO);
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::RelayDraining
+    ));
+    relay.join().unwrap();
+}
+
+/// Checks that room identifiers are as long as the relay is configured to hand out.
+#[test]
+fn test_room_identifier_digits() {
+    let file = Fixture::new("room-identifier-digits");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .room_identifier_digits(8, 10)
+        .spawn()
+        .unwrap();
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    assert_eq!(sender.room_identifier.to_string().len(), 8);
+
+    relay.drain(Duration::ZERO);
+    assert!(matches!(sender.join(), Err(IrisError::RelayDraining)));
+    relay.join().unwrap();
+}
+
+/// Checks that draining the relay closes the rooms still waiting for a receiver.
+#[test]
+fn test_drain_closes_waiting_rooms() {
+    let file = Fixture::new("drain-closes-waiting-rooms");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let port = relay.local_addr().port().to_string();
+
+    let (sender_worker_communication, sender_progress_communication) =
+        get_sender_communication_channels();
+    let sender = thread::spawn(move || {
+        simple_send(
+            "127.0.0.1".to_string(),
+            port,
+            &RelayConnectionOptions::default(),
+            None,
+            CipherType::XChaCha20Poly1305,
+            "this-is-secret",
+            vec![file.path().into()],
+            &sender_progress_communication,
+        )
+    });
+    while !matches!(
+        sender_worker_communication.read().unwrap(),
+        Some(SenderProgressMessage::AssignedRoomIdentifier { .. })
+    ) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    relay.drain(Duration::from_secs(1));
+    assert!(matches!(
+        sender.join().unwrap(),
+        Err(IrisError::RelayDraining)
+    ));
+    relay.join().unwrap();
+}
+
+/// Checks that a transfer that is already paired may finish while the relay drains, and that
+/// the relay stops as soon as it has.
+#[test]
+fn test_drain_lets_transfers_finish() {
+    let file = Fixture::new("drain-lets-transfers-finish");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+
+    let started_at = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| relay.drain(Duration::from_secs(30)));
+        thread::sleep(Duration::from_millis(200));
+        let result = send(
+            relay.local_addr(),
+            &RelayConnectionOptions::default(),
+            vec![file.path()],
+        );
+        assert!(matches!(result, Err(IrisError::RelayDraining)));
+
+        sender.write_all(b"the rest of the transfer").unwrap();
+        sender.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        receiver.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"the rest of the transfer");
+        drop((sender, receiver));
+    });
+    assert!(started_at.elapsed() < Duration::from_secs(30));
+    relay.join().unwrap();
+}
+
+/// Checks that a transfer still going when the drain times out is cut off.
+#[test]
+fn test_drain_deadline() {
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let (_sender, mut receiver) = pair(relay.local_addr());
+
+    let started_at = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| relay.drain(Duration::from_secs(1)));
+        // Nothing is ever sent, so this only returns once the relay hangs up
+        let _ = receiver.read_to_end(&mut Vec::new());
+        assert!(started_at.elapsed() >= Duration::from_secs(1));
+    });
+    relay.join().unwrap();
+}
+
+/// Checks that a relay with access tokens only lets in clients presenting one of them.
+#[test]
+fn test_access_tokens() {
+    let file = Fixture::new("access-tokens");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .access_token("ci", "correct-token")
+        .spawn()
+        .unwrap();
+    let port = relay.local_addr().port().to_string();
+
+    for access_token in [None, Some("wrong-token".to_string())] {
+        let (_worker_communication, progress_communication) = get_sender_communication_channels();
+        let result = simple_send(
+            "127.0.0.1".to_string(),
+            port.clone(),
+            &RelayConnectionOptions {
+                access_token,
+                ..Default::default()
+            },
+            None,
+            CipherType::XChaCha20Poly1305,
+            "this-is-secret",
+            vec![file.path().into()],
+            &progress_communication,
+        );
+        assert!(matches!(result, Err(IrisError::RelayAccessDenied)));
+    }
+
+    let (sender_worker_communication, sender_progress_communication) =
+        get_sender_communication_channels();
+    let sender = thread::spawn(move || {
+        simple_send(
+            "127.0.0.1".to_string(),
+            port,
+            &RelayConnectionOptions {
+                access_token: Some("correct-token".to_string()),
+                ..Default::default()
+            },
+            None,
+            CipherType::XChaCha20Poly1305,
+            "this-is-secret",
+            vec![file.path().into()],
+            &sender_progress_communication,
+        )
+    });
+    while !matches!(
+        sender_worker_communication.read().unwrap(),
+        Some(SenderProgressMessage::AssignedRoomIdentifier { .. })
+    ) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    relay.drain(Duration::ZERO);
+    assert!(matches!(
+        sender.join().unwrap(),
+        Err(IrisError::RelayDraining)
+    ));
+    relay.join().unwrap();
+}
+
+/// Checks that clients guessing access tokens get banned like those guessing room identifiers.
+#[test]
+fn test_failed_authentications_get_banned() {
+    let file = Fixture::new("failed-authentications-get-banned");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .access_token("ci", "correct-token")
+        .max_failed_joins(2)
+        .failed_join_backoff(Duration::ZERO)
+        .spawn()
+        .unwrap();
+    let with_token = |access_token: &str| RelayConnectionOptions {
+        access_token: Some(access_token.to_string()),
+        ..Default::default()
+    };
+
+    for _ in 0..2 {
+        let result = send(
+            relay.local_addr(),
+            &with_token("wrong-token"),
+            vec![file.path()],
+        );
+        assert!(matches!(result, Err(IrisError::RelayAccessDenied)));
+    }
+    let result = send(
+        relay.local_addr(),
+        &with_token("correct-token"),
+        vec![file.path()],
+    );
+    assert!(matches!(result, Err(IrisError::RateLimited(_))));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that receivers guessing room identifiers have to back off and eventually get banned.
+#[test]
+fn test_failed_joins_get_banned() {
+    let file = Fixture::new("failed-joins-get-banned");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_failed_joins(3)
+        .failed_join_backoff(Duration::from_millis(200))
+        .spawn()
+        .unwrap();
+    let options = RelayConnectionOptions::default();
+
+    let result = receive(relay.local_addr(), &options, "1000");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    let result = receive(relay.local_addr(), &options, "1001");
+    assert!(matches!(result, Err(IrisError::RateLimited(1))));
+
+    thread::sleep(Duration::from_millis(250));
+    let result = receive(relay.local_addr(), &options, "1001");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    thread::sleep(Duration::from_millis(450));
+    let result = receive(relay.local_addr(), &options, "1002");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+
+    // Banned addresses may not even create rooms anymore
+    let result = send(relay.local_addr(), &options, vec![file.path()]);
+    assert!(matches!(result, Err(IrisError::RateLimited(900))));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that joining a room of its own does not make up for a client's failed joins, and
+/// that moving around within a single IPv6 /64 does not get a client out of a ban.
+#[test]
+fn test_rate_limit_evasion() {
+    let file = Fixture::new("rate-limit-evasion");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_failed_joins(2)
+        .failed_join_backoff(Duration::from_millis(100))
+        .spawn()
+        .unwrap();
+    let options = RelayConnectionOptions::default();
+
+    let result = receive(relay.local_addr(), &options, "1000");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    thread::sleep(Duration::from_millis(150));
+    let sender = spawn_sender(relay.local_addr(), options.clone(), vec![file.path()]);
+    receive(
+        relay.local_addr(),
+        &options,
+        &sender.room_identifier.to_string(),
+    )
+    .unwrap();
+    sender.join().unwrap();
+    file.assert_received();
+
+    let result = receive(relay.local_addr(), &options, "1001");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    let result = send(relay.local_addr(), &options, vec![file.path()]);
+    assert!(matches!(result, Err(IrisError::RateLimited(900))));
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .trusted_proxy("127.0.0.0/8".parse().unwrap())
+        .max_failed_joins(2)
+        .failed_join_backoff(Duration::ZERO)
+        .spawn()
+        .unwrap();
+    let join_from = |source: &str| {
+        let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+        receiver
+            .write_all(format!("PROXY TCP6 {source} 2001:db8:ffff::1 50000 7777\r\n").as_bytes())
+            .unwrap();
+        write_message(
+            &mut receiver,
+            IrisMessage::ReceiverConnecting {
+                room_identifier: 1000,
+            },
+        );
+        read_message(&mut receiver)
+    };
+
+    assert!(matches!(
+        join_from("2001:db8::1"),
+        IrisMessage::BadRoomIdentifier
+    ));
+    assert!(matches!(
+        join_from("2001:db8::2"),
+        IrisMessage::BadRoomIdentifier
+    ));
+    assert!(matches!(
+        join_from("2001:db8::3"),
+        IrisMessage::RateLimited { .. }
+    ));
+    // Another /64 is somebody else
+    assert!(matches!(
+        join_from("2001:db8:0:1::1"),
+        IrisMessage::BadRoomIdentifier
+    ));
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that an address cannot create rooms faster than the relay allows.
+#[test]
+fn test_room_creation_limit() {
+    let file = Fixture::new("room-creation-limit");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_room_creations_per_minute(1)
+        .spawn()
+        .unwrap();
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    let result = send(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    assert!(matches!(result, Err(IrisError::RateLimited(secs)) if secs > 0 && secs <= 60));
+
+    relay.drain(Duration::ZERO);
+    assert!(matches!(sender.join(), Err(IrisError::RelayDraining)));
+    relay.join().unwrap();
+}
+
+/// Checks that a sender on IPv4 and a receiver on IPv6 end up in the same room.
+#[test]
+fn test_transfer_across_listeners() {
+    let file = Fixture::new("transfer-across-listeners");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .listen_on("[::1]:0")
+        .spawn()
+        .unwrap();
+    let ListenerAddr::Tcp(ipv6_addr) = relay.listener_addrs()[1] else {
+        panic!("expected a network address");
+    };
+    assert!(ipv6_addr.is_ipv6());
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    receive(
+        ipv6_addr,
+        &RelayConnectionOptions::default(),
+        &sender.room_identifier.to_string(),
+    )
+    .unwrap();
+    sender.join().unwrap();
+
+    file.assert_received();
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that clients on a Unix domain socket share the rooms with clients on the network.
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_listener() {
+    use std::os::unix::net::UnixStream;
+
+    let socket_path =
+        std::env::temp_dir().join(format!("iris-test-relay-{}.sock", std::process::id()));
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .listen_on(format!("unix:{}", socket_path.display()))
+        .spawn()
+        .unwrap();
+    assert_eq!(
+        relay.listener_addrs()[1],
+        ListenerAddr::Unix(socket_path.clone())
+    );
+
+    let mut sender = UnixStream::connect(&socket_path).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+
+    let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(
+        &mut receiver,
+        IrisMessage::ReceiverConnecting { room_identifier },
+    );
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverConnected
+    ));
+
+    // Once paired, the bytes flow both ways between the Unix domain socket and the network
+    sender.write_all(b"ping").unwrap();
+    let mut buffer = [0; 4];
+    receiver.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"ping");
+
+    drop(sender);
+    drop(receiver);
+    relay.shutdown();
+    relay.join().unwrap();
+    assert!(!socket_path.exists());
+}
+
+/// Checks that clients on a Unix domain socket share the rate limits of a single address and
+/// may not send a PROXY protocol header unless they are trusted.
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_trust() {
+    use std::os::unix::net::UnixStream;
+
+    const PROXY_HEADER: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 50000 7777\r\n";
+    let socket_path =
+        std::env::temp_dir().join(format!("iris-test-trust-{}.sock", std::process::id()));
+    let join_over_unix_socket = |header: &[u8]| {
+        let mut receiver = UnixStream::connect(&socket_path).unwrap();
+        receiver.write_all(header).unwrap();
+        write_message(
+            &mut receiver,
+            IrisMessage::ReceiverConnecting {
+                room_identifier: 1000,
+            },
+        );
+        receiver
+    };
+
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .listen_on(format!("unix:{}", socket_path.display()))
+        .max_failed_joins(1)
+        .spawn()
+        .unwrap();
+    assert!(matches!(
+        read_message(&mut join_over_unix_socket(b"")),
+        IrisMessage::BadRoomIdentifier
+    ));
+    assert!(matches!(
+        read_message(&mut join_over_unix_socket(b"")),
+        IrisMessage::RateLimited { .. }
+    ));
+    let mut response = Vec::new();
+    let _ = join_over_unix_socket(PROXY_HEADER).read_to_end(&mut response);
+    assert!(response.is_empty());
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .listen_on(format!("unix:{}", socket_path.display()))
+        .max_failed_joins(1)
+        .trust_unix_clients(true)
+        .spawn()
+        .unwrap();
+    for _ in 0..2 {
+        assert!(matches!(
+            read_message(&mut join_over_unix_socket(b"")),
+            IrisMessage::BadRoomIdentifier
+        ));
+    }
+    assert!(matches!(
+        read_message(&mut join_over_unix_socket(PROXY_HEADER)),
+        IrisMessage::BadRoomIdentifier
+    ));
+    assert!(matches!(
+        read_message(&mut join_over_unix_socket(PROXY_HEADER)),
+        IrisMessage::RateLimited { .. }
+    ));
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a listener on [::] takes IPv4 clients as well unless it is IPv6 only.
+#[test]
+fn test_ipv6_only() {
+    let relay = RelayBuilder::new("[::]:0")
+        .ipv6_only(false)
+        .spawn()
+        .unwrap();
+    let port = relay.local_addr().port();
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write_message(&mut client, IrisMessage::SenderConnecting);
+    assert!(matches!(
+        read_message(&mut client),
+        IrisMessage::AssignedRoomIdentifier { .. }
+    ));
+    drop(client);
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let relay = RelayBuilder::new("[::]:0").ipv6_only(true).spawn().unwrap();
+    let port = relay.local_addr().port();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that the address in a PROXY protocol header from a trusted load balancer is the one
+/// being rate limited, and that untrusted peers cannot send such a header.
+#[test]
+fn test_proxy_protocol() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .trusted_proxy("127.0.0.0/8".parse().unwrap())
+        .max_failed_joins(1)
+        .spawn()
+        .unwrap();
+    let proxy_protocol_v2_header = [
+        b"\r\n\r\n\0\r\nQUIT\n".as_slice(),
+        // PROXY command over TCP4 with 12 bytes of addresses
+        &[0x21, 0x11, 0x00, 0x0C],
+        &[203, 0, 113, 7, 10, 0, 0, 1],
+        &[0xC3, 0x50, 0x1E, 0x61],
+    ]
+    .concat();
+    let join_through_proxy = |header: &[u8]| {
+        let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+        receiver.write_all(header).unwrap();
+        write_message(
+            &mut receiver,
+            IrisMessage::ReceiverConnecting {
+                room_identifier: 1000,
+            },
+        );
+        read_message(&mut receiver)
+    };
+
+    // The client behind the load balancer gets banned after its first failed join...
+    assert!(matches!(
+        join_through_proxy(b"PROXY TCP4 203.0.113.7 10.0.0.1 50000 7777\r\n"),
+        IrisMessage::BadRoomIdentifier
+    ));
+    assert!(matches!(
+        join_through_proxy(&proxy_protocol_v2_header),
+        IrisMessage::RateLimited { .. }
+    ));
+    // ...while the load balancer itself is not
+    assert!(matches!(
+        join_through_proxy(b""),
+        IrisMessage::BadRoomIdentifier
+    ));
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let mut client = TcpStream::connect(relay.local_addr()).unwrap();
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 50000 7777\r\n")
+        .unwrap();
+    write_message(&mut client, IrisMessage::SenderConnecting);
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response);
+    assert!(response.is_empty());
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that paired rooms are held to the bandwidth limit, and that it can be lifted while
+/// the room is being relayed.
+#[test]
+fn test_room_bandwidth_limit() {
+    const CHUNK_SIZE: usize = 100_000;
+
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .room_bandwidth_limit(Some(CHUNK_SIZE as u64))
+        .spawn()
+        .unwrap();
+
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+    let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(
+        &mut receiver,
+        IrisMessage::ReceiverConnecting { room_identifier },
+    );
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverConnected
+    ));
+
+    let chunk = vec![0; CHUNK_SIZE];
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let started_at = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| sender.write_all(&chunk).unwrap());
+        receiver.read_exact(&mut buffer).unwrap();
+    });
+    assert!(started_at.elapsed() >= Duration::from_millis(800));
+
+    relay.set_room_bandwidth_limit(None);
+    let started_at = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..10 {
+                sender.write_all(&chunk).unwrap();
+            }
+        });
+        for _ in 0..10 {
+            receiver.read_exact(&mut buffer).unwrap();
+        }
+    });
+    // Allow for the chunk that may have been scheduled under the previous limit
+    assert!(started_at.elapsed() < Duration::from_secs(1));
+
+    drop(sender);
+    drop(receiver);
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a transfer going over the session size limit is ended and that both clients
+/// are told why.
+#[test]
+fn test_session_size_limit() {
+    let file = Fixture::new("session-size-limit");
+    // Enough for the start of the key exchange, not for the transfer itself
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_session_bytes(Some(100))
+        .spawn()
+        .unwrap();
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    let result = receive(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        &sender.room_identifier.to_string(),
+    );
+    assert!(matches!(result, Err(IrisError::SessionSizeExceeded)));
+    assert!(matches!(sender.join(), Err(IrisError::SessionSizeExceeded)));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that an idle session is ended once it has lasted as long as it may, and that only
+/// the room it was in is reported as having gone over the limit.
+#[test]
+fn test_session_duration_limit() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_session_duration(Some(Duration::from_secs(1)))
+        .spawn()
+        .unwrap();
+
+    let (room_identifier, mut sender, mut receiver) = pair_in_room(relay.local_addr());
+    let started_at = Instant::now();
+    assert!(read_until_closed(&mut sender).is_empty());
+    assert!(read_until_closed(&mut receiver).is_empty());
+    assert!(started_at.elapsed() < Duration::from_secs(3));
+    assert!(matches!(
+        ask_how_session_ended(relay.local_addr(), room_identifier),
+        IrisMessage::SessionDurationExceeded
+    ));
+    assert!(matches!(
+        ask_how_session_ended(relay.local_addr(), room_identifier + 1),
+        IrisMessage::SessionEndUnknown
+    ));
+
+    drop(sender);
+    drop(receiver);
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a session is ended once nothing went through it for too long, while one that
+/// keeps forwarding is left alone.
+#[test]
+fn test_session_idle_timeout() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .session_idle_timeout(Duration::from_secs(2))
+        .spawn()
+        .unwrap();
+
+    let (room_identifier, mut sender, mut receiver) = pair_in_room(relay.local_addr());
+    let started_at = Instant::now();
+    for _ in 0..3 {
+        thread::sleep(Duration::from_secs(1));
+        write_message(&mut sender, IrisMessage::Acknowledge);
+        assert!(matches!(
+            read_message(&mut receiver),
+            IrisMessage::Acknowledge
+        ));
+    }
+    assert!(read_until_closed(&mut sender).is_empty());
+    assert!(read_until_closed(&mut receiver).is_empty());
+    assert!(started_at.elapsed() < Duration::from_secs(7));
+    assert!(matches!(
+        ask_how_session_ended(relay.local_addr(), room_identifier),
+        IrisMessage::SessionIdleTimeout
+    ));
+
+    drop(sender);
+    drop(receiver);
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that what goes over the daily quota is still passed on, and that the address is
+/// turned away for the rest of the day afterwards.
+#[test]
+fn test_daily_quota() {
+    let file = Fixture::new("daily-quota");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .daily_quota_bytes(Some(1000))
+        .spawn()
+        .unwrap();
+
+    let (room_identifier, mut sender, mut receiver) = pair_in_room(relay.local_addr());
+    let payload = vec![7; 2000];
+    sender.write_all(&payload).unwrap();
+
+    assert_eq!(read_until_closed(&mut receiver), payload);
+    assert!(read_until_closed(&mut sender).is_empty());
+    assert!(matches!(
+        ask_how_session_ended(relay.local_addr(), room_identifier),
+        IrisMessage::DailyQuotaExceeded
+    ));
+    drop(sender);
+    drop(receiver);
+
+    let result = send(
+        relay.local_addr(),
+        &RelayConnectionOptions::default(),
+        vec![file.path()],
+    );
+    assert!(matches!(result, Err(IrisError::DailyQuotaExceeded)));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that rooms end up in the audit log, with one record per line, and that the log is
+/// rotated.
+#[test]
+fn test_audit_log() {
+    let directory = std::env::temp_dir().join(format!("iris-audit-log-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("audit.jsonl");
+    // Rotate after every record so that each of them ends up in a file of its own
+    let mut audit_log = AuditLogConfig::new(&path);
+    audit_log.max_file_size = 1;
+    audit_log.max_rotated_files = 2;
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .audit_log(audit_log)
+        .spawn()
+        .unwrap();
+
+    // Records are written once the relay is done with a room, which the clients do not wait for
+    let wait_for = |name: &str| {
+        let started_at = Instant::now();
+        while !directory.join(name).exists() && started_at.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    sender.write_all(b"hello").unwrap();
+    receiver.read_exact(&mut [0; 5]).unwrap();
+    drop(sender);
+    drop(receiver);
+    wait_for("audit.jsonl.1");
+
+    let _ = receive(relay.local_addr(), &RelayConnectionOptions::default(), "1");
+    // In the order the files are put in place while rotating
+    for name in ["audit.jsonl.2", "audit.jsonl.1", "audit.jsonl"] {
+        wait_for(name);
+    }
+
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let read_record = |name: &str| -> serde_json::Value {
+        let contents = fs::read_to_string(directory.join(name)).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        serde_json::from_str(&contents).unwrap()
+    };
+    let session = read_record("audit.jsonl.2");
+    assert_eq!(session["termination"], "normal");
+    assert_eq!(session["sender_to_receiver_bytes"], 5);
+    assert_eq!(session["receiver_to_sender_bytes"], 0);
+    assert!(session["paired_at"].is_string());
+    assert!(session["ended_at"].is_string());
+
+    let bad_room = read_record("audit.jsonl.1");
+    assert_eq!(bad_room["room_identifier"], 1);
+    assert_eq!(bad_room["termination"], "bad_room");
+    assert!(bad_room["sender_address"].is_null());
+    assert!(bad_room["receiver_address"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Checks that the metrics endpoint reports turned away receivers.
+#[test]
+fn test_metrics() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .metrics_address("127.0.0.1:0")
+        .spawn()
+        .unwrap();
+
+    let (_worker_communication, progress_communication) = get_receiver_communication_channels();
+    let _ = simple_receive(
+        "127.0.0.1".to_string(),
+        relay.local_addr().port().to_string(),
+        &RelayConnectionOptions::default(),
+        Some("1"),
+        "this-is-secret",
+        ConflictingFileMode::Error,
+        &progress_communication,
+    );
+
+    let mut metrics_connection = TcpStream::connect(relay.metrics_addr().unwrap()).unwrap();
+    metrics_connection
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    metrics_connection.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(
+        response.contains("iris_relay_handshake_failures_total{reason=\"bad_room_identifier\"} 1")
+    );
+    assert!(response.contains("iris_relay_waiting_rooms 0"));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a client trickling in its request is cut off once the whole request has taken
+/// too long, however often it sends something.
+#[test]
+fn test_metrics_request_deadline() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .metrics_address("127.0.0.1:0")
+        .spawn()
+        .unwrap();
+
+    let mut metrics_connection = TcpStream::connect(relay.metrics_addr().unwrap()).unwrap();
+    metrics_connection
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    metrics_connection
+        .write_all(b"GET /metrics HTTP/1.1\r\nX-Slow: ")
+        .unwrap();
+    let started_at = Instant::now();
+    let mut response = vec![0; 64];
+    loop {
+        match metrics_connection.read(&mut response) {
+            Ok(bytes_read) => {
+                response.truncate(bytes_read);
+                break;
+            }
+            Err(_) => assert!(started_at.elapsed() < Duration::from_secs(10)),
+        }
+        metrics_connection.write_all(b"a").unwrap();
+    }
+    let _ = metrics_connection.read_to_end(&mut response);
+    assert!(response.starts_with(b"HTTP/1.1 400"));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Sends a request to the admin API and returns the status code along with the JSON body.
+fn admin_request(relay: &iris::RelayHandle, method: &str, path: &str) -> (u16, serde_json::Value) {
+    let Some(ListenerAddr::Tcp(admin_addr)) = relay.admin_addr() else {
+        panic!("expected the admin API on a network address");
+    };
+    let mut admin_connection = TcpStream::connect(admin_addr).unwrap();
+    write!(
+        admin_connection,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    )
+    .unwrap();
+    let mut response = String::new();
+    admin_connection.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap_or_default())
+}
+
+/// Checks that the admin API lists and closes rooms and sessions, and toggles drain mode.
+#[test]
+fn test_admin_api() {
+    assert!(matches!(
+        RelayBuilder::new("127.0.0.1:0")
+            .admin_address("0.0.0.0:0")
+            .spawn(),
+        Err(IrisError::InvalidRelayConfig(_))
+    ));
+
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .admin_address("127.0.0.1:0")
+        .spawn()
+        .unwrap();
+
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+    let (status, rooms) = admin_request(&relay, "GET", "/rooms");
+    assert_eq!(status, 200);
+    assert_eq!(rooms[0]["room_identifier"], room_identifier);
+    let path = format!("/rooms/{room_identifier}");
+    assert_eq!(admin_request(&relay, "DELETE", &path).0, 200);
+    assert!(matches!(read_message(&mut sender), IrisMessage::RoomClosed));
+    assert_eq!(admin_request(&relay, "DELETE", &path).0, 404);
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    sender.write_all(b"hello").unwrap();
+    receiver.read_exact(&mut [0; 5]).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let session = loop {
+        let (status, sessions) = admin_request(&relay, "GET", "/sessions");
+        assert_eq!(status, 200);
+        if sessions[0]["sender_to_receiver_bytes"] == 5 || Instant::now() > deadline {
+            break sessions[0].clone();
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(session["sender_to_receiver_bytes"], 5);
+    assert_eq!(session["receiver_to_sender_bytes"], 0);
+    let path = format!("/sessions/{}", session["session_identifier"]);
+    assert_eq!(admin_request(&relay, "DELETE", &path).0, 200);
+    assert_eq!(receiver.read(&mut [0; 1]).unwrap(), 0);
+
+    assert_eq!(admin_request(&relay, "POST", "/drain").1["draining"], true);
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::RelayDraining
+    ));
+    assert_eq!(
+        admin_request(&relay, "DELETE", "/drain").1["draining"],
+        false
+    );
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::SenderConnecting);
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::AssignedRoomIdentifier { .. }
+    ));
+
+    assert_eq!(admin_request(&relay, "PUT", "/rooms").0, 405);
+    assert_eq!(admin_request(&relay, "GET", "/metrics").0, 404);
+
+    drop(sender);
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a broadcast starts once its room is full and goes on for the receivers that
+/// succeed when one of them fails, with the failure reported through the sender's progress.
+#[test]
+fn test_broadcast() {
+    let file = Fixture::new("broadcast");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let relay_addr = relay.local_addr();
+
+    let (worker_communication, progress_communication) = get_sender_communication_channels();
+    let files = vec![file.path().into()];
+    let sender = thread::spawn(move || {
+        simple_broadcast(
+            relay_addr.ip().to_string(),
+            relay_addr.port().to_string(),
+            &RelayConnectionOptions::default(),
+            CipherType::XChaCha20Poly1305,
+            PASSPHRASE,
+            files,
+            BroadcastOptions {
+                max_receivers: 2,
+                join_window: Duration::from_secs(60),
+            },
+            &progress_communication,
+        )
+    });
+    let room_identifier = loop {
+        match worker_communication.read().unwrap() {
+            Some(SenderProgressMessage::AssignedRoomIdentifier { room_identifier }) => {
+                break room_identifier
+            }
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    // Both receivers write to the same file, only one of them gets to create it
+    let receivers: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                receive(
+                    relay_addr,
+                    &RelayConnectionOptions::default(),
+                    &room_identifier.to_string(),
+                )
+            })
+        })
+        .collect();
+    let results: Vec<_> = receivers
+        .into_iter()
+        .map(|receiver| receiver.join().unwrap())
+        .collect();
+    sender.join().unwrap().unwrap();
+
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    assert!(results
+        .iter()
+        .any(|result| matches!(result, Err(IrisError::AlreadyExistsUserIOError(_)))));
+    file.assert_received();
+
+    let mut progress = Vec::new();
+    while let Ok(Some(message)) = worker_communication.read() {
+        progress.push(message);
+    }
+    assert!(progress.iter().any(|message| matches!(
+        message,
+        SenderProgressMessage::ReceiversJoined { total_receivers: 2 }
+    )));
+    let failed_receivers = progress
+        .iter()
+        .filter(|message| {
+            matches!(
+                message,
+                SenderProgressMessage::ReceiverProgress { progress, .. }
+                    if matches!(**progress, SenderProgressMessage::Error(_))
+            )
+        })
+        .count();
+    assert_eq!(failed_receivers, 1);
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that the receivers a broadcast is holding for its sender are still there when the
+/// room identifier it was started in is handed to another broadcast.
+#[test]
+fn test_broadcast_room_identifier_reuse() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .room_identifier_digits(1, 1)
+        .max_room_identifier_occupancy(1.0)
+        .max_room_creations_per_minute(1000)
+        .spawn()
+        .unwrap();
+    let relay_addr = relay.local_addr();
+
+    let create_room = || {
+        let mut sender = TcpStream::connect(relay_addr).unwrap();
+        write_message(
+            &mut sender,
+            IrisMessage::BroadcastSenderConnecting {
+                max_receivers: 2,
+                join_window_secs: 60,
+            },
+        );
+        let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender)
+        else {
+            panic!("the broadcast room was not created");
+        };
+        (room_identifier, sender)
+    };
+    // Fills the room so the broadcast starts with one receiver left for its sender to claim
+    let start_broadcast = |room_identifier, sender: &mut TcpStream| {
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut receiver = TcpStream::connect(relay_addr).unwrap();
+                write_message(
+                    &mut receiver,
+                    IrisMessage::ReceiverConnecting { room_identifier },
+                );
+                receiver
+            })
+            .collect();
+        let IrisMessage::BroadcastStarted { token, .. } = read_message(sender) else {
+            panic!("the broadcast did not start");
+        };
+        (token, receivers)
+    };
+    let claim = |room_identifier, token| {
+        let mut sender = TcpStream::connect(relay_addr).unwrap();
+        write_message(
+            &mut sender,
+            IrisMessage::SenderJoining {
+                room_identifier,
+                token,
+            },
+        );
+        read_message(&mut sender)
+    };
+
+    let (room_identifier, mut first_sender) = create_room();
+    let (first_token, _first_receivers) = start_broadcast(room_identifier, &mut first_sender);
+
+    // The other rooms are kept open so the identifier comes around again
+    let mut other_rooms = Vec::new();
+    let mut second_sender = loop {
+        let (reused_identifier, sender) = create_room();
+        if reused_identifier == room_identifier {
+            break sender;
+        }
+        other_rooms.push(sender);
+    };
+    let (second_token, _second_receivers) = start_broadcast(room_identifier, &mut second_sender);
+
+    assert!(matches!(
+        claim(room_identifier, first_token),
+        IrisMessage::ReceiverConnected
+    ));
+    assert!(matches!(
+        claim(room_identifier, second_token),
+        IrisMessage::ReceiverConnected
+    ));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a reusable room serves receivers one after another until it is used up, with
+/// the uses left reported to the sender.
+#[test]
+fn test_reusable_room() {
+    let file = Fixture::new("reusable-room");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let relay_addr = relay.local_addr();
+
+    let (worker_communication, progress_communication) = get_sender_communication_channels();
+    let files = vec![file.path().into()];
+    let sender = thread::spawn(move || {
+        simple_send_reusable(
+            relay_addr.ip().to_string(),
+            relay_addr.port().to_string(),
+            &RelayConnectionOptions::default(),
+            CipherType::XChaCha20Poly1305,
+            PASSPHRASE,
+            files,
+            ReusableRoomOptions {
+                max_uses: 2,
+                lifetime: Duration::from_secs(60),
+            },
+            &progress_communication,
+        )
+    });
+    let room_identifier = loop {
+        match worker_communication.read().unwrap() {
+            Some(SenderProgressMessage::AssignedRoomIdentifier { room_identifier }) => {
+                break room_identifier
+            }
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    for _ in 0..2 {
+        receive(
+            relay_addr,
+            &RelayConnectionOptions::default(),
+            &room_identifier.to_string(),
+        )
+        .unwrap();
+        file.assert_received();
+    }
+    sender.join().unwrap().unwrap();
+
+    let mut uses_left = Vec::new();
+    while let Ok(Some(message)) = worker_communication.read() {
+        if let SenderProgressMessage::ReceiverWaiting { uses_left: left } = message {
+            uses_left.push(left);
+        }
+    }
+    assert_eq!(uses_left, [1, 0]);
+
+    // The room is gone once used up
+    let result = receive(
+        relay_addr,
+        &RelayConnectionOptions::default(),
+        &room_identifier.to_string(),
+    );
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a receiver queued in a reusable room is told the room expired when its
+/// lifetime runs out before the sender comes for it.
+#[test]
+fn test_reusable_room_expiry() {
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let relay_addr = relay.local_addr();
+
+    let mut sender = TcpStream::connect(relay_addr).unwrap();
+    write_message(
+        &mut sender,
+        IrisMessage::ReusableSenderConnecting {
+            max_uses: 2,
+            lifetime_secs: 1,
+        },
+    );
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender) else {
+        panic!("the reusable room was not created");
+    };
+
+    // The sender never comes for the receiver
+    let result = receive(
+        relay_addr,
+        &RelayConnectionOptions::default(),
+        &room_identifier.to_string(),
+    );
+    assert!(matches!(result, Err(IrisError::RoomExpired)));
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverWaiting { .. }
+    ));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a receiver can create the room for the sender to join, and that a room whose
+/// two clients both want to receive fails instead of hanging.
+#[test]
+fn test_receiver_created_room() {
+    let file = Fixture::new("receiver-created-room");
+    let relay = RelayBuilder::new("127.0.0.1:0").spawn().unwrap();
+    let relay_addr = relay.local_addr();
+
+    let spawn_receiver = || {
+        let (worker_communication, progress_communication) = get_receiver_communication_channels();
+        let receiver = thread::spawn(move || {
+            simple_receive(
+                relay_addr.ip().to_string(),
+                relay_addr.port().to_string(),
+                &RelayConnectionOptions::default(),
+                None,
+                PASSPHRASE,
+                ConflictingFileMode::Error,
+                &progress_communication,
+            )
+        });
+        let room_identifier = loop {
+            match worker_communication.read().unwrap() {
+                Some(ReceiverProgressMessage::AssignedRoomIdentifier { room_identifier }) => {
+                    break room_identifier
+                }
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        (receiver, room_identifier, worker_communication)
+    };
+
+    let (receiver, room_identifier, _worker_communication) = spawn_receiver();
+    let (_worker_communication, progress_communication) = get_sender_communication_channels();
+    simple_send(
+        relay_addr.ip().to_string(),
+        relay_addr.port().to_string(),
+        &RelayConnectionOptions::default(),
+        Some(&room_identifier.to_string()),
+        CipherType::XChaCha20Poly1305,
+        PASSPHRASE,
+        vec![file.path().into()],
+        &progress_communication,
+    )
+    .unwrap();
+    receiver.join().unwrap().unwrap();
+    file.assert_received();
+
+    let (receiver, room_identifier, _worker_communication) = spawn_receiver();
+    let other_receiver = thread::spawn(move || {
+        receive(
+            relay_addr,
+            &RelayConnectionOptions::default(),
+            &room_identifier.to_string(),
+        )
+    });
+    assert!(matches!(
+        receiver.join().unwrap(),
+        Err(IrisError::RoleConflict)
+    ));
+    assert!(other_receiver.join().unwrap().is_err());
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that a sender and its receiver connecting to different nodes of a cluster are paired
+/// on the node the room is on, and that every node hands out room identifiers of its own.
+#[test]
+fn test_cluster() {
+    let file = Fixture::new("cluster");
+    let node_addrs: Vec<SocketAddr> = (0..3)
+        .map(|_| {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        })
+        .collect();
+    let nodes: Vec<String> = node_addrs.iter().map(ToString::to_string).collect();
+    let relays: Vec<_> = (0..nodes.len())
+        .map(|node_index| {
+            RelayBuilder::new(nodes[node_index].clone())
+                .access_token("cluster", "secret")
+                .cluster(ClusterConfig::new(nodes.clone(), node_index))
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let relay_connection_options = RelayConnectionOptions {
+        access_token: Some("secret".to_string()),
+        ..Default::default()
+    };
+
+    let sender = spawn_sender(
+        node_addrs[0],
+        relay_connection_options.clone(),
+        vec![file.path()],
+    );
+    assert_eq!(sender.room_identifier % 3, 0);
+    receive(
+        node_addrs[1],
+        &relay_connection_options,
+        &sender.room_identifier.to_string(),
+    )
+    .unwrap();
+    sender.join().unwrap();
+    file.assert_received();
+
+    // Room 1000 would be on the second node, which turns the receiver away
+    let result = receive(node_addrs[2], &relay_connection_options, "1000");
+    assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+
+    for relay in relays {
+        relay.shutdown();
+        relay.join().unwrap();
+    }
+}
+
+/// Checks that a node forwarding as many joins as it may turns the next ones away, without
+/// holding up the sessions paired on the node itself.
+#[test]
+fn test_cluster_forwarding_limit() {
+    let node_addrs: Vec<SocketAddr> = (0..2)
+        .map(|_| {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        })
+        .collect();
+    let nodes: Vec<String> = node_addrs.iter().map(ToString::to_string).collect();
+    let relays: Vec<_> = (0..nodes.len())
+        .map(|node_index| {
+            RelayBuilder::new(nodes[node_index].clone())
+                .max_concurrent_relays(1)
+                .max_broadcast_receivers(1)
+                .cluster(ClusterConfig::new(nodes.clone(), node_index))
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    // Rooms are created on the second node, and joined through the first one
+    let join_through_first_node = || {
+        let mut sender = TcpStream::connect(node_addrs[1]).unwrap();
+        write_message(&mut sender, IrisMessage::SenderConnecting);
+        let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender)
+        else {
+            panic!("expected a room identifier");
+        };
+        let mut receiver = TcpStream::connect(node_addrs[0]).unwrap();
+        write_message(
+            &mut receiver,
+            IrisMessage::ReceiverConnecting { room_identifier },
+        );
+        (sender, receiver)
+    };
+    let (mut sender, _receiver) = join_through_first_node();
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverConnected
+    ));
+    let (_sender, mut receiver) = join_through_first_node();
+    assert!(matches!(
+        read_message(&mut receiver),
+        IrisMessage::RelayFull
+    ));
+
+    // The forwarded join does not take the relay worker of the first node
+    let (mut sender, mut receiver) = pair(node_addrs[0]);
+    sender.write_all(b"through the first node").unwrap();
+    drop(sender);
+    assert_eq!(read_until_closed(&mut receiver), b"through the first node");
+
+    for relay in relays {
+        relay.shutdown();
+        relay.join().unwrap();
+    }
+}
+
+/// Checks that an IPv4 client forwarded to an IPv6 node is held to that node's address lists by
+/// its own address rather than by the address of the node forwarding it.
+#[test]
+fn test_cluster_forwarding_across_families() {
+    let node_addrs: Vec<SocketAddr> = ["127.0.0.1:0", "[::1]:0"]
+        .into_iter()
+        .map(|addr| TcpListener::bind(addr).unwrap().local_addr().unwrap())
+        .collect();
+    let nodes: Vec<String> = node_addrs.iter().map(ToString::to_string).collect();
+    let relays: Vec<_> = (0..nodes.len())
+        .map(|node_index| {
+            let relay = RelayBuilder::new(nodes[node_index].clone())
+                .cluster(ClusterConfig::new(nodes.clone(), node_index));
+            // Only the IPv6 node turns away IPv4 loopback clients
+            match node_index {
+                1 => relay.denied_network("127.0.0.0/8".parse().unwrap()),
+                _ => relay,
+            }
+            .spawn()
+            .unwrap()
+        })
+        .collect();
+
+    // Rooms are created on the IPv6 node, and joined through the IPv4 one
+    let join_through_first_node = || {
+        let mut sender = TcpStream::connect(node_addrs[1]).unwrap();
+        write_message(&mut sender, IrisMessage::SenderConnecting);
+        let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut sender)
+        else {
+            panic!("expected a room identifier");
+        };
+        let mut receiver = TcpStream::connect(node_addrs[0]).unwrap();
+        write_message(
+            &mut receiver,
+            IrisMessage::ReceiverConnecting { room_identifier },
+        );
+        (sender, receiver)
+    };
+    let (_sender, mut receiver) = join_through_first_node();
+    assert!(read_until_closed(&mut receiver).is_empty());
+
+    relays[1].set_address_lists(Vec::new(), Vec::new());
+    let (mut sender, _receiver) = join_through_first_node();
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::ReceiverConnected
+    ));
+
+    for relay in relays {
+        relay.shutdown();
+        relay.join().unwrap();
+    }
+}
+
+/// Checks that a receiver downloads the files a sender left in a mailbox, and that the mailbox
+/// only opens with the passphrase it was left with, each wrong guess counting as a failed join.
+#[test]
+fn test_mailbox() {
+    let file = Fixture::new("mailbox");
+    let directory = std::env::temp_dir().join(format!("iris-mailbox-{}", std::process::id()));
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .mailbox(MailboxConfig::new(&directory))
+        .failed_join_backoff(Duration::from_millis(200))
+        .spawn()
+        .unwrap();
+    let relay_addr = relay.local_addr();
+
+    let (worker_communication, progress_communication) = get_sender_communication_channels();
+    simple_send_to_mailbox(
+        relay_addr.ip().to_string(),
+        relay_addr.port().to_string(),
+        &RelayConnectionOptions::default(),
+        CipherType::XChaCha20Poly1305,
+        PASSPHRASE,
+        vec![file.path().into()],
+        &progress_communication,
+    )
+    .unwrap();
+    let mut room_identifier = None;
+    let mut is_stored = false;
+    while let Ok(Some(message)) = worker_communication.read() {
+        match message {
+            SenderProgressMessage::AssignedRoomIdentifier {
+                room_identifier: assigned_room_identifier,
+            } => room_identifier = Some(assigned_room_identifier),
+            SenderProgressMessage::MailboxStored { .. } => is_stored = true,
+            _ => {}
+        }
+    }
+    let room_identifier = room_identifier.unwrap().to_string();
+    assert!(is_stored);
+
+    // The sender is gone by the time the receiver comes for the files
+    let download = |room_identifier: &str, passphrase: &str| {
+        let (_worker_communication, progress_communication) = get_receiver_communication_channels();
+        simple_receive_from_mailbox(
+            relay_addr.ip().to_string(),
+            relay_addr.port().to_string(),
+            &RelayConnectionOptions::default(),
+            room_identifier,
+            passphrase,
+            ConflictingFileMode::Error,
+            &progress_communication,
+        )
+    };
+    download(&room_identifier, PASSPHRASE).unwrap();
+    file.assert_received();
+
+    assert!(matches!(
+        download(&room_identifier, "wrong-passphrase"),
+        Err(IrisError::InvalidPassphrase)
+    ));
+    assert!(matches!(
+        download(&room_identifier, "wrong-passphrase"),
+        Err(IrisError::RateLimited(1))
+    ));
+    thread::sleep(Duration::from_millis(250));
+    assert!(matches!(
+        download("1", PASSPHRASE),
+        Err(IrisError::InvalidPassphrase)
+    ));
+
+    relay.shutdown();
+    relay.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Checks that uploads are cut off once the mailboxes would take up more bytes than they may,
+/// and that what an upload took up is given back when it is thrown away.
+#[test]
+fn test_mailbox_total_size_limit() {
+    let directory = std::env::temp_dir().join(format!("iris-mailbox-total-{}", std::process::id()));
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .mailbox(MailboxConfig {
+            max_total_bytes: 1024,
+            ..MailboxConfig::new(&directory)
+        })
+        .spawn()
+        .unwrap();
+    let upload = |sizes: &[usize]| {
+        let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+        write_message(&mut sender, IrisMessage::MailboxUploading);
+        let IrisMessage::AssignedRoomIdentifier { .. } = read_message(&mut sender) else {
+            panic!("expected a room identifier");
+        };
+        for &size in sizes {
+            sender
+                .write_all(&u32::try_from(size).unwrap().to_be_bytes())
+                .unwrap();
+            sender.write_all(&vec![0; size]).unwrap();
+        }
+        read_message(&mut sender)
+    };
+
+    // The second message would take the mailboxes past their size, so it is never sent
+    assert!(matches!(upload(&[600, 600]), IrisMessage::RelayFull));
+    assert!(matches!(
+        upload(&[600, 0]),
+        IrisMessage::MailboxStored { .. }
+    ));
+    assert!(matches!(upload(&[600]), IrisMessage::RelayFull));
+
+    relay.shutdown();
+    relay.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Checks that a mailbox upload that sits idle is cut off and recorded as such.
+#[test]
+fn test_mailbox_idle_timeout() {
+    let directory = std::env::temp_dir().join(format!("iris-mailbox-idle-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let audit_log = directory.join("audit.jsonl");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .session_idle_timeout(Duration::from_secs(1))
+        .mailbox(MailboxConfig::new(directory.join("mailboxes")))
+        .audit_log(AuditLogConfig::new(&audit_log))
+        .spawn()
+        .unwrap();
+
+    // The sender never says how big its first message is
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::MailboxUploading);
+    let IrisMessage::AssignedRoomIdentifier { .. } = read_message(&mut sender) else {
+        panic!("expected a room identifier");
+    };
+    assert!(matches!(
+        read_message(&mut sender),
+        IrisMessage::SessionIdleTimeout
+    ));
+
+    relay.shutdown();
+    relay.join().unwrap();
+
+    let record: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&audit_log).unwrap()).unwrap();
+    assert_eq!(record["termination"], "session_idle_timeout");
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Checks that mailbox transfers run on workers of their own, turning away those beyond what
+/// they can take without holding up the transfers between clients.
+#[test]
+fn test_mailbox_workers_busy() {
+    let directory =
+        std::env::temp_dir().join(format!("iris-mailbox-workers-{}", std::process::id()));
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_concurrent_relays(1)
+        .max_broadcast_receivers(1)
+        .mailbox(MailboxConfig {
+            max_transfers: 1,
+            ..MailboxConfig::new(&directory)
+        })
+        .spawn()
+        .unwrap();
+
+    // An upload that never completes holds on to the only mailbox worker
+    let mut stalled_sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut stalled_sender, IrisMessage::MailboxUploading);
+    let IrisMessage::AssignedRoomIdentifier { room_identifier } = read_message(&mut stalled_sender)
+    else {
+        panic!("expected a room identifier");
+    };
+
+    let mut sender = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(&mut sender, IrisMessage::MailboxUploading);
+    assert!(matches!(read_message(&mut sender), IrisMessage::RelayFull));
+    let mut receiver = TcpStream::connect(relay.local_addr()).unwrap();
+    write_message(
+        &mut receiver,
+        IrisMessage::MailboxDownloading { room_identifier },
+    );
+    assert!(matches!(
+        read_message(&mut receiver),
+        IrisMessage::RelayFull
+    ));
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    sender.write_all(b"still relaying").unwrap();
+    let mut relayed = [0; 14];
+    receiver.read_exact(&mut relayed).unwrap();
+    assert_eq!(&relayed, b"still relaying");
+
+    drop(stalled_sender);
+    relay.shutdown();
+    relay.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Checks that the relay POSTs the events of a room to its webhook, retrying deliveries that
+/// fail.
+#[test]
+fn test_webhook() {
+    // Stands in for the webhook, failing the first delivery and recording every request
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/events", listener.local_addr().unwrap());
+    let (requests, received_requests) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for (index, stream) in listener.incoming().enumerate() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let status = if index == 0 {
+                "500 Internal Server Error"
+            } else {
+                "200 OK"
+            };
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            if requests.send((request_line, body)).is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut webhook = WebhookConfig::new(url);
+    webhook.initial_backoff = Duration::from_millis(10);
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .webhook(webhook)
+        .spawn()
+        .unwrap();
+
+    let (mut sender, mut receiver) = pair(relay.local_addr());
+    sender.write_all(b"hello").unwrap();
+    receiver.read_exact(&mut [0; 5]).unwrap();
+    drop(sender);
+    drop(receiver);
+
+    let mut requests = Vec::new();
+    while requests.len() < 4 {
+        requests.push(
+            received_requests
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap(),
+        );
+    }
+    relay.shutdown();
+    relay.join().unwrap();
+
+    assert!(requests
+        .iter()
+        .all(|(request_line, _)| request_line.starts_with("POST /events ")));
+    // The first delivery failed and was retried
+    assert_eq!(requests[0].1, requests[1].1);
+    let events: Vec<_> = requests[1..]
+        .iter()
+        .map(|(_, event)| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(events, ["created", "paired", "completed"]);
+
+    let room_identifier = &requests[1].1["room_identifier"];
+    let completed = &requests[3].1;
+    assert_eq!(&completed["room_identifier"], room_identifier);
+    assert_eq!(completed["sender_to_receiver_bytes"], 5);
+    assert_eq!(completed["termination"], "normal");
+    assert!(completed["receiver_address"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+}
+
+/// Checks that the relay hangs up on addresses that are denied or not allowed, and that the
+/// lists can be changed while it runs.
+#[test]
+fn test_address_lists() {
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .metrics_address("127.0.0.1:0")
+        .denied_network("127.0.0.0/8".parse().unwrap())
+        .spawn()
+        .unwrap();
+
+    let is_rejected = || {
+        let mut connection = TcpStream::connect(relay.local_addr()).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let message = serde_json::to_vec(&IrisMessage::SenderConnecting).unwrap();
+        let size = u32::try_from(message.len()).unwrap().to_be_bytes();
+        // A rejected connection may be hung up on before the message is even written
+        let _ = connection.write_all(&[&size[..], &message].concat());
+        let mut size = [0; 4];
+        connection.read_exact(&mut size).is_err()
+    };
+    assert!(is_rejected());
+
+    relay.set_address_lists(vec!["10.0.0.0/8".parse().unwrap()], Vec::new());
+    assert!(is_rejected());
+
+    relay.set_address_lists(vec!["127.0.0.1".parse().unwrap()], Vec::new());
+    assert!(!is_rejected());
+
+    let mut metrics_connection = TcpStream::connect(relay.metrics_addr().unwrap()).unwrap();
+    metrics_connection
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    metrics_connection.read_to_string(&mut response).unwrap();
+    assert!(response.contains("iris_relay_rejected_connections_total{reason=\"denied\"} 1"));
+    assert!(response.contains("iris_relay_rejected_connections_total{reason=\"not_allowed\"} 1"));
+
+    relay.shutdown();
+    relay.join().unwrap();
+}
+
+/// Checks that clients only meet peers in their own namespace, and that a namespace is held to
+/// its own limits without affecting the others.
+#[test]
+fn test_namespaces() {
+    let file = Fixture::new("namespaces");
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_failed_joins(0)
+        .namespace_limits(NamespaceLimits {
+            max_waiting_rooms: Some(1),
+            ..NamespaceLimits::new("tool-a".parse().unwrap())
+        })
+        .spawn()
+        .unwrap();
+    let in_namespace = |namespace: Option<&str>| RelayConnectionOptions {
+        namespace: namespace.map(|namespace| namespace.parse().unwrap()),
+        ..Default::default()
+    };
+
+    let sender = spawn_sender(
+        relay.local_addr(),
+        in_namespace(Some("tool-a")),
+        vec![file.path()],
+    );
+    let room_identifier = sender.room_identifier.to_string();
+    for namespace in [None, Some("tool-b")] {
+        let result = receive(
+            relay.local_addr(),
+            &in_namespace(namespace),
+            &room_identifier,
+        );
+        assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    }
+
+    let result = send(
+        relay.local_addr(),
+        &in_namespace(Some("tool-a")),
+        vec![file.path()],
+    );
+    assert!(matches!(result, Err(IrisError::RelayFull)));
+    let other_sender = spawn_sender(
+        relay.local_addr(),
+        in_namespace(Some("tool-b")),
+        vec![file.path()],
+    );
+
+    receive(
+        relay.local_addr(),
+        &in_namespace(Some("tool-a")),
+        &room_identifier,
+    )
+    .unwrap();
+    sender.join().unwrap();
+    file.assert_received();
+
+    relay.shutdown();
+    assert!(other_sender.join().is_err());
+    relay.join().unwrap();
+}
+
+/// Checks that mailboxes are only downloaded in the namespace they were uploaded in, and that
+/// mailbox transfers count towards the sessions of their namespace, which hold back pairs of
+/// rooms that were already waiting as well.
+#[test]
+fn test_namespace_mailboxes() {
+    let file = Fixture::new("namespace-mailboxes");
+    let directory =
+        std::env::temp_dir().join(format!("iris-mailbox-namespaces-{}", std::process::id()));
+    let relay = RelayBuilder::new("127.0.0.1:0")
+        .max_failed_joins(0)
+        .mailbox(MailboxConfig::new(&directory))
+        .namespace_limits(NamespaceLimits {
+            max_sessions: Some(1),
+            ..NamespaceLimits::new("tool-a".parse().unwrap())
+        })
+        .spawn()
+        .unwrap();
+    let relay_addr = relay.local_addr();
+    let in_namespace = |namespace: Option<&str>| RelayConnectionOptions {
+        namespace: namespace.map(|namespace| namespace.parse().unwrap()),
+        ..Default::default()
+    };
+    let connect_in_tool_a = |message: IrisMessage| {
+        let mut client = TcpStream::connect(relay_addr).unwrap();
+        write_message(
+            &mut client,
+            IrisMessage::EnteringNamespace {
+                namespace: "tool-a".parse().unwrap(),
+            },
+        );
+        write_message(&mut client, message);
+        client
+    };
+    let download = |namespace: Option<&str>, room_identifier: &str| {
+        let (_worker_communication, progress_communication) = get_receiver_communication_channels();
+        simple_receive_from_mailbox(
+            relay_addr.ip().to_string(),
+            relay_addr.port().to_string(),
+            &in_namespace(namespace),
+            room_identifier,
+            PASSPHRASE,
+            ConflictingFileMode::Error,
+            &progress_communication,
+        )
+    };
+
+    let (worker_communication, progress_communication) = get_sender_communication_channels();
+    simple_send_to_mailbox(
+        relay_addr.ip().to_string(),
+        relay_addr.port().to_string(),
+        &in_namespace(Some("tool-a")),
+        CipherType::XChaCha20Poly1305,
+        PASSPHRASE,
+        vec![file.path().into()],
+        &progress_communication,
+    )
+    .unwrap();
+    let mut mailbox_identifier = None;
+    while let Ok(Some(message)) = worker_communication.read() {
+        if let SenderProgressMessage::AssignedRoomIdentifier { room_identifier } = message {
+            mailbox_identifier = Some(room_identifier);
+        }
+    }
+    let mailbox_identifier = mailbox_identifier.unwrap();
+    for namespace in [None, Some("tool-b")] {
+        let result = download(namespace, &mailbox_identifier.to_string());
+        assert!(matches!(result, Err(IrisError::InvalidPassphrase)));
+    }
+
+    // Let the relay hand back the worker of the upload before filling up the namespace
+    thread::sleep(Duration::from_millis(100));
+    let sender = spawn_sender(relay_addr, in_namespace(Some("tool-a")), vec![file.path()]);
+    let mut stalled_sender = connect_in_tool_a(IrisMessage::MailboxUploading);
+    assert!(matches!(
+        read_message(&mut stalled_sender),
+        IrisMessage::AssignedRoomIdentifier { .. }
+    ));
+    let mut receiver = connect_in_tool_a(IrisMessage::MailboxDownloading {
+        room_identifier: mailbox_identifier,
+    });
+    assert!(matches!(
+        read_message(&mut receiver),
+        IrisMessage::RelayFull
+    ));
+    let mut receiver = connect_in_tool_a(IrisMessage::ReceiverConnecting {
+        room_identifier: sender.room_identifier,
+    });
+    assert!(matches!(
+        read_message(&mut receiver),
+        IrisMessage::RelayFull
+    ));
+    assert!(sender.join().is_err());
+
+    drop(stalled_sender);
+    thread::sleep(Duration::from_millis(100));
+    download(Some("tool-a"), &mailbox_identifier.to_string()).unwrap();
+    file.assert_received();
+
+    relay.shutdown();
+    relay.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "clap")]
+
+use std::process::{Command, Output};
+
+fn iris_relay(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_iris-relay"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Checks that the example configuration shipped with the relay is valid.
+#[test]
+fn test_check_example_config() {
+    let output = iris_relay(&["--config", "iris-relay.example.toml", "--check-config"]);
+    assert!(output.status.success(), "{output:?}");
+}
+
+/// Checks that command line flags take precedence over the configuration file.
+#[test]
+fn test_flags_override_config() {
+    let output = iris_relay(&[
+        "--config",
+        "iris-relay.example.toml",
+        "--min-room-identifier-digits",
+        "13",
+        "--check-config",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("room identifiers"));
+
+    let output = iris_relay(&[
+        "--config",
+        "iris-relay.example.toml",
+        "--min-room-identifier-digits",
+        "6",
+        "--max-room-identifier-digits",
+        "13",
+        "--check-config",
+    ]);
+    assert!(output.status.success(), "{output:?}");
+}
+
+/// Checks that mistakes in the configuration are reported instead of starting the relay.
+#[test]
+fn test_check_invalid_config() {
+    let output = iris_relay(&["--check-config"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("at least one listen address is required")
+    );
+
+    let output = iris_relay(&[
+        "--listen-address",
+        "127.0.0.1:0",
+        "--log-level",
+        "loud",
+        "--check-config",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("log_level"));
+
+    let output = iris_relay(&["--config", "tests/aaa", "--check-config"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unable to parse"));
+}